@@ -0,0 +1,412 @@
+use anyhow::{bail, Context, Result};
+use messages::{Message, Parameters};
+use pendulum_core::mlp_policy::MlpWeights;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::fs;
+
+struct Args {
+    input: String,
+    output: String,
+    history: usize,
+    hidden: usize,
+    epochs: usize,
+    lr: f32,
+    seed: u64,
+    version: Option<u32>,
+}
+
+fn parse_args() -> Result<Args> {
+    let mut positional = Vec::new();
+    let mut args = Args {
+        input: String::new(),
+        output: String::new(),
+        history: 2,
+        hidden: 16,
+        epochs: 2000,
+        lr: 0.01,
+        seed: 0,
+        version: None,
+    };
+
+    let mut iter = std::env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        if let Some(name) = arg.strip_prefix("--") {
+            let value = iter
+                .next()
+                .with_context(|| format!("Missing value for --{name}"))?;
+            match name {
+                "history" => args.history = value.parse()?,
+                "hidden" => args.hidden = value.parse()?,
+                "epochs" => args.epochs = value.parse()?,
+                "lr" => args.lr = value.parse()?,
+                "seed" => args.seed = value.parse()?,
+                "version" => args.version = Some(value.parse()?),
+                _ => bail!("Unknown option --{name}"),
+            }
+        } else {
+            positional.push(arg);
+        }
+    }
+
+    if positional.len() != 2 {
+        bail!("Usage: bc_trainer <monitor log> <output blob> [--history N] [--hidden N] [--epochs N] [--lr X] [--seed N] [--version N]");
+    }
+    if args.history == 0 || args.hidden == 0 {
+        bail!("--history and --hidden must be positive");
+    }
+    args.output = positional.pop().unwrap();
+    args.input = positional.pop().unwrap();
+    Ok(args)
+}
+
+/// Read `DEMO,<episode>,<step>,<obs>,<act>` lines and return (obs, act) sequences per episode.
+fn load_demonstrations(path: &str) -> Result<Vec<Vec<(f32, f32)>>> {
+    let text = fs::read_to_string(path).with_context(|| format!("Failed to read {path}"))?;
+    let mut episodes: BTreeMap<usize, Vec<(usize, f32, f32)>> = BTreeMap::new();
+
+    for line in text.lines() {
+        // The monitor may prepend other output to the line
+        let Some(start) = line.find("DEMO,") else {
+            continue;
+        };
+        let fields: Vec<&str> = line[start..].trim_end().split(',').collect();
+        if fields.len() != 5 {
+            println!("Skipping malformed line: {line}");
+            continue;
+        }
+        let parsed = (
+            fields[1].parse::<usize>(),
+            fields[2].parse::<usize>(),
+            fields[3].parse::<f32>(),
+            fields[4].parse::<f32>(),
+        );
+        match parsed {
+            (Ok(episode), Ok(step), Ok(obs), Ok(act)) => {
+                episodes.entry(episode).or_default().push((step, obs, act))
+            }
+            _ => println!("Skipping malformed line: {line}"),
+        }
+    }
+
+    Ok(episodes
+        .into_values()
+        .map(|mut steps| {
+            steps.sort_by_key(|s| s.0);
+            steps.into_iter().map(|(_, obs, act)| (obs, act)).collect()
+        })
+        .collect())
+}
+
+/// Build inputs with the latest `history` observations (newest first) and target actions.
+///
+/// This must match how `MlpPolicy` fills its input history on the device.
+fn build_dataset(episodes: &[Vec<(f32, f32)>], history: usize) -> (Vec<Vec<f32>>, Vec<f32>) {
+    let mut inputs = Vec::new();
+    let mut targets = Vec::new();
+    for episode in episodes {
+        for t in 0..episode.len() {
+            let x = (0..history)
+                .map(|k| episode[t.saturating_sub(k)].0)
+                .collect();
+            inputs.push(x);
+            targets.push(episode[t].1);
+        }
+    }
+    (inputs, targets)
+}
+
+/// MLP with the same architecture as `MlpPolicy`: `tanh(w2 . tanh(w1 x + b1) + b2)`.
+struct Mlp {
+    weights: MlpWeights,
+}
+
+impl Mlp {
+    fn new(history: usize, hidden: usize, rng: &mut StdRng) -> Self {
+        let bound1 = 1.0 / (history as f32).sqrt();
+        let bound2 = 1.0 / (hidden as f32).sqrt();
+        let mut params = Vec::with_capacity(MlpWeights::num_params(history, hidden));
+        params.extend((0..hidden * history).map(|_| rng.gen_range(-bound1..bound1)));
+        params.extend((0..hidden).map(|_| 0.0));
+        params.extend((0..hidden).map(|_| rng.gen_range(-bound2..bound2)));
+        params.push(0.0);
+        Mlp {
+            weights: MlpWeights {
+                history,
+                hidden,
+                params,
+            },
+        }
+    }
+
+    /// Output for the input `x`, storing the activations of the hidden layer in `h`.
+    fn forward(&self, x: &[f32], h: &mut [f32]) -> f32 {
+        let MlpWeights {
+            history,
+            hidden,
+            ref params,
+        } = self.weights;
+        let (w1, rest) = params.split_at(hidden * history);
+        let (b1, rest) = rest.split_at(hidden);
+        let (w2, b2) = rest.split_at(hidden);
+
+        let mut z = b2[0];
+        for j in 0..hidden {
+            let row = &w1[j * history..(j + 1) * history];
+            let a: f32 = row.iter().zip(x).map(|(w, x)| w * x).sum::<f32>() + b1[j];
+            h[j] = a.tanh();
+            z += w2[j] * h[j];
+        }
+        z.tanh()
+    }
+
+    /// Return the mean squared error and accumulate its gradient into `grad`.
+    fn loss_and_grad(&self, inputs: &[Vec<f32>], targets: &[f32], grad: &mut [f32]) -> f32 {
+        let (history, hidden) = (self.weights.history, self.weights.hidden);
+        let (n_w1, n_h) = (hidden * history, hidden);
+        let w2 = &self.weights.params[n_w1 + n_h..n_w1 + 2 * n_h];
+
+        grad.iter_mut().for_each(|g| *g = 0.0);
+        let scale = 1.0 / inputs.len() as f32;
+        let mut loss = 0.0;
+        let mut h = vec![0.0; hidden];
+
+        for (x, &target) in inputs.iter().zip(targets) {
+            let y = self.forward(x, &mut h);
+            let err = y - target;
+            loss += err * err * scale;
+
+            // Backward
+            let dz = 2.0 * err * scale * (1.0 - y * y);
+            grad[n_w1 + 2 * n_h] += dz;
+            for j in 0..hidden {
+                grad[n_w1 + n_h + j] += dz * h[j];
+                let da = dz * w2[j] * (1.0 - h[j] * h[j]);
+                grad[n_w1 + j] += da;
+                for (k, xk) in x.iter().enumerate() {
+                    grad[j * history + k] += da * xk;
+                }
+            }
+        }
+
+        loss
+    }
+}
+
+/// Full-batch Adam optimizer.
+struct Adam {
+    lr: f32,
+    m: Vec<f32>,
+    v: Vec<f32>,
+    t: i32,
+}
+
+impl Adam {
+    const BETA1: f32 = 0.9;
+    const BETA2: f32 = 0.999;
+    const EPS: f32 = 1e-8;
+
+    fn new(lr: f32, size: usize) -> Self {
+        Adam {
+            lr,
+            m: vec![0.0; size],
+            v: vec![0.0; size],
+            t: 0,
+        }
+    }
+
+    fn step(&mut self, params: &mut [f32], grad: &[f32]) {
+        self.t += 1;
+        let c1 = 1.0 - Self::BETA1.powi(self.t);
+        let c2 = 1.0 - Self::BETA2.powi(self.t);
+        for i in 0..params.len() {
+            self.m[i] = Self::BETA1 * self.m[i] + (1.0 - Self::BETA1) * grad[i];
+            self.v[i] = Self::BETA2 * self.v[i] + (1.0 - Self::BETA2) * grad[i] * grad[i];
+            params[i] -= self.lr * (self.m[i] / c1) / ((self.v[i] / c2).sqrt() + Self::EPS);
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let args = parse_args()?;
+
+    let episodes = load_demonstrations(&args.input)?;
+    let (inputs, targets) = build_dataset(&episodes, args.history);
+    if inputs.is_empty() {
+        bail!("No demonstrations found in {}", args.input);
+    }
+    println!(
+        "Loaded {} demonstrations, {} steps",
+        episodes.len(),
+        inputs.len()
+    );
+
+    let mut rng = StdRng::seed_from_u64(args.seed);
+    let mut mlp = Mlp::new(args.history, args.hidden, &mut rng);
+    let mut adam = Adam::new(args.lr, mlp.weights.params.len());
+    let mut grad = vec![0.0; mlp.weights.params.len()];
+
+    for epoch in 0..args.epochs {
+        let loss = mlp.loss_and_grad(&inputs, &targets, &mut grad);
+        adam.step(&mut mlp.weights.params, &grad);
+        if epoch % 100 == 0 || epoch + 1 == args.epochs {
+            println!("epoch {epoch}: loss = {loss:.6}");
+        }
+    }
+
+    let model = mlp.weights.to_bytes()?;
+    let bytes = match args.version {
+        Some(version) => Message::Parameters(Parameters { version, model }).encode()?,
+        None => model,
+    };
+    fs::write(&args.output, bytes).with_context(|| format!("Failed to write {}", args.output))?;
+    println!("Saved weights to {}", args.output);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pendulum_core::mlp_policy::MlpPolicy;
+
+    fn random_data(history: usize, n: usize, rng: &mut StdRng) -> (Vec<Vec<f32>>, Vec<f32>) {
+        let inputs = (0..n)
+            .map(|_| (0..history).map(|_| rng.gen_range(-2.0..2.0)).collect())
+            .collect();
+        let targets = (0..n).map(|_| rng.gen_range(-0.9..0.9)).collect();
+        (inputs, targets)
+    }
+
+    #[test]
+    fn gradient_matches_finite_differences() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut mlp = Mlp::new(3, 4, &mut rng);
+        // Perturb the weights so that the biases are not zero
+        for p in mlp.weights.params.iter_mut() {
+            *p += rng.gen_range(-0.3..0.3);
+        }
+        let (inputs, targets) = random_data(3, 8, &mut rng);
+
+        let mut grad = vec![0.0; mlp.weights.params.len()];
+        mlp.loss_and_grad(&inputs, &targets, &mut grad);
+
+        let mut scratch = grad.clone();
+        let eps = 1e-3;
+        for (i, analytic) in grad.iter().enumerate() {
+            let p = mlp.weights.params[i];
+            mlp.weights.params[i] = p + eps;
+            let plus = mlp.loss_and_grad(&inputs, &targets, &mut scratch);
+            mlp.weights.params[i] = p - eps;
+            let minus = mlp.loss_and_grad(&inputs, &targets, &mut scratch);
+            mlp.weights.params[i] = p;
+
+            let numeric = (plus - minus) / (2.0 * eps);
+            assert!(
+                (numeric - analytic).abs() < 2e-3 + 1e-2 * analytic.abs(),
+                "parameter {}: numeric {} analytic {}",
+                i,
+                numeric,
+                analytic
+            );
+        }
+    }
+
+    #[test]
+    fn adam_step() {
+        let mut adam = Adam::new(0.1, 3);
+        let mut params = [1.0, 1.0, 1.0];
+
+        // With bias correction, each step moves by the learning rate against the gradient
+        for k in 1..=3 {
+            adam.step(&mut params, &[0.5, -2.0, 0.0]);
+            let moved = 0.1 * k as f32;
+            assert!((params[0] - (1.0 - moved)).abs() < 1e-5, "{:?}", params);
+            assert!((params[1] - (1.0 + moved)).abs() < 1e-5, "{:?}", params);
+            assert_eq!(params[2], 1.0);
+        }
+
+        // The momentum outweighs a reversed gradient at first
+        let before = params[0];
+        adam.step(&mut params, &[-0.5, -2.0, 0.0]);
+        assert!(params[0] < before);
+    }
+
+    #[test]
+    fn training_reduces_the_loss() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut mlp = Mlp::new(2, 8, &mut rng);
+        let (inputs, _) = random_data(2, 64, &mut rng);
+        let targets: Vec<f32> = inputs
+            .iter()
+            .map(|x| (0.5 * x[0] - 0.3 * x[1]).tanh())
+            .collect();
+
+        let mut adam = Adam::new(0.01, mlp.weights.params.len());
+        let mut grad = vec![0.0; mlp.weights.params.len()];
+        let initial = mlp.loss_and_grad(&inputs, &targets, &mut grad);
+        for _ in 0..500 {
+            mlp.loss_and_grad(&inputs, &targets, &mut grad);
+            adam.step(&mut mlp.weights.params, &grad);
+        }
+        let last = mlp.loss_and_grad(&inputs, &targets, &mut grad);
+        assert!(last < 0.1 * initial, "{} -> {}", initial, last);
+    }
+
+    #[test]
+    fn dataset_uses_the_history_of_each_episode() {
+        let episodes = vec![
+            vec![(1.0, 0.1), (2.0, 0.2), (3.0, 0.3)],
+            vec![(-1.0, -0.1), (-2.0, -0.2)],
+        ];
+        let (inputs, targets) = build_dataset(&episodes, 2);
+        assert_eq!(
+            inputs,
+            [
+                vec![1.0, 1.0],
+                vec![2.0, 1.0],
+                vec![3.0, 2.0],
+                vec![-1.0, -1.0],
+                vec![-2.0, -1.0],
+            ]
+        );
+        assert_eq!(targets, [0.1, 0.2, 0.3, -0.1, -0.2]);
+    }
+
+    #[test]
+    fn blob_is_read_by_the_device_policy() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mlp = Mlp::new(3, 5, &mut rng);
+        let mut policy = MlpPolicy::from_bytes(&mlp.weights.to_bytes().unwrap()).unwrap();
+
+        // The device sees the observations one by one and keeps its own history
+        let episode: Vec<(f32, f32)> = [0.3, -0.1, 0.7, 1.2].iter().map(|&o| (o, 0.0)).collect();
+        let (inputs, _) = build_dataset(std::slice::from_ref(&episode), 3);
+        let mut h = vec![0.0; 5];
+        for ((obs, _), x) in episode.iter().zip(&inputs) {
+            let expected = mlp.forward(x, &mut h);
+            assert!((policy.next_action(*obs) - expected).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn parameters_message() {
+        let model = Mlp::new(2, 2, &mut StdRng::seed_from_u64(4))
+            .weights
+            .to_bytes()
+            .unwrap();
+        let bytes = Message::Parameters(Parameters {
+            version: 3,
+            model: model.clone(),
+        })
+        .encode()
+        .unwrap();
+        match Message::decode(&bytes).unwrap() {
+            Message::Parameters(parameters) => {
+                assert_eq!(parameters.version, 3);
+                assert_eq!(parameters.model, model);
+            }
+            message => panic!("Unexpected message {:?}", message),
+        }
+    }
+}
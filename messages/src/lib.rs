@@ -0,0 +1,366 @@
+//! Messages exchanged between the pendulum devices and the host over MQTT.
+//!
+//! Messages are encoded with [postcard](https://docs.rs/postcard) on the wire, and can be
+//! converted to JSON for debugging. Each encoded message starts with [`SCHEMA_VERSION`] so
+//! that a receiver can tell messages of an older schema from those of a newer one.
+//!
+//! postcard is not self-describing, so the schema changes as follows:
+//!
+//! * New variants of enums such as [`Message`] and [`Command`] are added at the end, which
+//!   keeps the encoding of the existing ones.
+//! * Any other change (a new field, a changed type) bumps [`SCHEMA_VERSION`], and the types of
+//!   the previous version are kept in a module `v<N>` to decode older messages.
+//!
+//! This crate does not depend on ESP-IDF so that it can be used on both sides and checked on
+//! the host.
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+mod upload;
+pub mod v1;
+
+pub use upload::{Reassembler, Upload, UploadAck, UploadChunk, UploadQuery};
+
+/// Version of the schema written at the head of every encoded message.
+pub const SCHEMA_VERSION: u16 = 2;
+
+/// Kind of policy that generated an episode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EpisodeKind {
+    Manual,
+    Auto,
+    Replay,
+    Blended,
+}
+
+/// Steps of a whole episode. Episodes too large for a single MQTT message are sent through an
+/// [`Upload`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EpisodeChunk {
+    /// Id of the episode, unique per device.
+    pub episode: u16,
+    pub kind: EpisodeKind,
+    pub observations: Vec<f32>,
+    pub actions: Vec<f32>,
+}
+
+/// Periodic status of a device.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+    /// Application state, e.g. `Idle`.
+    pub state: String,
+    /// Version of the model parameters in use, 0 if none.
+    pub policy_version: u32,
+    /// Number of episodes in the buffer.
+    pub episodes: u16,
+    pub free_heap: u32,
+    pub uptime_ms: u64,
+}
+
+/// Model parameters delivered to the devices.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Parameters {
+    pub version: u32,
+    /// Serialized model, see `bc_trainer`.
+    pub model: Vec<u8>,
+}
+
+/// Request from the host to a device.
+///
+/// `pendulum1` does not receive these yet. Its serial console accepts the same requests.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Command {
+    RunAuto { steps: u32 },
+    SendEpisodes,
+    ClearEpisodes,
+    ReceiveParameters,
+    Cancel,
+}
+
+/// Reply to a [`Command`] or to received [`Parameters`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Ack {
+    /// Identifier given by the sender of the request.
+    pub id: u32,
+    /// Reason of the failure, `None` on success.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Message {
+    EpisodeChunk(EpisodeChunk),
+    Status(Status),
+    Parameters(Parameters),
+    Command { id: u32, command: Command },
+    Ack(Ack),
+    UploadChunk(UploadChunk),
+    UploadQuery(UploadQuery),
+    UploadAck(UploadAck),
+}
+
+impl Message {
+    /// Encode with postcard, prefixed by the schema version.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut bytes = postcard::to_stdvec(&SCHEMA_VERSION)?;
+        bytes.extend(postcard::to_stdvec(self)?);
+        Ok(bytes)
+    }
+
+    /// Decode a message encoded by [`Message::encode`] of this or an older schema.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let (version, rest) =
+            postcard::take_from_bytes::<u16>(bytes).context("Missing schema version")?;
+        let message = match version {
+            SCHEMA_VERSION => postcard::from_bytes(rest)?,
+            1 => postcard::from_bytes::<v1::Message>(rest)?.try_into()?,
+            v if v > SCHEMA_VERSION => {
+                bail!("Schema version {v} is newer than {SCHEMA_VERSION}, update this program")
+            }
+            v => bail!("Unsupported schema version {v}"),
+        };
+        Ok(message)
+    }
+
+    /// JSON for debugging, with the schema version.
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(&serde_json::json!({
+            "version": SCHEMA_VERSION,
+            "message": self,
+        }))?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct Versioned {
+            version: u16,
+            message: serde_json::Value,
+        }
+
+        let versioned: Versioned = serde_json::from_str(json)?;
+        match versioned.version {
+            SCHEMA_VERSION => Ok(serde_json::from_value(versioned.message)?),
+            1 => serde_json::from_value::<v1::Message>(versioned.message)?.try_into(),
+            v => bail!("Unsupported schema version {v}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One message of every variant.
+    fn samples() -> Vec<Message> {
+        vec![
+            Message::EpisodeChunk(EpisodeChunk {
+                episode: 3,
+                kind: EpisodeKind::Blended,
+                observations: vec![0.5, -1.0],
+                actions: vec![0.25],
+            }),
+            Message::Status(Status {
+                state: "Idle".to_string(),
+                policy_version: 2,
+                episodes: 1,
+                free_heap: 200,
+                uptime_ms: 1000,
+            }),
+            Message::Parameters(Parameters {
+                version: 7,
+                model: vec![1, 2, 3],
+            }),
+            Message::Command {
+                id: 9,
+                command: Command::RunAuto { steps: 300 },
+            },
+            Message::Command {
+                id: 10,
+                command: Command::SendEpisodes,
+            },
+            Message::Command {
+                id: 11,
+                command: Command::ClearEpisodes,
+            },
+            Message::Command {
+                id: 12,
+                command: Command::ReceiveParameters,
+            },
+            Message::Command {
+                id: 13,
+                command: Command::Cancel,
+            },
+            Message::Ack(Ack { id: 9, error: None }),
+            Message::Ack(Ack {
+                id: 10,
+                error: Some("busy".to_string()),
+            }),
+            Message::UploadChunk(UploadChunk {
+                upload: 5,
+                seq: 1,
+                num_chunks: 2,
+                crc32: crc32fast::hash(b"ab"),
+                data: b"ab".to_vec(),
+            }),
+            Message::UploadQuery(UploadQuery {
+                upload: 5,
+                num_chunks: 2,
+            }),
+            Message::UploadAck(UploadAck {
+                upload: 5,
+                missing: vec![0, 1],
+            }),
+        ]
+    }
+
+    /// Encoding of `samples` in schema version 1, where episodes had a part, the number of parts
+    /// and the first step. Devices running older firmware still send these.
+    const GOLDEN_V1: [&[u8]; 13] = [
+        &[
+            1, 0, 3, 3, 0, 1, 0, 2, 0, 0, 0, 63, 0, 0, 128, 191, 1, 0, 0, 128, 62,
+        ],
+        &[1, 1, 4, 73, 100, 108, 101, 2, 1, 200, 1, 232, 7],
+        &[1, 2, 7, 3, 1, 2, 3],
+        &[1, 3, 9, 0, 172, 2],
+        &[1, 3, 10, 1],
+        &[1, 3, 11, 2],
+        &[1, 3, 12, 3],
+        &[1, 3, 13, 4],
+        &[1, 4, 9, 0],
+        &[1, 4, 10, 1, 4, 98, 117, 115, 121],
+        &[1, 5, 5, 1, 2, 237, 144, 141, 244, 9, 2, 97, 98],
+        &[1, 6, 5, 2],
+        &[1, 7, 5, 2, 0, 1],
+    ];
+
+    /// Encoding of `samples` in schema version 2. These must not change while the version
+    /// stays the same, or deployed devices and the host stop understanding each other.
+    const GOLDEN_V2: [&[u8]; 13] = [
+        &[2, 0, 3, 3, 2, 0, 0, 0, 63, 0, 0, 128, 191, 1, 0, 0, 128, 62],
+        &[2, 1, 4, 73, 100, 108, 101, 2, 1, 200, 1, 232, 7],
+        &[2, 2, 7, 3, 1, 2, 3],
+        &[2, 3, 9, 0, 172, 2],
+        &[2, 3, 10, 1],
+        &[2, 3, 11, 2],
+        &[2, 3, 12, 3],
+        &[2, 3, 13, 4],
+        &[2, 4, 9, 0],
+        &[2, 4, 10, 1, 4, 98, 117, 115, 121],
+        &[2, 5, 5, 1, 2, 237, 144, 141, 244, 9, 2, 97, 98],
+        &[2, 6, 5, 2],
+        &[2, 7, 5, 2, 0, 1],
+    ];
+
+    #[test]
+    fn postcard_round_trip() {
+        for message in samples() {
+            let bytes = message.encode().unwrap();
+            assert_eq!(Message::decode(&bytes).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn json_round_trip() {
+        for message in samples() {
+            let json = message.to_json().unwrap();
+            assert_eq!(Message::from_json(&json).unwrap(), message, "{}", json);
+        }
+    }
+
+    #[test]
+    fn golden_v2() {
+        assert_eq!(SCHEMA_VERSION, 2);
+        for (message, golden) in samples().iter().zip(GOLDEN_V2) {
+            assert_eq!(message.encode().unwrap(), golden, "{:?}", message);
+            assert_eq!(&Message::decode(golden).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn decode_v1() {
+        for (message, golden) in samples().iter().zip(GOLDEN_V1) {
+            assert_eq!(&Message::decode(golden).unwrap(), message);
+        }
+
+        let json = r#"{"version":1,"message":{"EpisodeChunk":{"episode":3,"kind":"Blended",
+            "part":0,"num_parts":1,"start_step":0,"observations":[0.5,-1.0],"actions":[0.25]}}}"#;
+        assert_eq!(Message::from_json(json).unwrap(), samples()[0]);
+    }
+
+    #[test]
+    fn reject_v1_episode_parts() {
+        let chunk = v1::EpisodeChunk {
+            episode: 3,
+            kind: EpisodeKind::Manual,
+            part: 1,
+            num_parts: 2,
+            start_step: 100,
+            observations: vec![0.5],
+            actions: vec![0.25],
+        };
+        let mut bytes = postcard::to_stdvec(&1u16).unwrap();
+        bytes.extend(postcard::to_stdvec(&v1::Message::EpisodeChunk(chunk)).unwrap());
+        let error = Message::decode(&bytes).unwrap_err().to_string();
+        assert!(error.contains("not a whole episode"), "{}", error);
+    }
+
+    #[test]
+    fn json_carries_the_version() {
+        let json = Message::Ack(Ack { id: 1, error: None }).to_json().unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["version"], 2);
+        assert_eq!(value["message"]["Ack"]["id"], 1);
+    }
+
+    #[test]
+    fn reject_a_newer_version() {
+        let mut bytes = samples()[1].encode().unwrap();
+        bytes[0] = 3;
+        let error = Message::decode(&bytes).unwrap_err().to_string();
+        assert!(error.contains("newer"), "{}", error);
+
+        // A varint version beyond a single byte
+        let mut bytes = postcard::to_stdvec(&300u16).unwrap();
+        bytes.extend_from_slice(&GOLDEN_V2[1][1..]);
+        assert!(Message::decode(&bytes).is_err());
+
+        let json = samples()[1]
+            .to_json()
+            .unwrap()
+            .replace("\"version\":2", "\"version\":3");
+        assert!(Message::from_json(&json).is_err());
+    }
+
+    #[test]
+    fn reject_an_unknown_older_version() {
+        let mut bytes = samples()[1].encode().unwrap();
+        bytes[0] = 0;
+        let error = Message::decode(&bytes).unwrap_err().to_string();
+        assert!(error.contains("Unsupported"), "{}", error);
+    }
+
+    #[test]
+    fn reject_a_missing_version() {
+        assert!(Message::decode(&[]).is_err());
+        // A message of an unknown variant, as if the version were left out
+        assert!(Message::decode(&GOLDEN_V2[1][1..]).is_err());
+        // A truncated varint
+        assert!(Message::decode(&[0x80]).is_err());
+
+        let json = serde_json::json!({ "message": samples()[1] }).to_string();
+        assert!(Message::from_json(&json).is_err());
+    }
+
+    #[test]
+    fn reject_truncated_and_unknown_messages() {
+        for golden in GOLDEN_V1.iter().chain(&GOLDEN_V2) {
+            assert!(
+                Message::decode(&golden[..golden.len() - 1]).is_err(),
+                "{:?}",
+                golden
+            );
+        }
+        assert!(Message::decode(&[1, 8]).is_err());
+        assert!(Message::decode(&[2, 8]).is_err());
+        assert!(Message::decode(&[2, 3, 9, 5]).is_err());
+    }
+}
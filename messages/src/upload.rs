@@ -0,0 +1,401 @@
+//! Chunked, resumable upload of large messages such as episodes.
+//!
+//! The sender splits the data into chunks with CRCs and asks the receiver which chunks are
+//! missing with an [`UploadQuery`]. The receiver answers with an [`UploadAck`] listing them,
+//! and the sender sends only those until nothing is missing. Uploads are identified by an id
+//! chosen by the sender, so an upload interrupted by a reconnect resumes where it stopped as
+//! long as both sides keep their state. The CRCs only check the integrity of the chunks.
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Number of completed uploads remembered by [`Reassembler`] to answer repeated queries.
+const COMPLETED_CAPACITY: usize = 64;
+
+/// Number of incomplete uploads kept by [`Reassembler`]. The oldest one is discarded to start
+/// another, e.g. when a sender gave up an upload.
+const PARTIAL_CAPACITY: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UploadChunk {
+    pub upload: u32,
+    pub seq: u16,
+    pub num_chunks: u16,
+    /// CRC-32 of `data`.
+    pub crc32: u32,
+    pub data: Vec<u8>,
+}
+
+impl UploadChunk {
+    pub fn is_valid(&self) -> bool {
+        self.seq < self.num_chunks && crc32fast::hash(&self.data) == self.crc32
+    }
+}
+
+/// Request from the sender for the missing chunks.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UploadQuery {
+    pub upload: u32,
+    pub num_chunks: u16,
+}
+
+/// Chunks the receiver still needs. The upload is complete when `missing` is empty.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UploadAck {
+    pub upload: u32,
+    pub missing: Vec<u16>,
+}
+
+/// Sender side of an upload.
+#[derive(Debug, Clone)]
+pub struct Upload {
+    id: u32,
+    chunks: Vec<UploadChunk>,
+    acked: Vec<bool>,
+}
+
+impl Upload {
+    /// Split `data` into chunks of at most `chunk_size` bytes. `id` must differ from those of
+    /// the other uploads the receiver remembers, e.g. a random number.
+    pub fn new(id: u32, data: &[u8], chunk_size: usize) -> Self {
+        let parts: Vec<&[u8]> = if data.is_empty() {
+            vec![data]
+        } else {
+            data.chunks(chunk_size.max(1)).collect()
+        };
+        assert!(parts.len() <= u16::MAX as usize, "Too many chunks");
+
+        let num_chunks = parts.len() as u16;
+        let chunks: Vec<_> = parts
+            .into_iter()
+            .enumerate()
+            .map(|(seq, data)| UploadChunk {
+                upload: id,
+                seq: seq as u16,
+                num_chunks,
+                crc32: crc32fast::hash(data),
+                data: data.to_vec(),
+            })
+            .collect();
+        let acked = vec![false; chunks.len()];
+
+        Upload { id, chunks, acked }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn num_chunks(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Whether this upload carries `data`, e.g. to resume it.
+    pub fn carries(&self, data: &[u8]) -> bool {
+        let mut rest = data;
+        for chunk in &self.chunks {
+            match rest.strip_prefix(chunk.data.as_slice()) {
+                Some(r) => rest = r,
+                None => return false,
+            }
+        }
+        rest.is_empty()
+    }
+
+    /// Total size of the data in bytes.
+    pub fn data_len(&self) -> usize {
+        self.chunks.iter().map(|chunk| chunk.data.len()).sum()
+    }
+
+    pub fn query(&self) -> UploadQuery {
+        UploadQuery {
+            upload: self.id,
+            num_chunks: self.chunks.len() as u16,
+        }
+    }
+
+    /// Apply an ack. Returns `false` if it is for another upload.
+    pub fn on_ack(&mut self, ack: &UploadAck) -> bool {
+        if ack.upload != self.id {
+            return false;
+        }
+        self.acked.fill(true);
+        for &seq in &ack.missing {
+            if let Some(acked) = self.acked.get_mut(seq as usize) {
+                *acked = false;
+            }
+        }
+        true
+    }
+
+    /// Chunks not acknowledged yet.
+    pub fn pending(&self) -> impl Iterator<Item = &UploadChunk> {
+        self.chunks
+            .iter()
+            .zip(&self.acked)
+            .filter(|(_, acked)| !**acked)
+            .map(|(chunk, _)| chunk)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.acked.iter().all(|acked| *acked)
+    }
+}
+
+/// Receiver side of uploads.
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    /// Incomplete uploads, oldest first.
+    partial: VecDeque<(u32, Vec<Option<Vec<u8>>>)>,
+    completed: VecDeque<u32>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store a chunk. Returns the data once all chunks of the upload are received. Invalid
+    /// chunks are dropped and requested again by the next ack.
+    pub fn on_chunk(&mut self, chunk: UploadChunk) -> Option<Vec<u8>> {
+        if !chunk.is_valid() || self.completed.contains(&chunk.upload) {
+            return None;
+        }
+        let index = match self.find(chunk.upload) {
+            Some(index) => index,
+            None => self.start(chunk.upload, chunk.num_chunks),
+        };
+        let chunks = &mut self.partial[index].1;
+        if chunks.len() != chunk.num_chunks as usize {
+            return None;
+        }
+        chunks[chunk.seq as usize] = Some(chunk.data);
+        if chunks.iter().any(Option::is_none) {
+            return None;
+        }
+
+        let (_, chunks) = self.partial.remove(index)?;
+        if self.completed.len() == COMPLETED_CAPACITY {
+            self.completed.pop_front();
+        }
+        self.completed.push_back(chunk.upload);
+        Some(chunks.into_iter().flatten().flatten().collect())
+    }
+
+    /// Answer a query with the missing chunks. Chunks received with another number of chunks
+    /// than the query are discarded, as they belong to another upload with the same id.
+    pub fn on_query(&mut self, query: &UploadQuery) -> UploadAck {
+        let missing = if self.completed.contains(&query.upload) {
+            Vec::new()
+        } else {
+            match self.find(query.upload) {
+                Some(index) if self.partial[index].1.len() == query.num_chunks as usize => {
+                    let chunks = &self.partial[index].1;
+                    (0..chunks.len() as u16)
+                        .filter(|&seq| chunks[seq as usize].is_none())
+                        .collect()
+                }
+                found => {
+                    if let Some(index) = found {
+                        self.partial.remove(index);
+                    }
+                    (0..query.num_chunks.max(1)).collect()
+                }
+            }
+        };
+
+        UploadAck {
+            upload: query.upload,
+            missing,
+        }
+    }
+
+    /// Number of incomplete uploads.
+    pub fn num_partial(&self) -> usize {
+        self.partial.len()
+    }
+
+    fn find(&self, upload: u32) -> Option<usize> {
+        self.partial.iter().position(|(id, _)| *id == upload)
+    }
+
+    /// Start an upload, discarding the oldest one if there are too many. Returns its index.
+    fn start(&mut self, upload: u32, num_chunks: u16) -> usize {
+        if self.partial.len() == PARTIAL_CAPACITY {
+            self.partial.pop_front();
+        }
+        self.partial
+            .push_back((upload, vec![None; num_chunks as usize]));
+        self.partial.len() - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7) as u8).collect()
+    }
+
+    /// Send the pending chunks except those in `lost` and apply the ack of the next query.
+    /// Returns the data once reassembled.
+    fn round(upload: &mut Upload, receiver: &mut Reassembler, lost: &[u16]) -> Option<Vec<u8>> {
+        let ack = receiver.on_query(&upload.query());
+        assert!(upload.on_ack(&ack));
+        let mut received = None;
+        for chunk in upload.pending().cloned().collect::<Vec<_>>() {
+            if !lost.contains(&chunk.seq) {
+                received = received.or(receiver.on_chunk(chunk));
+            }
+        }
+        received
+    }
+
+    #[test]
+    fn upload_in_one_round() {
+        let data = data(10);
+        let mut upload = Upload::new(1, &data, 4);
+        assert_eq!(upload.num_chunks(), 3);
+        assert_eq!(upload.data_len(), 10);
+        assert!(!upload.is_complete());
+
+        let mut receiver = Reassembler::new();
+        assert_eq!(round(&mut upload, &mut receiver, &[]), Some(data));
+        assert!(upload.on_ack(&receiver.on_query(&upload.query())));
+        assert!(upload.is_complete());
+        assert_eq!(upload.pending().count(), 0);
+        assert_eq!(receiver.num_partial(), 0);
+    }
+
+    #[test]
+    fn empty_data() {
+        let mut upload = Upload::new(1, &[], 4);
+        assert_eq!(upload.num_chunks(), 1);
+        assert!(upload.carries(&[]));
+
+        let mut receiver = Reassembler::new();
+        assert_eq!(round(&mut upload, &mut receiver, &[]), Some(Vec::new()));
+    }
+
+    #[test]
+    fn lost_chunks_are_requested_again() {
+        let data = data(10);
+        let mut upload = Upload::new(1, &data, 4);
+        let mut receiver = Reassembler::new();
+
+        assert_eq!(round(&mut upload, &mut receiver, &[0, 2]), None);
+        let ack = receiver.on_query(&upload.query());
+        assert_eq!(ack.missing, [0, 2]);
+        assert!(upload.on_ack(&ack));
+        let pending: Vec<_> = upload.pending().map(|chunk| chunk.seq).collect();
+        assert_eq!(pending, [0, 2]);
+
+        assert_eq!(round(&mut upload, &mut receiver, &[]), Some(data));
+    }
+
+    #[test]
+    fn corrupt_chunks_are_requested_again() {
+        let data = data(10);
+        let upload = Upload::new(1, &data, 4);
+        let mut receiver = Reassembler::new();
+        receiver.on_query(&upload.query());
+
+        let mut chunks: Vec<_> = upload.pending().cloned().collect();
+        chunks[1].data[0] ^= 1;
+        assert!(!chunks[1].is_valid());
+        for chunk in chunks {
+            assert_eq!(receiver.on_chunk(chunk), None);
+        }
+        assert_eq!(receiver.on_query(&upload.query()).missing, [1]);
+    }
+
+    #[test]
+    fn resume_after_a_reconnect() {
+        let data = data(20);
+        let mut upload = Upload::new(7, &data, 4);
+        let mut receiver = Reassembler::new();
+        assert_eq!(round(&mut upload, &mut receiver, &[1, 3, 4]), None);
+
+        // The connection drops before the ack, and the sender keeps the upload to resume it
+        assert!(upload.carries(&data));
+        assert!(!upload.carries(&data[..19]));
+        assert!(!upload.carries(&[data.as_slice(), &[0]].concat()));
+        assert!(!upload.carries(&[0; 20]));
+
+        let ack = receiver.on_query(&upload.query());
+        assert_eq!(ack.missing, [1, 3, 4]);
+        assert_eq!(round(&mut upload, &mut receiver, &[]), Some(data));
+    }
+
+    #[test]
+    fn completed_uploads_are_not_received_again() {
+        let data = data(10);
+        let mut upload = Upload::new(1, &data, 4);
+        let mut receiver = Reassembler::new();
+        assert!(round(&mut upload, &mut receiver, &[]).is_some());
+
+        // A sender that lost the ack sends the chunks again
+        let mut again = Upload::new(1, &data, 4);
+        assert!(again.on_ack(&receiver.on_query(&again.query())));
+        assert!(again.is_complete());
+        for chunk in upload.pending().cloned().collect::<Vec<_>>() {
+            assert_eq!(receiver.on_chunk(chunk), None);
+        }
+        assert_eq!(receiver.num_partial(), 0);
+
+        // Another upload of the same data has another id
+        let mut other = Upload::new(2, &data, 4);
+        assert_eq!(round(&mut other, &mut receiver, &[]), Some(data));
+    }
+
+    #[test]
+    fn acks_of_other_uploads_are_ignored() {
+        let mut upload = Upload::new(1, &data(10), 4);
+        let ack = UploadAck {
+            upload: 2,
+            missing: Vec::new(),
+        };
+        assert!(!upload.on_ack(&ack));
+        assert!(!upload.is_complete());
+    }
+
+    #[test]
+    fn num_chunks_mismatch() {
+        let mut receiver = Reassembler::new();
+        let first = Upload::new(1, &data(10), 4);
+        let chunk = first.pending().next().unwrap().clone();
+        assert_eq!(receiver.on_chunk(chunk), None);
+
+        // A chunk of another upload with the same id does not mix with the first one
+        let mut second = Upload::new(1, &data(10), 5);
+        let chunk = second.pending().next().unwrap().clone();
+        assert_eq!(receiver.on_chunk(chunk), None);
+        assert_eq!(receiver.on_query(&first.query()).missing, [1, 2]);
+
+        // A query with another number of chunks starts over
+        assert_eq!(receiver.on_query(&second.query()).missing, [0, 1]);
+        assert_eq!(round(&mut second, &mut receiver, &[]), Some(data(10)));
+
+        let mut invalid = first.pending().next().unwrap().clone();
+        invalid.seq = invalid.num_chunks;
+        assert!(!invalid.is_valid());
+    }
+
+    #[test]
+    fn incomplete_uploads_are_bounded() {
+        let mut receiver = Reassembler::new();
+        let uploads: Vec<_> = (0..PARTIAL_CAPACITY as u32 + 2)
+            .map(|id| Upload::new(id, &data(10), 4))
+            .collect();
+        for upload in &uploads {
+            let chunk = upload.pending().next().unwrap().clone();
+            receiver.on_chunk(chunk);
+            assert!(receiver.num_partial() <= PARTIAL_CAPACITY);
+        }
+        assert_eq!(receiver.num_partial(), PARTIAL_CAPACITY);
+
+        // The oldest ones were discarded and start over
+        assert_eq!(receiver.on_query(&uploads[0].query()).missing, [0, 1, 2]);
+        assert_eq!(receiver.on_query(&uploads[5].query()).missing, [1, 2]);
+    }
+}
@@ -0,0 +1,63 @@
+//! Types of schema version 1, kept to decode messages of older devices.
+use crate::{Ack, Command, EpisodeKind, Parameters, Status, UploadAck, UploadChunk, UploadQuery};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+/// Steps of an episode, which could be split into parts.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EpisodeChunk {
+    pub episode: u16,
+    pub kind: EpisodeKind,
+    pub part: u16,
+    pub num_parts: u16,
+    pub start_step: u32,
+    pub observations: Vec<f32>,
+    pub actions: Vec<f32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Message {
+    EpisodeChunk(EpisodeChunk),
+    Status(Status),
+    Parameters(Parameters),
+    Command { id: u32, command: Command },
+    Ack(Ack),
+    UploadChunk(UploadChunk),
+    UploadQuery(UploadQuery),
+    UploadAck(UploadAck),
+}
+
+impl TryFrom<Message> for crate::Message {
+    type Error = anyhow::Error;
+
+    /// Convert to the current schema. Only whole episodes can be converted, which is all that
+    /// `pendulum1` sent in this version.
+    fn try_from(message: Message) -> Result<Self> {
+        let message = match message {
+            Message::EpisodeChunk(chunk) => {
+                if chunk.part != 0 || chunk.num_parts != 1 || chunk.start_step != 0 {
+                    bail!(
+                        "Part {} of {} of episode {} is not a whole episode",
+                        chunk.part,
+                        chunk.num_parts,
+                        chunk.episode
+                    );
+                }
+                crate::Message::EpisodeChunk(crate::EpisodeChunk {
+                    episode: chunk.episode,
+                    kind: chunk.kind,
+                    observations: chunk.observations,
+                    actions: chunk.actions,
+                })
+            }
+            Message::Status(status) => crate::Message::Status(status),
+            Message::Parameters(parameters) => crate::Message::Parameters(parameters),
+            Message::Command { id, command } => crate::Message::Command { id, command },
+            Message::Ack(ack) => crate::Message::Ack(ack),
+            Message::UploadChunk(chunk) => crate::Message::UploadChunk(chunk),
+            Message::UploadQuery(query) => crate::Message::UploadQuery(query),
+            Message::UploadAck(ack) => crate::Message::UploadAck(ack),
+        };
+        Ok(message)
+    }
+}
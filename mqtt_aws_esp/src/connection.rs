@@ -0,0 +1,177 @@
+//! MQTT connection that survives disconnects.
+//!
+//! ESP-MQTT reconnects by itself, but subscriptions of a clean session are lost and messages
+//! published while offline fail. [`ConnectionManager`] tracks the state from the connection
+//! events, resubscribes after a reconnect and keeps outbound messages in an
+//! [`OfflineQueue`] until the broker is back.
+use crate::offline_queue::{DropPolicy, OfflineQueue};
+use anyhow::Result;
+use esp_idf_svc::mqtt::client::{EspMqttConnection, QoS};
+use log::{error, info, warn};
+use secrets::{ConnectionState, MqttClient, TlsCredentials};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use topic_router::Router;
+
+/// Number of messages kept while offline.
+const QUEUE_CAPACITY: usize = 32;
+
+#[derive(Debug)]
+struct Message {
+    topic: String,
+    qos: QoS,
+    retain: bool,
+    payload: Vec<u8>,
+}
+
+pub struct ConnectionManager {
+    client: MqttClient,
+    state: Arc<ConnectionState>,
+    router: Arc<Mutex<Router>>,
+    /// Thread pumping the current connection.
+    pump: Option<JoinHandle<()>>,
+    /// Session the subscriptions were last made in.
+    session: u32,
+    subscriptions: Vec<(String, QoS)>,
+    queue: OfflineQueue<Message>,
+}
+
+/// Pump `conn`, dispatching received messages by `router`.
+fn spawn_router_pump(
+    conn: EspMqttConnection,
+    state: Arc<ConnectionState>,
+    router: Arc<Mutex<Router>>,
+) -> Result<JoinHandle<()>> {
+    info!("MQTT Listening for messages");
+    secrets::spawn_pump(conn, state, move |topic, payload| {
+        let handled = match router.lock() {
+            Ok(mut router) => router.dispatch(topic, payload),
+            Err(_) => 0,
+        };
+        if handled == 0 {
+            warn!("No handler for topic \"{topic}\"");
+        }
+    })
+}
+
+impl ConnectionManager {
+    /// Start pumping the connection in a background thread. Received messages are dispatched
+    /// by `router` in that thread.
+    pub fn start(client: MqttClient, conn: EspMqttConnection, router: Router) -> Result<Self> {
+        let state = Arc::new(ConnectionState::default());
+        let router = Arc::new(Mutex::new(router));
+        let pump = spawn_router_pump(conn, state.clone(), router.clone())?;
+
+        Ok(ConnectionManager {
+            client,
+            state,
+            router,
+            pump: Some(pump),
+            session: 0,
+            subscriptions: Vec::new(),
+            queue: OfflineQueue::new(QUEUE_CAPACITY, DropPolicy::DropOldest),
+        })
+    }
+
+    /// Reconnect with new credentials, e.g. after the certificate is rotated. The subscriptions
+    /// are restored and the queued messages sent by `poll` once the new connection is up. On
+    /// error, the current connection is kept.
+    #[allow(dead_code)]
+    pub fn rotate(&mut self, credentials: TlsCredentials) -> Result<()> {
+        let conn = self.client.rotate(credentials)?;
+        // Dropping the old client closed its connection, so wait for its pump to finish
+        // before pumping the new one
+        if let Some(pump) = self.pump.take() {
+            if pump.join().is_err() {
+                error!("The previous MQTT pump panicked");
+            }
+        }
+
+        // Fresh state so that the new session is counted from the start
+        let state = Arc::new(ConnectionState::default());
+        self.pump = Some(spawn_router_pump(conn, state.clone(), self.router.clone())?);
+        self.state = state;
+        self.session = 0;
+        Ok(())
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state.is_connected()
+    }
+
+    /// Number of messages waiting for the broker and discarded so far.
+    pub fn queued(&self) -> (usize, usize) {
+        (self.queue.len(), self.queue.dropped())
+    }
+
+    /// Subscribe now if connected, and again after every reconnect.
+    pub fn subscribe(&mut self, topic: &str, qos: QoS) -> Result<()> {
+        self.subscriptions.retain(|(t, _)| t != topic);
+        self.subscriptions.push((topic.to_string(), qos));
+        if self.is_connected() {
+            self.client.subscribe(topic, qos)?;
+            info!("Subscribed to topic \"{topic}\"");
+        }
+        Ok(())
+    }
+
+    /// Publish a message, or queue it while offline.
+    pub fn publish(&mut self, topic: &str, qos: QoS, retain: bool, payload: &[u8]) {
+        let message = Message {
+            topic: topic.to_string(),
+            qos,
+            retain,
+            payload: payload.to_vec(),
+        };
+        // Keep the order with the messages queued before
+        if self.is_connected() && self.queue.is_empty() {
+            match self.send(&message) {
+                Ok(()) => return,
+                Err(e) => warn!("Failed to publish to \"{topic}\": {e}, queued"),
+            }
+        }
+        if !self.queue.push(message) {
+            warn!("Offline queue is full, {} dropped", self.queue.dropped());
+        }
+    }
+
+    /// Resubscribe after a reconnect and send the queued messages. Call this periodically.
+    pub fn poll(&mut self) {
+        if !self.is_connected() {
+            return;
+        }
+
+        let session = self.state.sessions();
+        if session != self.session {
+            self.session = session;
+            for (topic, qos) in &self.subscriptions {
+                match self.client.subscribe(topic, *qos) {
+                    Ok(_) => info!("Subscribed to topic \"{topic}\""),
+                    Err(e) => {
+                        error!("Failed to subscribe to topic \"{topic}\": {e}, retrying...");
+                        // Retry on the next call
+                        self.session = 0;
+                    }
+                }
+            }
+        }
+
+        while let Some(message) = self.queue.pop() {
+            if let Err(e) = self.send(&message) {
+                warn!("Failed to publish to \"{}\": {e}", message.topic);
+                self.queue.push_front(message);
+                break;
+            }
+        }
+    }
+
+    fn send(&mut self, message: &Message) -> Result<()> {
+        self.client.enqueue(
+            &message.topic,
+            message.qos,
+            message.retain,
+            &message.payload,
+        )?;
+        Ok(())
+    }
+}
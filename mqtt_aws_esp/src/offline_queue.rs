@@ -0,0 +1,155 @@
+//! Bounded queue of outbound messages while the broker is not connected.
+//!
+//! This module does not depend on ESP-IDF so that it can be checked on the host.
+use std::collections::VecDeque;
+
+/// Which message to discard when the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Discard the oldest message, e.g. for telemetry where only recent values matter.
+    DropOldest,
+    /// Discard the new message, keeping the order of what was queued first.
+    DropNewest,
+}
+
+#[derive(Debug)]
+pub struct OfflineQueue<T> {
+    messages: VecDeque<T>,
+    capacity: usize,
+    policy: DropPolicy,
+    dropped: usize,
+}
+
+impl<T> OfflineQueue<T> {
+    pub fn new(capacity: usize, policy: DropPolicy) -> Self {
+        OfflineQueue {
+            messages: VecDeque::with_capacity(capacity),
+            capacity,
+            policy,
+            dropped: 0,
+        }
+    }
+
+    /// Queue a message. Returns `false` if a message was discarded.
+    pub fn push(&mut self, message: T) -> bool {
+        if self.messages.len() < self.capacity {
+            self.messages.push_back(message);
+            return true;
+        }
+
+        self.dropped += 1;
+        if self.policy == DropPolicy::DropOldest && self.capacity > 0 {
+            self.messages.pop_front();
+            self.messages.push_back(message);
+        }
+        false
+    }
+
+    /// Put back a message that failed to be sent, ahead of the others.
+    pub fn push_front(&mut self, message: T) {
+        if self.messages.len() < self.capacity {
+            self.messages.push_front(message);
+        } else {
+            self.dropped += 1;
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        self.messages.pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Number of messages discarded so far.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(queue: &mut OfflineQueue<u32>) -> Vec<u32> {
+        std::iter::from_fn(|| queue.pop()).collect()
+    }
+
+    #[test]
+    fn keeps_the_order() {
+        let mut queue = OfflineQueue::new(3, DropPolicy::DropOldest);
+        assert!(queue.is_empty());
+        assert!(queue.push(1));
+        assert!(queue.push(2));
+        assert_eq!(queue.len(), 2);
+        assert_eq!(drain(&mut queue), [1, 2]);
+        assert!(queue.is_empty());
+        assert_eq!(queue.dropped(), 0);
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let mut queue = OfflineQueue::new(3, DropPolicy::DropOldest);
+        for message in 1..=3 {
+            assert!(queue.push(message));
+        }
+        assert!(!queue.push(4));
+        assert!(!queue.push(5));
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(drain(&mut queue), [3, 4, 5]);
+    }
+
+    #[test]
+    fn drop_newest() {
+        let mut queue = OfflineQueue::new(3, DropPolicy::DropNewest);
+        for message in 1..=3 {
+            assert!(queue.push(message));
+        }
+        assert!(!queue.push(4));
+        assert!(!queue.push(5));
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(drain(&mut queue), [1, 2, 3]);
+    }
+
+    #[test]
+    fn push_front_puts_back_a_failed_message() {
+        let mut queue = OfflineQueue::new(3, DropPolicy::DropOldest);
+        queue.push(1);
+        queue.push(2);
+        let failed = queue.pop().unwrap();
+        queue.push_front(failed);
+        assert_eq!(drain(&mut queue), [1, 2]);
+    }
+
+    #[test]
+    fn push_front_at_capacity() {
+        for policy in [DropPolicy::DropOldest, DropPolicy::DropNewest] {
+            let mut queue = OfflineQueue::new(2, policy);
+            queue.push(1);
+            let failed = queue.pop().unwrap();
+            // Messages queued while sending fill the queue
+            queue.push(2);
+            queue.push(3);
+            queue.push_front(failed);
+            assert_eq!(queue.dropped(), 1);
+            assert_eq!(drain(&mut queue), [2, 3]);
+        }
+    }
+
+    #[test]
+    fn zero_capacity() {
+        for policy in [DropPolicy::DropOldest, DropPolicy::DropNewest] {
+            let mut queue = OfflineQueue::new(0, policy);
+            assert!(!queue.push(1));
+            queue.push_front(2);
+            assert!(queue.is_empty());
+            assert_eq!(queue.dropped(), 2);
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! Receive episodes uploaded by `pendulum1`.
+use anyhow::Result;
+use messages::{Message, Reassembler};
+use rumqttc::{Client, QoS};
+use std::{fs, path::Path, time::SystemTime};
+use topic_router::Router;
+
+const EPISODE_TOPIC: &str = "pendulum1/episodes";
+const ACK_TOPIC: &str = "pendulum1/episodes/ack";
+
+/// Directory the received episodes are saved to as JSON.
+const EPISODE_DIR: &str = "episodes";
+
+fn save_episode(data: &[u8]) -> Result<()> {
+    let json = Message::decode(data)?.to_json()?;
+    let millis = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_millis();
+    fs::create_dir_all(EPISODE_DIR)?;
+    let path = Path::new(EPISODE_DIR).join(format!("{millis}.json"));
+    fs::write(&path, json)?;
+    println!("Saved episode to {}", path.display());
+    Ok(())
+}
+
+/// Reassemble uploaded chunks and answer the queries of the device with the missing chunks.
+pub fn add_receiver(router: &mut Router, client: Client) -> Result<()> {
+    client.subscribe(EPISODE_TOPIC, QoS::AtLeastOnce)?;
+
+    let mut reassembler = Reassembler::new();
+    router.add(EPISODE_TOPIC, move |topic, payload| {
+        match Message::decode(payload) {
+            Ok(Message::UploadChunk(chunk)) => {
+                if let Some(data) = reassembler.on_chunk(chunk) {
+                    if let Err(e) = save_episode(&data) {
+                        println!("Failed to save episode: {e}");
+                    }
+                }
+            }
+            Ok(Message::UploadQuery(query)) => {
+                let ack = reassembler.on_query(&query);
+                println!(
+                    "Upload {:08x}: {} chunks missing",
+                    ack.upload,
+                    ack.missing.len()
+                );
+                // Do not block the loop pumping the connection, the device queries again
+                let result = Message::UploadAck(ack).encode().and_then(|ack| {
+                    Ok(client.try_publish(ACK_TOPIC, QoS::AtLeastOnce, false, ack)?)
+                });
+                if let Err(e) = result {
+                    println!("Failed to send ack: {e}");
+                }
+            }
+            Ok(message) => println!("Unexpected message on {topic}: {message:?}"),
+            Err(e) => println!("Invalid message on {topic}: {e}"),
+        }
+    })
+}
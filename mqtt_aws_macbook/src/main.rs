@@ -1,6 +1,10 @@
+mod episodes;
+
 use anyhow::Result;
-use rumqttc::{Client, Connection, MqttOptions, QoS, TlsConfiguration, Transport};
+use messages::{Command, Message};
+use rumqttc::{Client, Connection, Event, MqttOptions, Packet, QoS, TlsConfiguration, Transport};
 use std::{thread, time::Duration};
+use topic_router::Router;
 
 // Define a function to create an MQTT client and connection
 fn create_mqtt_client(endpoint: &str) -> Result<(Client, Connection)> {
@@ -30,8 +34,13 @@ fn create_mqtt_client(endpoint: &str) -> Result<(Client, Connection)> {
 fn publish(client: Client) {
     thread::sleep(Duration::from_secs(1));
     client.subscribe("hello/+/world", QoS::AtMostOnce).unwrap();
-    for i in 0..3_usize {
-        let payload = vec![1; i];
+    for i in 0..3_u32 {
+        let payload = Message::Command {
+            id: i,
+            command: Command::RunAuto { steps: 100 * i },
+        }
+        .encode()
+        .unwrap();
         let topic = format!("hello/{i}/world");
         println!("{:?}", topic);
         let qos = QoS::AtLeastOnce;
@@ -52,13 +61,26 @@ fn main() -> Result<()> {
     let (client, mut conn) = create_mqtt_client(aws_iot_endpoint)?;
     println!("Connected to AWS IoT Core");
 
+    let mut router = Router::new();
+    episodes::add_receiver(&mut router, client.clone())?;
+
     publish(client);
     println!("Published messages");
 
+    router.add("hello/+/world", |topic, payload| {
+        match Message::decode(payload).and_then(|m| m.to_json()) {
+            Ok(json) => println!("Received {json} on {topic}"),
+            Err(e) => println!("Invalid message on {topic}: {e}"),
+        }
+    })?;
+
     for (i, notification) in conn.iter().enumerate() {
         match notification {
             Ok(notif) => {
                 println!("{i}. Notification = {notif:?}");
+                if let Event::Incoming(Packet::Publish(publish)) = &notif {
+                    router.dispatch(&publish.topic, &publish.payload);
+                }
             }
             Err(error) => {
                 println!("{i}. Notification = {error:?}");
@@ -1,3 +1,16 @@
+use std::{env, fs, path::PathBuf};
+
 fn main() {
     embuild::espidf::sysenv::output();
+
+    // Embed the action sequence for ReplayPolicy given by REPLAY_ACTIONS=<path>, if any
+    println!("cargo:rerun-if-env-changed=REPLAY_ACTIONS");
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("replay_actions.txt");
+    match env::var("REPLAY_ACTIONS") {
+        Ok(path) => {
+            println!("cargo:rerun-if-changed={path}");
+            fs::copy(&path, &out).unwrap_or_else(|e| panic!("Failed to read {path}: {e}"));
+        }
+        Err(_) => fs::write(&out, "").unwrap(),
+    }
 }
@@ -0,0 +1,69 @@
+use crate::env::{PendulumEnv, PendulumEnvAct, PendulumEnvObs};
+use crate::mlp_policy::MlpPolicy;
+use crate::signal_policy::SignalPolicy;
+use anyhow::{bail, Result};
+use border_core::Policy;
+use messages::Parameters;
+
+/// The policy used in the `AutoPolicy` state.
+///
+/// It starts with a signal policy and is replaced with an MLP policy when model parameters are
+/// received from the server.
+pub enum AutoPolicy {
+    Signal(SignalPolicy),
+    Mlp { policy: MlpPolicy, version: u32 },
+}
+
+impl AutoPolicy {
+    /// Version of the model parameters, or 0 for the signal policy.
+    pub fn version(&self) -> u32 {
+        match self {
+            AutoPolicy::Signal(_) => 0,
+            AutoPolicy::Mlp { version, .. } => *version,
+        }
+    }
+
+    /// Replace the policy with a model loaded from `parameters`.
+    ///
+    /// Parameters that are not newer than the current ones are rejected, and the current
+    /// policy is kept if the model cannot be loaded.
+    pub fn load(&mut self, parameters: &Parameters) -> Result<()> {
+        if parameters.version <= self.version() {
+            bail!(
+                "Parameters version {} is not newer than {}",
+                parameters.version,
+                self.version()
+            );
+        }
+        let policy = MlpPolicy::from_bytes(&parameters.model)?;
+        *self = AutoPolicy::Mlp {
+            policy,
+            version: parameters.version,
+        };
+        Ok(())
+    }
+
+    /// Change the step period of a signal policy. An MLP policy only sees the observations, so
+    /// it is left as is.
+    pub fn set_dt(&mut self, dt: f32) {
+        if let AutoPolicy::Signal(policy) = self {
+            policy.set_dt(dt);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        match self {
+            AutoPolicy::Signal(policy) => policy.reset(),
+            AutoPolicy::Mlp { policy, .. } => policy.reset(),
+        }
+    }
+}
+
+impl<'d> Policy<PendulumEnv<'d>> for AutoPolicy {
+    fn sample(&mut self, obs: &PendulumEnvObs) -> PendulumEnvAct {
+        match self {
+            AutoPolicy::Signal(policy) => policy.sample(obs),
+            AutoPolicy::Mlp { policy, .. } => policy.sample(obs),
+        }
+    }
+}
@@ -0,0 +1,32 @@
+use crate::env::{PendulumEnv, PendulumEnvAct, PendulumEnvObs};
+use border_core::Policy;
+pub use pendulum_core::blended_policy::Blend;
+
+/// A policy that mixes the actions of an operator and an automatic policy.
+///
+/// The action is `w * auto + (1 - w) * human`, where `w` is given by [`Blend`]. Both policies
+/// are sampled on every step so that their internal states (filters, noise processes and
+/// histories) stay up to date while the other one is in control.
+pub struct BlendedPolicy<'a, H, A> {
+    human: &'a mut H,
+    auto: &'a mut A,
+    blend: Blend,
+}
+
+impl<'a, H, A> BlendedPolicy<'a, H, A> {
+    pub fn new(human: &'a mut H, auto: &'a mut A, blend: Blend) -> Self {
+        BlendedPolicy { human, auto, blend }
+    }
+}
+
+impl<'a, 'd, H, A> Policy<PendulumEnv<'d>> for BlendedPolicy<'a, H, A>
+where
+    H: Policy<PendulumEnv<'d>>,
+    A: Policy<PendulumEnv<'d>>,
+{
+    fn sample(&mut self, obs: &PendulumEnvObs) -> PendulumEnvAct {
+        let human = self.human.sample(obs).value();
+        let auto = self.auto.sample(obs).value();
+        self.blend.mix(obs.value(), human, auto).into()
+    }
+}
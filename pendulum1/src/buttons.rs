@@ -1,61 +1,41 @@
-/// Handles the buttons.
+//! Handles the buttons.
 use anyhow::Result;
 use esp_idf_svc::hal::gpio::*;
 use esp_idf_svc::hal::peripheral::Peripheral;
-use std::sync::atomic::Ordering;
+use esp_idf_svc::sys::{esp_timer_get_time, gpio_get_level};
+use pendulum_core::button_events::{event_for, input_for, ButtonInput, Edge, EdgeQueue};
+use pendulum_core::state;
+use std::time::Duration;
 
-fn get_state() -> u8 {
-    crate::STATE.load(Ordering::Relaxed)
-}
-
-fn set_state(state: u8) {
-    crate::STATE.store(state, Ordering::Relaxed);
-}
+/// Edges pushed by the interrupt handlers and consumed by the button task.
+static EDGES: EdgeQueue = EdgeQueue::new();
 
-/// Handlers for the reset button.
-fn gpio_interrupt_handler1() {
-    match get_state()
-    {
-        crate::OFFSET_CORRECTION => set_state(crate::OFFSET_CORRECTION_CANCEL),
-        crate::POTENTIOMETER_MIN => set_state(crate::POTENTIOMETER_CANCEL),
-        crate::POTENTIOMETER_MAX => set_state(crate::POTENTIOMETER_CANCEL),
-        crate::AUTO_POLICY => set_state(crate::IDLE),
-        crate::MANUAL_POLICY => set_state(crate::IDLE),
-        crate::IDLE => set_state(crate::TERMINATE),
-        _ => {}, // do nothing
-    }
-}
-
-/// Handlers for the offset correction button.
-fn gpio_interrupt_handler2() {
-    match get_state() {
-        crate::IDLE => set_state(crate::OFFSET_CORRECTION),
-        crate::OFFSET_CORRECTION => set_state(crate::OFFSET_CORRECTION_END),
-        crate::POTENTIOMETER_MIN => set_state(crate::POTENTIOMETER_MAX),
-        crate::POTENTIOMETER_MAX => set_state(crate::IDLE),
-        _ => {}, // do nothing
-    }
-}
+/// Period of the button task.
+const POLL_PERIOD_MS: u64 = 5;
 
-// Handlers for the run episode button.
-fn gpio_interrupt_handler3() {
-    match get_state() {
-        crate::IDLE => set_state(crate::AUTO_POLICY),
-        crate::AUTO_POLICY => set_state(crate::MANUAL_POLICY_START),
-        _ => {}, // do nothing
-    }
+/// Milliseconds since boot, wrapping around after about 49 days.
+fn now_ms() -> u32 {
+    (unsafe { esp_timer_get_time() } / 1000) as u32
 }
 
-fn gpio_interrupt_handler4() {
-    crate::STATE.store(4, Ordering::Relaxed);
+/// Handler for the interrupts of all buttons.
+///
+/// This runs in the interrupt context, so it only records the edge. The buttons are pulled up,
+/// so a pressed button reads low.
+fn gpio_interrupt_handler(button: u8, pin: i32) {
+    let pressed = unsafe { gpio_get_level(pin) } == 0;
+    EDGES.push(Edge {
+        button,
+        pressed,
+        time_ms: now_ms(),
+    });
 }
 
 /// Initialize a button with an interrupt handler.
-fn init_button<T, F>(pin: T, callback: F) -> PinDriver<'static, T::P, Input>
+fn init_button<T>(pin: T, index: u8) -> PinDriver<'static, T::P, Input>
 where
     T: Peripheral + 'static,
     T::P: InputPin + OutputPin,
-    F: FnMut() + Send + 'static,
 {
     // Configure button pin as input
     let mut button = PinDriver::input(pin).unwrap();
@@ -63,11 +43,16 @@ where
     // Configure button pin with internal pull up
     button.set_pull(Pull::Up).unwrap();
 
-    // Configure button pin to detect interrupts on a positive edge
-    button.set_interrupt_type(InterruptType::PosEdge).unwrap();
+    // Configure button pin to detect interrupts on both edges to measure press durations
+    button.set_interrupt_type(InterruptType::AnyEdge).unwrap();
 
     // Attach the ISR to the button interrupt
-    unsafe { button.subscribe(callback).unwrap() }
+    let pin_number = button.pin();
+    unsafe {
+        button
+            .subscribe(move || gpio_interrupt_handler(index, pin_number))
+            .unwrap()
+    }
 
     button
 }
@@ -83,18 +68,19 @@ where
     P3::P: InputPin + OutputPin,
     P4::P: InputPin + OutputPin,
 {
-    /// Button to run an episode.
+    /// Button to cancel the current operation.
     ///
-    /// This button works in the idle state.
+    /// In the idle state, this button terminates the program. A long press removes the saved
+    /// calibration.
     button1: PinDriver<'static, P1::P, Input>,
 
-    /// Button to send episode data to the server.
+    /// Button for the calibration. A long press sends the episode data to the server.
     button2: PinDriver<'static, P2::P, Input>,
 
-    /// Button to receive model parameters from the server.
+    /// Button to run an episode. A long press receives model parameters from the server.
     button3: PinDriver<'static, P3::P, Input>,
 
-    /// Button to clear the episode data.
+    /// Button to clear the episode data with a double press.
     button4: PinDriver<'static, P4::P, Input>,
 }
 
@@ -110,10 +96,10 @@ where
     P4::P: InputPin + OutputPin,
 {
     pub fn new(p1: P1, p2: P2, p3: P3, p4: P4) -> Self {
-        let button1 = init_button(p1, gpio_interrupt_handler1);
-        let button2 = init_button(p2, gpio_interrupt_handler2);
-        let button3 = init_button(p3, gpio_interrupt_handler3);
-        let button4 = init_button(p4, gpio_interrupt_handler4);
+        let button1 = init_button(p1, 0);
+        let button2 = init_button(p2, 1);
+        let button3 = init_button(p3, 2);
+        let button4 = init_button(p4, 3);
 
         Self {
             button1,
@@ -131,4 +117,64 @@ where
         self.button4.enable_interrupt()?;
         Ok(())
     }
+
+    /// Return whether each button is pressed.
+    fn pressed(&self) -> [bool; 4] {
+        [
+            self.button1.is_low(),
+            self.button2.is_low(),
+            self.button3.is_low(),
+            self.button4.is_low(),
+        ]
+    }
+
+    /// Start a task that turns the edges into gestures and dispatches their events.
+    ///
+    /// The task owns the buttons and re-enables their interrupts, which are disabled by the
+    /// driver after each edge.
+    pub fn spawn(mut self) -> Result<()>
+    where
+        Self: Send,
+    {
+        self.enable_interrupt()?;
+        std::thread::Builder::new()
+            .name("buttons".into())
+            .stack_size(4096)
+            .spawn(move || {
+                let mut inputs: [ButtonInput; 4] = std::array::from_fn(input_for);
+                loop {
+                    while let Some(edge) = EDGES.pop() {
+                        if let Some(input) = inputs.get_mut(edge.button as usize) {
+                            input.edge(edge.pressed, edge.time_ms);
+                        }
+                    }
+                    if let Err(e) = self.enable_interrupt() {
+                        log::error!("Failed to enable button interrupts: {:?}", e);
+                    }
+
+                    // Also sample the levels in case an edge was missed while the interrupt
+                    // was disabled
+                    let now = now_ms();
+                    for (input, pressed) in inputs.iter_mut().zip(self.pressed()) {
+                        input.edge(pressed, now);
+                    }
+
+                    for (button, input) in inputs.iter_mut().enumerate() {
+                        let Some(gesture) = input.poll(now) else {
+                            continue;
+                        };
+                        log::info!("Button {}: {:?} press", button + 1, gesture);
+                        match event_for(button, gesture) {
+                            Some(event) => {
+                                state::dispatch(event);
+                            }
+                            None => log::info!("No command for this gesture"),
+                        }
+                    }
+
+                    std::thread::sleep(Duration::from_millis(POLL_PERIOD_MS));
+                }
+            })?;
+        Ok(())
+    }
 }
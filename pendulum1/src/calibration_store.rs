@@ -0,0 +1,52 @@
+//! Stores the calibration in NVS.
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use pendulum_core::calibration::{self, Calibration};
+
+const NAMESPACE: &str = "pendulum1";
+const KEY: &str = "calibration";
+
+pub struct CalibrationStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl CalibrationStore {
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self> {
+        Ok(CalibrationStore {
+            nvs: EspNvs::new(partition, NAMESPACE, true)?,
+        })
+    }
+
+    /// Load the saved calibration.
+    ///
+    /// Returns `None` if nothing is saved, or if the saved value is invalid, e.g. written by
+    /// firmware with another schema version.
+    pub fn load(&self) -> Option<Calibration> {
+        let mut buf = [0; calibration::SIZE];
+        match self.nvs.get_blob(KEY, &mut buf) {
+            Ok(Some(bytes)) => match Calibration::from_bytes(bytes) {
+                Ok(calibration) => Some(calibration),
+                Err(e) => {
+                    log::warn!("Ignoring saved calibration: {:?}", e);
+                    None
+                }
+            },
+            Ok(None) => None,
+            Err(e) => {
+                log::warn!("Failed to read calibration: {:?}", e);
+                None
+            }
+        }
+    }
+
+    pub fn save(&mut self, calibration: &Calibration) -> Result<()> {
+        self.nvs.set_blob(KEY, &calibration.to_bytes())?;
+        Ok(())
+    }
+
+    /// Remove the saved calibration, so that the calibration is required after the next boot.
+    pub fn invalidate(&mut self) -> Result<()> {
+        self.nvs.remove(KEY)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+//! Line-based command console over the serial port.
+use anyhow::Result;
+use pendulum_core::commands::{self, Command, HELP};
+use pendulum_core::state::{self, AppState, Event};
+use pendulum_core::supervisor;
+use std::io::Read;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+
+/// Period to poll the serial port when no input is available.
+const POLL_PERIOD_MS: u64 = 20;
+
+/// Maximum length of a line. Longer lines are discarded.
+const MAX_LINE: usize = 128;
+
+/// Start a task that reads commands from the serial port.
+///
+/// Events are dispatched by the task as the buttons do. Commands that need the devices or the
+/// episodes are passed to the main loop through the returned receiver.
+pub fn spawn() -> Result<Receiver<Command>> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::Builder::new()
+        .name("console".into())
+        .stack_size(4096)
+        .spawn(move || run(sender))?;
+    Ok(receiver)
+}
+
+fn run(sender: Sender<Command>) {
+    let mut stdin = std::io::stdin();
+    let mut line = Vec::with_capacity(MAX_LINE);
+    let mut byte = [0u8];
+    loop {
+        // stdin is non-blocking, so poll it
+        match stdin.read(&mut byte) {
+            Ok(1) => {}
+            _ => {
+                std::thread::sleep(Duration::from_millis(POLL_PERIOD_MS));
+                continue;
+            }
+        }
+
+        match byte[0] {
+            b'\r' | b'\n' => {
+                if line.len() <= MAX_LINE {
+                    handle_line(&String::from_utf8_lossy(&line), &sender);
+                } else {
+                    println!("Line too long");
+                }
+                line.clear();
+            }
+            b => {
+                if line.len() <= MAX_LINE {
+                    line.push(b);
+                }
+            }
+        }
+    }
+}
+
+fn handle_line(line: &str, sender: &Sender<Command>) {
+    match commands::parse(line) {
+        Ok(None) => {}
+        Ok(Some(Command::Help)) => println!("{}", HELP),
+        Ok(Some(Command::State)) => {
+            println!("State: {:?}", state::current());
+            if let Some(trip) = supervisor::last_trip() {
+                println!("Last safe state trip: {:?}", trip);
+            }
+        }
+        Ok(Some(Command::Event(event))) => {
+            if state::dispatch(event).is_none() {
+                println!(
+                    "{:?} is not accepted in state {:?}",
+                    event,
+                    state::current()
+                );
+            }
+        }
+        Ok(Some(Command::Calibrate)) => {
+            if state::current() == AppState::Idle {
+                state::dispatch(Event::Confirm);
+            } else {
+                println!("calibrate is only accepted in the idle state");
+            }
+        }
+        Ok(Some(command)) => {
+            let _ = sender.send(command);
+        }
+        Err(e) => println!("{}", e),
+    }
+}
@@ -1,9 +1,14 @@
+use crate::safe_state;
 use anyhow::Result;
 use as5600::As5600;
 use border_core::{record::Record, Act, Env, Obs, Step};
 use esp_idf_svc::hal::delay::FreeRtos;
 use esp_idf_svc::hal::{i2c::I2cDriver, ledc::LedcDriver};
-use std::sync::atomic::Ordering;
+use pendulum_core::state::{self, AppState, Event};
+use pendulum_shadow::RewardWeights;
+
+/// Time after which a calibration step waiting for the operator is cancelled.
+pub const CALIBRATION_TIMEOUT_MS: u32 = 60_000;
 
 #[derive(Debug, Clone)]
 pub struct PendulumEnvObs {
@@ -57,6 +62,7 @@ pub struct PendulumEnv<'d> {
     offset: f32,
     direction: f32,
     scale: f32,
+    reward: RewardWeights,
 }
 
 impl<'d> Env for PendulumEnv<'d> {
@@ -78,7 +84,10 @@ impl<'d> Env for PendulumEnv<'d> {
         // Take action
         let value = 180.0 * (self.scale * act.value() + 1.0) * 0.5;
         let duty = self.map(value as _);
-        self.motor.set_duty(duty).unwrap();
+        // The watchdog may have driven the servo to the neutral pose while the policy ran
+        if !safe_state::tripped() {
+            self.motor.set_duty(duty).unwrap();
+        }
 
         println!(
             "obs, act, duty = ({:?}, {:?}, {:?})",
@@ -90,7 +99,7 @@ impl<'d> Env for PendulumEnv<'d> {
         let step = Step::new(
             obs,
             act,
-            vec![0.0], // Placeholder for reward
+            vec![self.reward.reward(obs.value(), act.value())],
             vec![0],
             vec![0],
             (),
@@ -134,9 +143,35 @@ impl<'d> PendulumEnv<'d> {
             offset: 0.0,
             direction: 0.0,
             scale: 0.6,
+            reward: RewardWeights::default(),
         }
     }
 
+    /// Offset in radians and direction set by `correct_offset()`.
+    pub fn offset(&self) -> (f32, f32) {
+        (self.offset, self.direction)
+    }
+
+    /// Set the offset and direction, e.g. loaded from a saved calibration.
+    pub fn set_offset(&mut self, offset: f32, direction: f32) {
+        self.offset = offset;
+        self.direction = direction;
+    }
+
+    /// Duty of the servo at the neutral pose.
+    pub fn neutral_duty(&self) -> u32 {
+        self.map(90)
+    }
+
+    /// Set the scale of the action applied to the servo, clamped to [0, 1].
+    pub fn set_scale(&mut self, scale: f32) {
+        self.scale = scale.clamp(0.0, 1.0);
+    }
+
+    pub fn set_reward_weights(&mut self, reward: RewardWeights) {
+        self.reward = reward;
+    }
+
     // Function that maps one range to another
     fn map(&self, x: u32) -> u32 {
         let in_min = 0;
@@ -167,11 +202,12 @@ impl<'d> PendulumEnv<'d> {
         log::info!("Starting offset correction in 1 second...");
         FreeRtos::delay_ms(1000);
 
-        loop {
+        for t in 0.. {
             let angle = self.sensor.angle().unwrap();
             log::info!("Current angle: {} ({})", angle, self.angle());
 
-            if get_state() == crate::OFFSET_CORRECTION_END {
+            let current = state::current();
+            if current == AppState::OffsetCorrectionEnd {
                 self.offset = offset as f32 * std::f32::consts::PI / 2048.0;
                 self.direction = get_direction(angle, offset) as f32;
                 if self.direction == 1.0 {
@@ -182,10 +218,13 @@ impl<'d> PendulumEnv<'d> {
                 log::info!("Offset correction completed.");
                 FreeRtos::delay_ms(1000);
                 break;
-            } else if get_state() == crate::OFFSET_CORRECTION_CANCEL {
+            } else if current != AppState::OffsetCorrection {
                 log::info!("Offset correction cancelled.");
                 FreeRtos::delay_ms(1000);
                 break;
+            } else if t * 100 >= CALIBRATION_TIMEOUT_MS {
+                log::warn!("Offset correction timed out.");
+                state::dispatch(Event::Timeout);
             }
             FreeRtos::delay_ms(100);
         }
@@ -216,7 +255,3 @@ fn get_direction(angle: u16, offset: u16) -> i8 {
         }
     }
 }
-
-fn get_state() -> u8 {
-    crate::STATE.load(Ordering::Relaxed)
-}
@@ -0,0 +1,199 @@
+//! Buffers episodes recorded by `PendulumEvaluator`.
+use crate::replay_policy::ReplayPolicy;
+use messages::{EpisodeChunk, Message};
+
+/// Maximum number of steps kept in RAM over all buffered episodes.
+///
+/// A step takes 8 bytes, so the buffer uses at most 64KB.
+pub const MAX_STEPS: usize = 8192;
+
+/// Which policy generated an episode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EpisodeKind {
+    /// Actions were taken by the automatic policy.
+    Auto,
+
+    /// Actions were taken by the operator with the potentiometer.
+    ///
+    /// These episodes are used as demonstrations for imitation learning.
+    Manual,
+
+    /// Actions of the operator and the automatic policy were blended.
+    Blended,
+
+    /// A recorded action sequence was played back.
+    Replay,
+}
+
+/// An observation and the action taken by the policy for it.
+#[derive(Debug, Clone, Copy)]
+pub struct Transition {
+    pub obs: f32,
+    pub act: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Episode {
+    /// Per-device id, given when the episode is first sent.
+    pub id: Option<u16>,
+    /// Whether the server has received the episode.
+    pub sent: bool,
+    pub kind: EpisodeKind,
+    /// Step period of the control loop in seconds.
+    pub step_period: f32,
+    pub transitions: Vec<Transition>,
+}
+
+impl Episode {
+    pub fn new(kind: EpisodeKind, step_period: f32) -> Self {
+        Episode {
+            id: None,
+            sent: false,
+            kind,
+            step_period,
+            transitions: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.transitions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transitions.is_empty()
+    }
+
+    /// Policy that plays back the actions of this episode.
+    pub fn replay_policy(&self) -> ReplayPolicy {
+        ReplayPolicy::new(
+            self.transitions.iter().map(|tr| tr.act).collect(),
+            self.step_period,
+        )
+    }
+
+    /// Message sent to the server as episode `id`.
+    pub fn to_message(&self, id: u16) -> Message {
+        let kind = match self.kind {
+            EpisodeKind::Auto => messages::EpisodeKind::Auto,
+            EpisodeKind::Manual => messages::EpisodeKind::Manual,
+            EpisodeKind::Blended => messages::EpisodeKind::Blended,
+            EpisodeKind::Replay => messages::EpisodeKind::Replay,
+        };
+
+        Message::EpisodeChunk(EpisodeChunk {
+            episode: id,
+            kind,
+            observations: self.transitions.iter().map(|tr| tr.obs).collect(),
+            actions: self.transitions.iter().map(|tr| tr.act).collect(),
+        })
+    }
+}
+
+/// Episodes kept in RAM until they are sent to the server or cleared.
+#[derive(Default)]
+pub struct EpisodeBuffer {
+    episodes: Vec<Episode>,
+    num_steps: usize,
+}
+
+impl EpisodeBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start recording a new episode stepped every `step_period` seconds.
+    pub fn start(&mut self, kind: EpisodeKind, step_period: f32) {
+        self.episodes.push(Episode::new(kind, step_period));
+    }
+
+    /// Append a transition to the episode being recorded.
+    ///
+    /// Returns `false` if the buffer is full and the transition was dropped.
+    pub fn push(&mut self, obs: f32, act: f32) -> bool {
+        if self.num_steps >= MAX_STEPS {
+            return false;
+        }
+        match self.episodes.last_mut() {
+            Some(episode) => {
+                episode.transitions.push(Transition { obs, act });
+                self.num_steps += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Finish recording the current episode, discarding it if it has no steps.
+    pub fn finish(&mut self) {
+        if self.episodes.last().is_some_and(|e| e.is_empty()) {
+            self.episodes.pop();
+        }
+    }
+
+    pub fn episodes(&self) -> &[Episode] {
+        &self.episodes
+    }
+
+    pub fn episodes_mut(&mut self) -> &mut [Episode] {
+        &mut self.episodes
+    }
+
+    /// Total number of steps over all buffered episodes.
+    pub fn num_steps(&self) -> usize {
+        self.num_steps
+    }
+
+    /// Drop all buffered episodes and release their memory.
+    ///
+    /// Returns the number of bytes freed, counting the allocated capacity of the buffers.
+    pub fn clear(&mut self) -> usize {
+        let freed = self
+            .episodes
+            .iter()
+            .map(|e| e.transitions.capacity() * std::mem::size_of::<Transition>())
+            .sum::<usize>()
+            + self.episodes.capacity() * std::mem::size_of::<Episode>();
+        self.episodes = Vec::new();
+        self.num_steps = 0;
+        freed
+    }
+
+    /// Print an episode to the serial output.
+    ///
+    /// The episode is printed as a `#` comment line followed by a line
+    /// `STEP,<episode>,<step>,<obs>,<act>` per step, which can be passed to
+    /// `ReplayPolicy::from_text` as is. Returns `false` if there is no episode at `index`.
+    pub fn dump_episode(&self, index: usize) -> bool {
+        let Some(episode) = self.episodes.get(index) else {
+            return false;
+        };
+        println!(
+            "# Episode {} ({:?}, {} steps)",
+            index,
+            episode.kind,
+            episode.len()
+        );
+        for (t, tr) in episode.transitions.iter().enumerate() {
+            println!("STEP,{},{},{},{}", index, t, tr.obs, tr.act);
+        }
+        true
+    }
+
+    /// Print a manual episode as a demonstration to the serial output.
+    ///
+    /// Each step is printed as a line `DEMO,<episode>,<step>,<obs>,<act>`, so that the monitor
+    /// output can be saved to a file and passed to `bc_trainer` as is. Returns `false` if there
+    /// is no manual episode at `index`.
+    pub fn dump_demonstration(&self, index: usize) -> bool {
+        let Some(episode) = self.episodes.get(index) else {
+            return false;
+        };
+        if episode.kind != EpisodeKind::Manual {
+            return false;
+        }
+        for (t, tr) in episode.transitions.iter().enumerate() {
+            println!("DEMO,{},{},{},{}", index, t, tr.obs, tr.act);
+        }
+        true
+    }
+}
@@ -1,17 +1,33 @@
 use crate::env::PendulumEnv;
+use crate::episode::{EpisodeBuffer, EpisodeKind};
+use crate::safe_state;
 use anyhow::Result;
 use border_core::{Env, Policy};
 use esp_idf_svc::hal::{
     delay::FreeRtos,
     timer::{TimerDriver, TIMER00},
 };
-use std::sync::atomic::Ordering;
+use pendulum_core::state::{self, AppState, Event};
+use pendulum_core::supervisor::{Limits, Supervisor, Trip};
+
+/// Step period of the evaluator in milliseconds.
+pub const STEP_PERIOD_MS: u32 = 20;
+
+/// Default step period of the evaluator in seconds.
+///
+/// Time-dependent policies should advance their clock by the step period on each call to
+/// `sample()`, see [`PendulumEvaluator::set_step_period_ms`].
+pub const STEP_PERIOD: f32 = STEP_PERIOD_MS as f32 / 1000.0;
 
 /// Evaluate given policy with PendulumEnv.
 ///
-/// The sampling frequency is 50Hz, which means that the policy is called every 20ms.
+/// The sampling frequency is 50Hz by default, which means that the policy is called every
+/// [`STEP_PERIOD_MS`] ms. Each step is checked by a [`Supervisor`], and the servo is driven to
+/// the neutral pose if a check fails or the loop stalls.
 pub struct PendulumEvaluator<'d> {
     timer: TimerDriver<'d>,
+    supervisor: Supervisor,
+    step_period_ms: u32,
 }
 
 impl PendulumEvaluator<'_> {
@@ -19,36 +35,105 @@ impl PendulumEvaluator<'_> {
         let config = esp_idf_svc::hal::timer::config::Config::new();
         PendulumEvaluator {
             timer: TimerDriver::new(timer, &config).expect("Failed to create timer driver"),
+            supervisor: Supervisor::new(Limits::default(), STEP_PERIOD),
+            step_period_ms: STEP_PERIOD_MS,
         }
     }
 
+    /// Change the period of the control loop. The stall time of the supervisor is kept in
+    /// seconds.
+    ///
+    /// Time-dependent policies have to be given [`PendulumEvaluator::step_period`] too, or
+    /// their signals are stretched or compressed in time.
+    pub fn set_step_period_ms(&mut self, period_ms: u32) {
+        self.step_period_ms = period_ms;
+        self.supervisor.set_step_period(self.step_period());
+    }
+
+    /// Period of the control loop in seconds.
+    pub fn step_period(&self) -> f32 {
+        self.step_period_ms as f32 / 1000.0
+    }
+
+    /// Replace the limits enforced during episodes.
+    #[allow(dead_code)]
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.supervisor = Supervisor::new(limits, self.step_period());
+    }
+
+    /// Run an episode and record it in `buffer` with the given kind.
+    ///
+    /// The episode runs until the state changes from the one at the start of the episode. If
+    /// `steps` is positive, the episode also ends after `steps` steps and the state goes back to
+    /// idle.
     pub fn evaluate<'d, P: Policy<PendulumEnv<'d>>>(
         &mut self,
         policy: &mut P,
         env: &mut PendulumEnv<'d>,
-        _steps: usize,
+        buffer: &mut EpisodeBuffer,
+        kind: EpisodeKind,
+        steps: usize,
     ) -> Result<()> {
-        let mut obs = env.reset(None)?;
+        let running = state::current();
+        let mut obs = match env.reset(None) {
+            Ok(obs) => obs,
+            Err(e) => {
+                safe_state::trip(Trip::Error(format!("{:?}", e)));
+                state::dispatch(Event::Cancel);
+                return Err(e);
+            }
+        };
+        let mut buffer_full = false;
+        buffer.start(kind, self.step_period());
+        self.supervisor.reset();
+        safe_state::arm();
 
-        loop {
+        for t in 1.. {
             // Reset timer
             let _ = self.timer.set_counter(0);
 
-            // Proceed with the environment step
-            let (step, _) = env.step(&policy.sample(&obs));
+            // Proceed with the environment step if the supervisor accepts it
+            let act = policy.sample(&obs);
+            let act = match self.supervisor.check(obs.value(), act.value()) {
+                Ok(act) => act.into(),
+                Err(trip) => {
+                    safe_state::trip(trip);
+                    state::dispatch(Event::Cancel);
+                    break;
+                }
+            };
+            let (step, _) = env.step(&act);
+            safe_state::feed();
+
+            // Record the observation given to the policy and the action taken for it
+            if !buffer.push(obs.value(), act.value()) && !buffer_full {
+                log::warn!("Episode buffer is full, the rest of the episode is not recorded");
+                buffer_full = true;
+            }
             obs = step.obs.clone();
 
-            // Break if the state changes to something other than running an episode
-            let state = crate::STATE.load(Ordering::Relaxed);
-            if state == crate::IDLE {
+            // Break if the state changes to something other than running this episode
+            let state = state::current();
+            if state != running {
+                if matches!(
+                    state,
+                    AppState::ManualPolicyStart
+                        | AppState::BlendedPolicyStart
+                        | AppState::ReplayPolicyStart
+                ) {
+                    state::dispatch(Event::Started);
+                }
                 break;
-            } else if state ==  crate::MANUAL_POLICY_START {
-                crate::set_state(crate::MANUAL_POLICY);
+            } else if steps > 0 && t >= steps {
+                log::info!("Reached {} steps", steps);
+                state::dispatch(Event::Done);
                 break;
             }
 
-            // Wait for 20ms to simulate the 50Hz sampling frequency
-            let wait_time = 20 - self.timer.counter().unwrap() / 1000; // Convert to milliseconds
+            // Wait for the rest of the step period to keep the sampling frequency. The timer
+            // counts microseconds.
+            let elapsed_ms = (self.timer.counter().unwrap() / 1000) as i64;
+            let wait_time = self.step_period_ms as i64 - elapsed_ms;
             if wait_time > 0 {
                 FreeRtos::delay_ms(wait_time as _);
             } else {
@@ -56,6 +141,14 @@ impl PendulumEvaluator<'_> {
             }
         }
 
+        safe_state::disarm();
+        buffer.finish();
+        log::info!(
+            "Episode finished, {} steps in {} buffered episodes",
+            buffer.num_steps(),
+            buffer.episodes().len()
+        );
+
         Ok(())
     }
 }
@@ -1,41 +1,60 @@
+mod auto_policy;
+mod blended_policy;
 mod buttons;
+mod calibration_store;
+mod console;
 mod env;
+mod episode;
 mod evaluator;
 mod manual_policy;
-mod sin_policy;
+mod mlp_policy;
+mod noisy_policy;
+mod replay_policy;
+mod safe_state;
+mod server;
+mod signal_policy;
+mod status_led;
 
 use anyhow::Result;
 use as5600::As5600;
+use esp_idf_svc::eventloop::EspSystemEventLoop;
 use esp_idf_svc::hal::delay::FreeRtos;
 use esp_idf_svc::hal::gpio::{InputPin, OutputPin};
 use esp_idf_svc::hal::i2c::*;
-use esp_idf_svc::hal::ledc::{
-    config::TimerConfig, LedcDriver, LedcTimerDriver, Resolution,
-};
+use esp_idf_svc::hal::ledc::{config::TimerConfig, LedcDriver, LedcTimerDriver, Resolution};
 use esp_idf_svc::hal::peripheral::Peripheral;
 use esp_idf_svc::hal::peripherals::Peripherals;
 use esp_idf_svc::hal::prelude::*;
-
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use pendulum_core::calibration::Calibration;
+use pendulum_core::commands::{Command, Setting};
+use pendulum_core::state::{self, AppState, Event};
+use pendulum_shadow::Config;
+use std::f32::consts::TAU;
+use std::time::{Duration, Instant};
+
+use auto_policy::AutoPolicy;
+use blended_policy::{Blend, BlendedPolicy};
 use buttons::Buttons;
+use calibration_store::CalibrationStore;
 use env::PendulumEnv;
+use episode::{EpisodeBuffer, EpisodeKind};
 use evaluator::PendulumEvaluator;
-use sin_policy::SinPolicy;
 use manual_policy::ManualPolicy;
-use std::sync::atomic::{AtomicU8, Ordering};
-
-static STATE: AtomicU8 = AtomicU8::new(0);
-
-const IDLE: u8 = 0;
-const OFFSET_CORRECTION: u8 = 10;
-const OFFSET_CORRECTION_END: u8 = 11;
-const OFFSET_CORRECTION_CANCEL: u8 = 12;
-const POTENTIOMETER_MIN: u8 = 13;
-const POTENTIOMETER_MAX: u8 = 14;
-const POTENTIOMETER_CANCEL: u8 = 15;
-const AUTO_POLICY: u8 = 21;
-const MANUAL_POLICY_START: u8 = 22;
-const MANUAL_POLICY: u8 = 23;
-const TERMINATE: u8 = 255;
+use noisy_policy::NoisyPolicy;
+use replay_policy::ReplayPolicy;
+use server::Server;
+use signal_policy::SignalPolicy;
+use status_led::Ws2812;
+
+/// Action sequence replayed by ReplayPolicy, embedded from the file given by the
+/// `REPLAY_ACTIONS` environment variable at build time (see build.rs).
+///
+/// If it is empty, the actions of the last buffered episode are replayed instead.
+const REPLAY_ACTIONS: &str = include_str!(concat!(env!("OUT_DIR"), "/replay_actions.txt"));
+
+/// Interval of applying the deltas of the device shadow while idle.
+const SHADOW_SYNC_PERIOD: Duration = Duration::from_secs(5);
 
 fn create_as5600<'d>(
     i2c: I2C0,
@@ -102,9 +121,14 @@ fn main() -> Result<()> {
     let pin_button3 = peripherals.pins.gpio5;
     let pin_button4 = peripherals.pins.gpio4;
     let pin_potentiometer = peripherals.pins.gpio3;
+    let pin_led = peripherals.pins.gpio2;
     let adc = peripherals.adc1;
 
     // Devices
+    log::info!("Initialize RMT for status LED...");
+    let led = Ws2812::new(peripherals.rmt.channel0, pin_led)?;
+    status_led::spawn(led)?;
+
     log::info!("Initialize I2C for rotary encoder...");
     let config = I2cConfig::new().baudrate(100.kHz().into());
     let i2c_driver = I2cDriver::new(peripherals.i2c0, pin_sda, pin_scl, &config)?;
@@ -136,102 +160,399 @@ fn main() -> Result<()> {
     FreeRtos::delay_ms(5000);
 
     log::info!("Initialize buttons...");
-    let mut buttons = Buttons::new(pin_button1, pin_button2, pin_button3, pin_button4);
-    buttons.enable_interrupt()?;
+    let buttons = Buttons::new(pin_button1, pin_button2, pin_button3, pin_button4);
+    buttons.spawn()?;
 
-    log::info!("Initialize PendulumEnv and SinPolicy...");
+    log::info!("Initialize PendulumEnv and AutoPolicy...");
     let mut env = PendulumEnv::from_devices(as5600, motor);
-    let mut auto_policy = SinPolicy::new(1.0);
+    safe_state::init(env.neutral_duty())?;
+    // The exploration noise is off until set from the console
+    let mut auto_policy = NoisyPolicy::new(
+        // 1.25 rad/s, the speed of the former `SinPolicy::new(1.0)` that advanced 0.025 s per step
+        AutoPolicy::Signal(SignalPolicy::sine(1.25 / TAU, evaluator::STEP_PERIOD)),
+        0,
+        evaluator::STEP_PERIOD,
+    );
     let mut evaluator = PendulumEvaluator::new(peripherals.timer00);
 
     log::info!("Initialize ManualPolicy...");
     let mut manual_policy = ManualPolicy::new(adc, pin_potentiometer);
+    manual_policy.set_oversampling(8);
+    manual_policy.input_mut().set_deadband(0.05);
+    manual_policy.input_mut().set_expo(0.3);
+    manual_policy.input_mut().set_smoothing(0.5);
+    let mut episodes = EpisodeBuffer::new();
+    let mut blend = Blend::Upright {
+        threshold: 0.3,
+        width: 0.5,
+    };
+
+    log::info!("Load calibration...");
+    let nvs = EspDefaultNvsPartition::take()?;
+    let mut calibration_store = CalibrationStore::new(nvs.clone())?;
+    match calibration_store.load() {
+        Some(calibration) => {
+            env.set_offset(calibration.offset, calibration.direction);
+            manual_policy.set_min_limit(calibration.min_limit);
+            manual_policy.set_max_limit(calibration.max_limit);
+            log::info!("Loaded calibration: {:?}", calibration);
+        }
+        None => log::info!("No saved calibration, press button 2 to calibrate"),
+    }
+
+    log::info!("Initialize Wifi...");
+    let sys_loop = EspSystemEventLoop::take()?;
+    let mut server = Server::new(peripherals.modem, sys_loop, nvs)?;
+
+    log::info!("Initialize console...");
+    let console = console::spawn()?;
+
+    // Number of steps of the next automatic episode, 0 for unlimited
+    let mut auto_steps = 0;
+
+    // Configuration synchronized through the device shadow
+    let mut config = Config::default();
+    let mut last_sync: Option<Instant> = None;
+    // Policy version requested by the shadow and being downloaded
+    let mut requested_version = None;
 
     log::info!("Starting main loop");
     loop {
-        match get_state() {
+        // Handle console commands that need the devices or the episodes
+        while let Ok(command) = console.try_recv() {
+            match command {
+                Command::RunAuto { steps } => {
+                    if state::current() != AppState::Idle {
+                        println!("run auto is only accepted in the idle state");
+                    } else if state::dispatch(Event::Run) == Some(AppState::AutoPolicy) {
+                        auto_steps = steps.unwrap_or(0);
+                    }
+                }
+                Command::Set(setting) => {
+                    match setting {
+                        Setting::Scale(scale) => {
+                            env.set_scale(scale);
+                            config.servo_scale = scale.clamp(0.0, 1.0);
+                            if let Err(e) = server.report_shadow(&config, &[]) {
+                                log::warn!("Failed to report to the shadow: {:?}", e);
+                            }
+                        }
+                        Setting::Deadband(x) => manual_policy.input_mut().set_deadband(x),
+                        Setting::Expo(x) => manual_policy.input_mut().set_expo(x),
+                        Setting::Smoothing(x) => manual_policy.input_mut().set_smoothing(x),
+                        Setting::Oversampling(n) => manual_policy.set_oversampling(n),
+                    }
+                    println!("Set {:?}", setting);
+                }
+                Command::Signal(signal) => {
+                    if state::current() != AppState::Idle {
+                        println!("signal is only accepted in the idle state");
+                    } else if let AutoPolicy::Signal(policy) = auto_policy.inner_mut() {
+                        println!("Set {:?}", signal);
+                        policy.set_signal(signal);
+                    } else {
+                        println!(
+                            "signal is only accepted while the automatic policy is a signal policy"
+                        );
+                    }
+                }
+                Command::Noise(noise) => {
+                    println!("Set noise {:?}", noise);
+                    auto_policy.set_noise(noise);
+                }
+                Command::NoiseSeed(seed) => {
+                    println!("Set noise seed {}", seed);
+                    auto_policy.set_seed(seed);
+                }
+                Command::NoiseSchedule(schedule) => {
+                    println!("Set noise schedule {:?}", schedule);
+                    auto_policy.set_schedule(schedule);
+                }
+                Command::Blend(new_blend) => {
+                    println!("Set blend {:?}", new_blend);
+                    blend = new_blend;
+                }
+                Command::DumpEpisode { index } => {
+                    let index = index.unwrap_or(episodes.episodes().len().saturating_sub(1));
+                    if !episodes.dump_episode(index) {
+                        println!("No episode {}", index);
+                    }
+                }
+                Command::DumpDemonstration { index } => {
+                    let index = index.or_else(|| {
+                        episodes
+                            .episodes()
+                            .iter()
+                            .rposition(|e| e.kind == EpisodeKind::Manual)
+                    });
+                    if !index.is_some_and(|index| episodes.dump_demonstration(index)) {
+                        println!("No manual episode to dump");
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        match state::current() {
             // Idle
-            IDLE => {
-                log::info!("polling: {}", STATE.load(Ordering::Relaxed));
-                FreeRtos::delay_ms(1000);
+            AppState::Idle => {
+                if !last_sync.is_some_and(|t| t.elapsed() < SHADOW_SYNC_PERIOD) {
+                    last_sync = Some(Instant::now());
+                    match server.sync_shadow(&mut config) {
+                        Ok(response) => {
+                            if response.changed {
+                                env.set_scale(config.servo_scale);
+                                env.set_reward_weights(config.reward);
+                                evaluator.set_step_period_ms(config.step_period_ms());
+                                auto_policy.set_dt(evaluator.step_period());
+                                auto_policy.inner_mut().set_dt(evaluator.step_period());
+                                log::info!("Applied configuration: {:?}", config);
+                            }
+                            if let Some(version) = response.policy_version {
+                                log::info!("Shadow requested parameters version {}", version);
+                                requested_version = Some(version);
+                                state::dispatch(Event::ReceiveParameters);
+                            }
+                        }
+                        Err(e) => log::warn!("Failed to sync the shadow: {:?}", e),
+                    }
+                }
+                FreeRtos::delay_ms(100);
             }
 
             // Offset correction
-            OFFSET_CORRECTION => {
+            AppState::OffsetCorrection => {
                 // Start pooling loop inside PendulumEnv for offset correction
                 env.correct_offset();
-                set_state(POTENTIOMETER_MIN);
             }
 
-            POTENTIOMETER_MIN => {
+            // Offset correction confirmed or cancelled
+            AppState::OffsetCorrectionEnd | AppState::OffsetCorrectionCancel => {
+                state::dispatch(Event::Done);
+            }
+
+            AppState::PotentiometerMin => {
                 log::info!("Take minimum potentiometer value");
                 FreeRtos::delay_ms(1000);
-                let value = manual_policy.take_potentiometer_value(POTENTIOMETER_MIN);
+                let value = manual_policy.take_potentiometer_value(AppState::PotentiometerMin);
 
-                if get_state() == POTENTIOMETER_CANCEL {
-                    set_state(IDLE);
-                } else {
+                if state::current() != AppState::PotentiometerCancel {
                     manual_policy.set_min_limit(value);
                 }
             }
 
-            POTENTIOMETER_MAX => {
+            AppState::PotentiometerMax => {
                 log::info!("Take maximum potentiometer value");
                 FreeRtos::delay_ms(1000);
-                let value = manual_policy.take_potentiometer_value(POTENTIOMETER_MAX);
+                let value = manual_policy.take_potentiometer_value(AppState::PotentiometerMax);
 
-                if get_state() == POTENTIOMETER_CANCEL {
-                    set_state(IDLE);
-                } else {
+                if state::current() != AppState::PotentiometerCancel {
                     manual_policy.set_max_limit(value);
+
+                    // The calibration sequence is complete
+                    let (offset, direction) = env.offset();
+                    let calibration = Calibration {
+                        offset,
+                        direction,
+                        min_limit: manual_policy.input().min_limit(),
+                        max_limit: manual_policy.input().max_limit(),
+                    };
+                    match calibration_store.save(&calibration) {
+                        Ok(()) => log::info!("Saved calibration: {:?}", calibration),
+                        Err(e) => {
+                            log::error!("Failed to save calibration: {:?}", e);
+                            status_led::report_fault();
+                        }
+                    }
                 }
             }
 
+            AppState::PotentiometerCancel => {
+                log::info!("Potentiometer calibration cancelled.");
+                state::dispatch(Event::Done);
+            }
+
             // Run an episode
-            AUTO_POLICY => evaluator.evaluate(&mut auto_policy, &mut env, 0).unwrap(),
+            AppState::AutoPolicy => {
+                // The number of steps only applies to the run requested from the console
+                let steps = std::mem::take(&mut auto_steps);
+                auto_policy.inner_mut().reset();
+                auto_policy.reset();
+                evaluator
+                    .evaluate(
+                        &mut auto_policy,
+                        &mut env,
+                        &mut episodes,
+                        EpisodeKind::Auto,
+                        steps,
+                    )
+                    .unwrap_or_else(|e| log::error!("Episode failed: {:?}", e));
+            }
 
             // Run an episode
-            MANUAL_POLICY => evaluator.evaluate(&mut manual_policy, &mut env, 0).unwrap(),
+            AppState::ManualPolicy => {
+                manual_policy.input_mut().reset();
+                evaluator
+                    .evaluate(
+                        &mut manual_policy,
+                        &mut env,
+                        &mut episodes,
+                        EpisodeKind::Manual,
+                        0,
+                    )
+                    .unwrap_or_else(|e| log::error!("Episode failed: {:?}", e))
+            }
+
+            // Run an episode with the operator assisted by the automatic policy
+            AppState::BlendedPolicy => {
+                manual_policy.input_mut().reset();
+                auto_policy.inner_mut().reset();
+                auto_policy.reset();
+                let mut blended_policy =
+                    BlendedPolicy::new(&mut manual_policy, &mut auto_policy, blend.clone());
+                evaluator
+                    .evaluate(
+                        &mut blended_policy,
+                        &mut env,
+                        &mut episodes,
+                        EpisodeKind::Blended,
+                        0,
+                    )
+                    .unwrap_or_else(|e| log::error!("Episode failed: {:?}", e))
+            }
+
+            // Play back a recorded action sequence
+            AppState::ReplayPolicy => {
+                let step_period = evaluator.step_period();
+                let mut replay_policy = if REPLAY_ACTIONS.trim().is_empty() {
+                    match episodes.episodes().last() {
+                        Some(episode) => episode.replay_policy(),
+                        None => ReplayPolicy::new(vec![], step_period),
+                    }
+                } else {
+                    // The file is taken to be recorded at the default control rate
+                    ReplayPolicy::from_text(REPLAY_ACTIONS, evaluator::STEP_PERIOD).unwrap_or_else(
+                        |e| {
+                            log::error!("Failed to parse REPLAY_ACTIONS: {:?}", e);
+                            ReplayPolicy::new(vec![], step_period)
+                        },
+                    )
+                };
+                replay_policy.set_dt(step_period);
+
+                if replay_policy.is_empty() {
+                    log::warn!("No action sequence to replay");
+                    status_led::report_fault();
+                    state::dispatch(Event::Done);
+                } else {
+                    log::info!(
+                        "Replaying {} actions in {} steps",
+                        replay_policy.len(),
+                        replay_policy.num_steps()
+                    );
+                    replay_policy.reset();
+                    evaluator
+                        .evaluate(
+                            &mut replay_policy,
+                            &mut env,
+                            &mut episodes,
+                            EpisodeKind::Replay,
+                            replay_policy.num_steps(),
+                        )
+                        .unwrap_or_else(|e| log::error!("Episode failed: {:?}", e))
+                }
+            }
 
             // Terminate the program
-            TERMINATE => {
+            AppState::Terminate => {
                 log::info!("Terminating program...");
                 break;
             }
 
             // Send episode data to the server
-            2 => {
-                log::info!("polling: {}", STATE.load(Ordering::Relaxed));
-                FreeRtos::delay_ms(1000);
-                STATE.store(0, Ordering::Relaxed);
+            AppState::SendEpisodes => {
+                log::info!(
+                    "Sending {} episodes ({} steps)...",
+                    episodes.episodes().len(),
+                    episodes.num_steps()
+                );
+                match server.send_episodes(episodes.episodes_mut()) {
+                    Ok(()) => log::info!("Sent episodes"),
+                    Err(e) => {
+                        log::error!("Failed to send episodes: {:?}", e);
+                        status_led::report_fault();
+                    }
+                }
+                state::dispatch(Event::Done);
             }
 
             // Receive model parameters from the server
-            3 => {
-                log::info!("polling: {}", STATE.load(Ordering::Relaxed));
-                FreeRtos::delay_ms(1000);
-                STATE.store(0, Ordering::Relaxed);
+            AppState::ReceiveParameters => {
+                // Episodes do not run in this state, so the policy can be swapped safely
+                let result = server
+                    .receive_parameters()
+                    .and_then(|parameters| auto_policy.inner_mut().load(&parameters));
+                match result {
+                    Ok(()) => log::info!(
+                        "Loaded parameters version {}",
+                        auto_policy.inner_mut().version()
+                    ),
+                    Err(e) => {
+                        log::error!("Failed to receive parameters: {:?}", e);
+                        status_led::report_fault();
+                    }
+                }
+
+                // Report the version in use, rejecting a requested one that was not delivered
+                config.policy_version = auto_policy.inner_mut().version();
+                let rejected: &[&str] = match requested_version.take() {
+                    Some(version) if version != config.policy_version => &["policy_version"],
+                    _ => &[],
+                };
+                if let Err(e) = server.report_shadow(&config, rejected) {
+                    log::warn!("Failed to report to the shadow: {:?}", e);
+                }
+                state::dispatch(Event::Done);
             }
 
             // Clear the episode data
-            4 => {
-                log::info!("polling: {}", STATE.load(Ordering::Relaxed));
-                FreeRtos::delay_ms(1000);
-                STATE.store(0, Ordering::Relaxed);
+            //
+            // Episodes are only kept in RAM: the buffer and the copy held to resume an upload.
+            AppState::ClearEpisodes => {
+                let num_episodes = episodes.episodes().len();
+                let num_steps = episodes.num_steps();
+                let freed = episodes.clear() + server.discard_upload();
+                log::info!(
+                    "Cleared {} episodes ({} steps), freed {} bytes of RAM, free heap {} bytes",
+                    num_episodes,
+                    num_steps,
+                    freed,
+                    unsafe { esp_idf_svc::sys::esp_get_free_heap_size() }
+                );
+                state::dispatch(Event::Done);
+            }
+
+            // Remove the saved calibration
+            AppState::ClearCalibration => {
+                match calibration_store.invalidate() {
+                    Ok(()) => log::info!("Removed saved calibration, calibrate again after reboot"),
+                    Err(e) => {
+                        log::error!("Failed to remove calibration: {:?}", e);
+                        status_led::report_fault();
+                    }
+                }
+                state::dispatch(Event::Done);
+            }
+
+            // Waiting for the running episode to finish
+            AppState::ManualPolicyStart
+            | AppState::BlendedPolicyStart
+            | AppState::ReplayPolicyStart => {
+                state::dispatch(Event::Started);
             }
-            _ => {}
         }
-        buttons.enable_interrupt()?;
     }
 
     #[allow(unreachable_code)]
     Ok(())
 }
-
-fn get_state() -> u8 {
-    STATE.load(Ordering::Relaxed)
-}
-
-fn set_state(state: u8) {
-    STATE.store(state, Ordering::Relaxed);
-}
@@ -1,4 +1,4 @@
-use crate::env::{PendulumEnv, PendulumEnvAct, PendulumEnvObs};
+use crate::env::{PendulumEnv, PendulumEnvAct, PendulumEnvObs, CALIBRATION_TIMEOUT_MS};
 use border_core::Policy;
 use esp_idf_svc::hal::delay::FreeRtos;
 use esp_idf_svc::hal::{
@@ -6,15 +6,20 @@ use esp_idf_svc::hal::{
     gpio::ADCPin,
     peripheral::Peripheral,
 };
-use std::sync::atomic::Ordering;
+use pendulum_core::potentiometer::PotentiometerInput;
+use pendulum_core::state::{self, AppState, Event};
+
+/// Number of ADC reads averaged for a single potentiometer value by default.
+const DEFAULT_OVERSAMPLING: usize = 8;
 
 pub struct ManualPolicy<T>
 where
     T: ADCPin,
 {
     adc_pin: AdcChannelDriver<'static, T, AdcDriver<'static, T::Adc>>,
-    min_limit: u16,
-    max_limit: u16,
+    input: PotentiometerInput,
+    oversampling: usize,
+    last_action: f32,
 }
 
 impl<T> ManualPolicy<T>
@@ -35,39 +40,82 @@ where
 
         ManualPolicy {
             adc_pin,
-            min_limit: 0,
-            max_limit: 2048,
+            input: PotentiometerInput::default(),
+            oversampling: DEFAULT_OVERSAMPLING,
+            last_action: 0.0,
         }
     }
 
-    pub fn take_potentiometer_value(&mut self, current_state: u8) -> u16 {
+    /// Log the potentiometer value until the state changes from `current_state`, and return
+    /// the last value.
+    ///
+    /// The step is cancelled if the state does not change within `CALIBRATION_TIMEOUT_MS`.
+    pub fn take_potentiometer_value(&mut self, current_state: AppState) -> u16 {
         let mut value = 0;
 
-        loop {
+        for t in 0.. {
             FreeRtos::delay_ms(100);
 
-            match self.adc_pin.read() {
-                Ok(value_) => {
-                    value = value_;
+            match self.read_average() {
+                Some(value_) => {
+                    value = value_.round() as u16;
                     log::info!("Potentiometer value: {}", value);
                 }
-                Err(e) => {
-                    log::error!("Failed to read ADC value: {:?}", e);
+                None => {
+                    log::error!("Failed to read ADC value");
                 }
             }
 
-            if get_state() != current_state {
-                return value; // Return a default value or handle cancellation as needed
+            if state::current() != current_state {
+                break;
+            } else if t * 100 >= CALIBRATION_TIMEOUT_MS {
+                log::warn!("Taking potentiometer value timed out.");
+                state::dispatch(Event::Timeout);
             }
         }
+
+        value // Return a default value or handle cancellation as needed
     }
 
     pub fn set_min_limit(&mut self, min_limit: u16) {
-        self.min_limit = min_limit;
+        self.input.set_min_limit(min_limit);
     }
 
     pub fn set_max_limit(&mut self, max_limit: u16) {
-        self.max_limit = max_limit;
+        self.input.set_max_limit(max_limit);
+    }
+
+    /// Set the number of ADC reads averaged for a single potentiometer value.
+    pub fn set_oversampling(&mut self, oversampling: usize) {
+        self.oversampling = oversampling.max(1);
+    }
+
+    /// Get the conversion from potentiometer readings to actions.
+    pub fn input(&self) -> &PotentiometerInput {
+        &self.input
+    }
+
+    /// Get the conversion from potentiometer readings to actions to configure it.
+    pub fn input_mut(&mut self) -> &mut PotentiometerInput {
+        &mut self.input
+    }
+
+    /// Average `oversampling` ADC reads, ignoring failed reads.
+    ///
+    /// Returns `None` if all reads failed.
+    fn read_average(&mut self) -> Option<f32> {
+        let mut sum = 0u32;
+        let mut count = 0u32;
+        for _ in 0..self.oversampling {
+            match self.adc_pin.read() {
+                Ok(raw) => {
+                    sum += raw as u32;
+                    count += 1;
+                }
+                Err(e) => log::warn!("Failed to read ADC value: {:?}", e),
+            }
+        }
+        (count > 0).then(|| sum as f32 / count as f32)
     }
 }
 
@@ -76,25 +124,10 @@ where
     T: ADCPin,
 {
     fn sample(&mut self, _obs: &PendulumEnvObs) -> PendulumEnvAct {
-        let raw = self.adc_pin.read().unwrap();
-        map(raw, self.min_limit, self.max_limit).into()
-    }
-}
-
-fn get_state() -> u8 {
-    crate::STATE.load(Ordering::Relaxed)
-}
-
-// Return the mapped value, taking between -1.0 and 1.0
-//
-// min_limit and max_limit are the limits of the raw value.
-fn map(raw: u16, min_limit: u16, max_limit: u16) -> f32 {
-    if raw < min_limit {
-        return -1.0;
-    } else if raw > max_limit {
-        return 1.0;
+        // Keep the previous action if the ADC cannot be read
+        if let Some(raw) = self.read_average() {
+            self.last_action = self.input.process(raw);
+        }
+        self.last_action.into()
     }
-    let range = max_limit - min_limit;
-    let normalized = (raw - min_limit) as f32 / range as f32;
-    normalized * 2.0 - 1.0 // Scale to [-1, 1]
 }
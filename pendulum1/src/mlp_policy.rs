@@ -0,0 +1,9 @@
+use crate::env::{PendulumEnv, PendulumEnvAct, PendulumEnvObs};
+use border_core::Policy;
+pub use pendulum_core::mlp_policy::MlpPolicy;
+
+impl<'d> Policy<PendulumEnv<'d>> for MlpPolicy {
+    fn sample(&mut self, obs: &PendulumEnvObs) -> PendulumEnvAct {
+        self.next_action(obs.value()).into()
+    }
+}
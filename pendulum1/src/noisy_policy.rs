@@ -0,0 +1,13 @@
+use crate::env::{PendulumEnv, PendulumEnvAct, PendulumEnvObs};
+use border_core::Policy;
+pub use pendulum_core::noisy_policy::NoisyPolicy;
+
+impl<'d, P> Policy<PendulumEnv<'d>> for NoisyPolicy<P>
+where
+    P: Policy<PendulumEnv<'d>>,
+{
+    fn sample(&mut self, obs: &PendulumEnvObs) -> PendulumEnvAct {
+        let action = self.inner_mut().sample(obs).value();
+        self.add_noise(action).into()
+    }
+}
@@ -0,0 +1,9 @@
+use crate::env::{PendulumEnv, PendulumEnvAct, PendulumEnvObs};
+use border_core::Policy;
+pub use pendulum_core::replay_policy::ReplayPolicy;
+
+impl<'d> Policy<PendulumEnv<'d>> for ReplayPolicy {
+    fn sample(&mut self, _obs: &PendulumEnvObs) -> PendulumEnvAct {
+        self.next_action().into()
+    }
+}
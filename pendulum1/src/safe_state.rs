@@ -0,0 +1,114 @@
+//! Drives the servo to a neutral pose when something goes wrong.
+//!
+//! The servo is normally owned by `PendulumEnv`. To reach it from the panic hook and the
+//! watchdog task, the duty is written with the LEDC functions of ESP-IDF directly.
+use crate::status_led;
+use anyhow::Result;
+use esp_idf_svc::sys::{
+    esp_timer_get_time, ledc_channel_t_LEDC_CHANNEL_0, ledc_mode_t_LEDC_LOW_SPEED_MODE,
+    ledc_set_duty, ledc_update_duty,
+};
+use pendulum_core::state::{self, Event};
+use pendulum_core::supervisor::{self, Trip};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::Duration;
+
+/// Time without a step after which the watchdog trips.
+const WATCHDOG_TIMEOUT_MS: u32 = 200;
+
+/// Period of the watchdog task.
+const WATCHDOG_PERIOD_MS: u64 = 50;
+
+static NEUTRAL_DUTY: AtomicU32 = AtomicU32::new(0);
+static ARMED: AtomicBool = AtomicBool::new(false);
+static TRIPPED: AtomicBool = AtomicBool::new(false);
+static FED_AT: AtomicU32 = AtomicU32::new(0);
+
+/// Milliseconds since boot, wrapping around after about 49 days.
+fn now_ms() -> u32 {
+    (unsafe { esp_timer_get_time() } / 1000) as u32
+}
+
+/// Drive the servo to the neutral pose.
+///
+/// The servo must be on LEDC channel 0 in low speed mode, as set up in `main`.
+pub fn neutral() {
+    let duty = NEUTRAL_DUTY.load(Ordering::Acquire);
+    unsafe {
+        ledc_set_duty(
+            ledc_mode_t_LEDC_LOW_SPEED_MODE,
+            ledc_channel_t_LEDC_CHANNEL_0,
+            duty,
+        );
+        ledc_update_duty(
+            ledc_mode_t_LEDC_LOW_SPEED_MODE,
+            ledc_channel_t_LEDC_CHANNEL_0,
+        );
+    }
+}
+
+/// Drive the servo to the neutral pose and keep it there until the next [`arm`].
+fn stop() {
+    // Latch first, so that `PendulumEnv` does not move the servo again after `neutral`
+    TRIPPED.store(true, Ordering::Release);
+    disarm();
+    neutral();
+}
+
+/// Whether the servo has been driven to the neutral pose since the last [`arm`].
+pub fn tripped() -> bool {
+    TRIPPED.load(Ordering::Acquire)
+}
+
+/// Drive the servo to the neutral pose and record why.
+pub fn trip(trip: Trip) {
+    stop();
+    log::error!("Safe state: {:?}", trip);
+    supervisor::record_trip(trip);
+    status_led::report_fault();
+}
+
+/// Start watching the control loop. Call [`feed`] on every step until [`disarm`].
+pub fn arm() {
+    TRIPPED.store(false, Ordering::Release);
+    FED_AT.store(now_ms(), Ordering::Release);
+    ARMED.store(true, Ordering::Release);
+}
+
+pub fn feed() {
+    FED_AT.store(now_ms(), Ordering::Release);
+}
+
+pub fn disarm() {
+    ARMED.store(false, Ordering::Release);
+}
+
+/// Install the panic hook and start the watchdog task.
+///
+/// `neutral_duty` is the duty of the neutral pose of the servo.
+pub fn init(neutral_duty: u32) -> Result<()> {
+    NEUTRAL_DUTY.store(neutral_duty, Ordering::Release);
+
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        // Stop the servo before anything that may block or panic again
+        stop();
+        supervisor::record_trip(Trip::Panic(info.to_string()));
+        status_led::report_fault();
+        default_hook(info);
+    }));
+
+    std::thread::Builder::new()
+        .name("watchdog".into())
+        .stack_size(4096)
+        .spawn(|| loop {
+            if ARMED.load(Ordering::Acquire)
+                && now_ms().wrapping_sub(FED_AT.load(Ordering::Acquire)) > WATCHDOG_TIMEOUT_MS
+            {
+                trip(Trip::Watchdog);
+                state::dispatch(Event::Cancel);
+            }
+            std::thread::sleep(Duration::from_millis(WATCHDOG_PERIOD_MS));
+        })?;
+    Ok(())
+}
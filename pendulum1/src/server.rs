@@ -0,0 +1,355 @@
+//! Communication with the server over MQTT.
+//!
+//! The TLS/MQTT setup follows `mqtt_aws_esp`, and Wi-Fi is kept connected by `wifi_manager`.
+//! Credentials and the endpoint are read from the secrets partition at startup.
+use crate::episode::Episode;
+use anyhow::{bail, Result};
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::modem::Modem;
+use esp_idf_svc::mqtt::client::*;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{error, info, warn};
+use messages::{Message, Parameters, Upload};
+use pendulum_shadow::{self as shadow, Config, Shadow};
+use secrets::{ConnectionState, MqttClient, Secrets, TlsCredentials};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+use topic_router::Router;
+use wifi_manager::{KnownNetwork, WifiManager};
+
+const MQTT_CLIENT_ID: &str = "pendulum1";
+const NVS_NAMESPACE: &str = "pendulum1";
+
+/// NVS key of the id given to the next episode sent.
+const NEXT_EPISODE_KEY: &str = "next_episode";
+
+const EPISODE_TOPIC: &str = "pendulum1/episodes";
+
+/// Topic of the model parameters. The server publishes the latest parameters as a retained
+/// message, so they are delivered as soon as the device subscribes.
+const PARAMETER_TOPIC: &str = "pendulum1/parameters";
+
+/// Topic of the acks of episode uploads from the server.
+const ACK_TOPIC: &str = "pendulum1/episodes/ack";
+
+/// Maximum size of the data in a single MQTT message.
+const CHUNK_SIZE: usize = 2048;
+
+/// Number of queries before giving up an upload.
+const MAX_ROUNDS: usize = 5;
+
+/// Time to wait for an ack of a query.
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time to wait for the MQTT connection to be established.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time to wait for the model parameters after subscribing.
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn wifi_networks(secrets: &Secrets) -> Result<Vec<KnownNetwork>> {
+    let ssid = secrets.require_str(secrets::WIFI_SSID)?;
+    let pass = secrets.require_str(secrets::WIFI_PASS)?;
+
+    Ok(vec![KnownNetwork::new(ssid, pass, 0)])
+}
+
+/// Connection to the server.
+///
+/// Wi-Fi is connected in the background from the start, and MQTT on first use or once Wi-Fi
+/// is up for the shadow, so that the device works without a network.
+pub struct Server {
+    secrets: Secrets,
+    wifi: WifiManager,
+    client: Option<MqttClient>,
+    state: Arc<ConnectionState>,
+    nvs: EspNvs<NvsDefault>,
+    ack_sender: Sender<Vec<u8>>,
+    ack_received: Receiver<Vec<u8>>,
+    parameter_sender: Sender<Vec<u8>>,
+    parameter_received: Receiver<Vec<u8>>,
+    shadow: Shadow,
+    shadow_sender: Sender<(String, Vec<u8>)>,
+    shadow_received: Receiver<(String, Vec<u8>)>,
+    /// Session the shadow topics were last subscribed in.
+    shadow_session: u32,
+    /// Session `ACK_TOPIC` was subscribed in during `send_episodes`, or 0.
+    ack_session: u32,
+    /// Upload to be resumed by the next `send_episodes`.
+    upload: Option<Upload>,
+}
+
+impl Server {
+    pub fn new(
+        modem: Modem,
+        sys_loop: EspSystemEventLoop,
+        nvs: EspDefaultNvsPartition,
+    ) -> Result<Self> {
+        let secrets = Secrets::load()?;
+        let wifi =
+            WifiManager::start(modem, sys_loop, Some(nvs.clone()), wifi_networks(&secrets)?)?;
+        let (ack_sender, ack_received) = mpsc::channel();
+        let (parameter_sender, parameter_received) = mpsc::channel();
+        let (shadow_sender, shadow_received) = mpsc::channel();
+        Ok(Server {
+            secrets,
+            wifi,
+            client: None,
+            state: Arc::new(ConnectionState::default()),
+            nvs: EspNvs::new(nvs, NVS_NAMESPACE, true)?,
+            ack_sender,
+            ack_received,
+            parameter_sender,
+            parameter_received,
+            shadow: Shadow::new(MQTT_CLIENT_ID),
+            shadow_sender,
+            shadow_received,
+            shadow_session: 0,
+            ack_session: 0,
+            upload: None,
+        })
+    }
+
+    /// Connect to Wi-Fi and the MQTT broker if not connected yet.
+    fn ensure_connected(&mut self) -> Result<&mut MqttClient> {
+        if !self.wifi.wait_connected(CONNECT_TIMEOUT) {
+            bail!("Wifi is not connected: {:?}", self.wifi.status());
+        }
+
+        if self.client.is_none() {
+            let endpoint = self.secrets.require_str(secrets::ENDPOINT)?;
+            info!("Connecting to MQTT broker {}...", endpoint);
+            let credentials = TlsCredentials::from_secrets(&self.secrets)?;
+            let (client, conn) = MqttClient::connect(endpoint, MQTT_CLIENT_ID, credentials)?;
+
+            // Each kind of message has its own channel, so that a request never takes the
+            // messages of another one
+            let mut router = Router::new();
+            let sender = self.ack_sender.clone();
+            router.add(ACK_TOPIC, move |_, payload| {
+                let _ = sender.send(payload.to_vec());
+            })?;
+            let sender = self.parameter_sender.clone();
+            router.add(PARAMETER_TOPIC, move |_, payload| {
+                let _ = sender.send(payload.to_vec());
+            })?;
+            let sender = self.shadow_sender.clone();
+            router.add(&self.shadow.filter(), move |topic, payload| {
+                let _ = sender.send((topic.to_string(), payload.to_vec()));
+            })?;
+
+            secrets::spawn_pump(conn, self.state.clone(), move |topic, payload| {
+                router.dispatch(topic, payload);
+            })?;
+            self.client = Some(client);
+        }
+
+        // Wait for the broker to accept the connection
+        let start = std::time::Instant::now();
+        while !self.state.is_connected() {
+            if start.elapsed() > CONNECT_TIMEOUT {
+                bail!("Timed out connecting to the MQTT broker");
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        Ok(self.client.as_mut().unwrap())
+    }
+
+    /// Apply the shadow deltas received since the last call to `config`, and report the
+    /// applied values. Call this periodically while idle.
+    ///
+    /// Only the first call waits for the broker, later calls return immediately while the
+    /// broker is not connected.
+    pub fn sync_shadow(&mut self, config: &mut Config) -> Result<shadow::Response> {
+        if !self.wifi.is_connected() || (self.client.is_some() && !self.state.is_connected()) {
+            return Ok(shadow::Response::default());
+        }
+        self.ensure_connected()?;
+        let client = self.client.as_mut().unwrap();
+
+        // Subscriptions are lost when the broker reconnects with a clean session
+        let session = self.state.sessions();
+        if session != self.shadow_session {
+            client.subscribe(&self.shadow.filter(), QoS::AtLeastOnce)?;
+            // The current shadow is delivered on get/accepted, including a pending delta
+            client.publish(&self.shadow.get_topic(), QoS::AtLeastOnce, false, b"")?;
+            self.shadow_session = session;
+        }
+
+        let mut result = shadow::Response::default();
+        while let Ok((topic, payload)) = self.shadow_received.try_recv() {
+            let response = match self.shadow.handle(config, &topic, &payload) {
+                Ok(response) => response,
+                Err(e) => {
+                    warn!("Failed to handle {}: {}", topic, e);
+                    continue;
+                }
+            };
+            if let Some(update) = &response.update {
+                client.publish(
+                    &self.shadow.update_topic(),
+                    QoS::AtLeastOnce,
+                    false,
+                    update.as_bytes(),
+                )?;
+            }
+            result.changed |= response.changed;
+            result.policy_version = response.policy_version.or(result.policy_version);
+        }
+
+        Ok(result)
+    }
+
+    /// Report `config` to the shadow, removing the desired values of `rejected` keys.
+    pub fn report_shadow(&mut self, config: &Config, rejected: &[&str]) -> Result<()> {
+        let update = Shadow::report(config, rejected);
+        let topic = self.shadow.update_topic();
+        self.ensure_connected()?
+            .publish(&topic, QoS::AtLeastOnce, false, update.as_bytes())?;
+        Ok(())
+    }
+
+    /// Upload the episodes that have not been sent yet, marking them as sent.
+    ///
+    /// Each episode is sent as an [`Upload`] in chunks of `CHUNK_SIZE` bytes. The server
+    /// answers queries on `EPISODE_TOPIC` with the missing chunks on `ACK_TOPIC`, and only those
+    /// are sent again. An upload that fails is kept, so calling this again after a reconnect
+    /// resumes it.
+    pub fn send_episodes(&mut self, episodes: &mut [Episode]) -> Result<()> {
+        let pending: Vec<_> = episodes.iter_mut().filter(|e| !e.sent).collect();
+        if pending.is_empty() {
+            info!("No episodes to send");
+            return Ok(());
+        }
+
+        self.ensure_connected()?;
+        let result = pending
+            .into_iter()
+            .try_for_each(|episode| self.send_episode(episode));
+        if let Err(e) = self.client.as_mut().unwrap().unsubscribe(ACK_TOPIC) {
+            error!("Failed to unsubscribe from {}: {}", ACK_TOPIC, e);
+        }
+        self.ack_session = 0;
+
+        result
+    }
+
+    /// Take the id of the next episode, saving the following one so that the ids stay unique
+    /// across reboots.
+    fn next_episode_id(&mut self) -> Result<u16> {
+        let id = self.nvs.get_u16(NEXT_EPISODE_KEY)?.unwrap_or(0);
+        self.nvs.set_u16(NEXT_EPISODE_KEY, id.wrapping_add(1))?;
+        Ok(id)
+    }
+
+    fn send_episode(&mut self, episode: &mut Episode) -> Result<()> {
+        let id = match episode.id {
+            Some(id) => id,
+            None => *episode.id.insert(self.next_episode_id()?),
+        };
+        let data = episode.to_message(id).encode()?;
+        let mut upload = match self.upload.take() {
+            Some(upload) if upload.carries(&data) => {
+                info!("Resuming the upload of episode {}", id);
+                upload
+            }
+            // A random id, as the server remembers the uploads across reboots of the device
+            _ => Upload::new(rand::random(), &data, CHUNK_SIZE),
+        };
+
+        let result = self.run_upload(id, &mut upload);
+        match result {
+            Ok(()) => episode.sent = true,
+            Err(_) => self.upload = Some(upload),
+        }
+        result
+    }
+
+    fn run_upload(&mut self, id: u16, upload: &mut Upload) -> Result<()> {
+        let client = self.client.as_mut().unwrap();
+        let query = Message::UploadQuery(upload.query()).encode()?;
+
+        for _ in 0..MAX_ROUNDS {
+            // Subscriptions are lost when the broker reconnects with a clean session
+            let session = self.state.sessions();
+            if session != self.ack_session {
+                client.subscribe(ACK_TOPIC, QoS::AtLeastOnce)?;
+                self.ack_session = session;
+            }
+
+            // Drop acks left from a previous query
+            while self.ack_received.try_recv().is_ok() {}
+
+            client.publish(EPISODE_TOPIC, QoS::AtLeastOnce, false, &query)?;
+            let deadline = std::time::Instant::now() + ACK_TIMEOUT;
+            let mut acked = false;
+            while let Some(timeout) = deadline.checked_duration_since(std::time::Instant::now()) {
+                let Ok(message) = self.ack_received.recv_timeout(timeout) else {
+                    break;
+                };
+                if let Ok(Message::UploadAck(ack)) = Message::decode(&message) {
+                    if upload.on_ack(&ack) {
+                        acked = true;
+                        break;
+                    }
+                }
+            }
+            if !acked {
+                warn!("No ack for episode {}, retrying", id);
+                continue;
+            }
+            if upload.is_complete() {
+                info!("Sent episode {} ({} chunks)", id, upload.num_chunks());
+                return Ok(());
+            }
+
+            let pending: Vec<_> = upload.pending().cloned().collect();
+            info!(
+                "Sending {}/{} chunks of episode {}",
+                pending.len(),
+                upload.num_chunks(),
+                id
+            );
+            for chunk in pending {
+                let payload = Message::UploadChunk(chunk).encode()?;
+                client.publish(EPISODE_TOPIC, QoS::AtLeastOnce, false, &payload)?;
+            }
+        }
+
+        bail!("Failed to send episode {} in {} rounds", id, MAX_ROUNDS)
+    }
+
+    /// Drop the upload kept for resuming, returning the size of its data in bytes.
+    pub fn discard_upload(&mut self) -> usize {
+        self.upload.take().map_or(0, |upload| upload.data_len())
+    }
+
+    /// Receive the latest model parameters from the server.
+    pub fn receive_parameters(&mut self) -> Result<Parameters> {
+        self.ensure_connected()?;
+        let client = self.client.as_mut().unwrap();
+
+        // Drop messages left from a previous request
+        while self.parameter_received.try_recv().is_ok() {}
+
+        client.subscribe(PARAMETER_TOPIC, QoS::AtLeastOnce)?;
+        info!("Waiting for parameters on {}...", PARAMETER_TOPIC);
+        let message = self.parameter_received.recv_timeout(RECEIVE_TIMEOUT);
+        if let Err(e) = client.unsubscribe(PARAMETER_TOPIC) {
+            error!("Failed to unsubscribe from {}: {}", PARAMETER_TOPIC, e);
+        }
+
+        match message {
+            Ok(message) => {
+                info!("Received {} bytes of parameters", message.len());
+                match Message::decode(&message)? {
+                    Message::Parameters(parameters) => Ok(parameters),
+                    _ => bail!("Not a parameter message"),
+                }
+            }
+            Err(_) => bail!("Timed out waiting for parameters"),
+        }
+    }
+}
@@ -0,0 +1,9 @@
+use crate::env::{PendulumEnv, PendulumEnvAct, PendulumEnvObs};
+use border_core::Policy;
+pub use pendulum_core::signal_policy::SignalPolicy;
+
+impl<'d> Policy<PendulumEnv<'d>> for SignalPolicy {
+    fn sample(&mut self, _obs: &PendulumEnvObs) -> PendulumEnvAct {
+        self.next_action().into()
+    }
+}
@@ -0,0 +1,91 @@
+//! Drives the RGB LED of the M5Stamp C3U to show the status.
+use anyhow::Result;
+use esp_idf_svc::hal::gpio::OutputPin;
+use esp_idf_svc::hal::peripheral::Peripheral;
+use esp_idf_svc::hal::rmt::config::TransmitConfig;
+use esp_idf_svc::hal::rmt::{FixedLengthSignal, PinState, Pulse, RmtChannel, TxRmtDriver};
+use esp_idf_svc::sys::esp_timer_get_time;
+use pendulum_core::indicator::{Indicator, Rgb, Status, StatusLed};
+use pendulum_core::state;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+/// Period of the LED task.
+const UPDATE_PERIOD_MS: u64 = 50;
+
+/// Time for which a fault is shown after it is reported.
+const FAULT_DISPLAY_MS: u32 = 3000;
+
+/// Time when the last fault was reported, plus one so that 0 means no fault.
+static FAULT_AT: AtomicU32 = AtomicU32::new(0);
+
+/// Milliseconds since boot, wrapping around after about 49 days.
+fn now_ms() -> u32 {
+    (unsafe { esp_timer_get_time() } / 1000) as u32
+}
+
+/// Show the fault pattern for a while, e.g. when sending episodes failed.
+pub fn report_fault() {
+    FAULT_AT.store(now_ms().wrapping_add(1), Ordering::Release);
+}
+
+fn has_fault(now_ms: u32) -> bool {
+    match FAULT_AT.load(Ordering::Acquire) {
+        0 => false,
+        at => now_ms.wrapping_sub(at.wrapping_sub(1)) < FAULT_DISPLAY_MS,
+    }
+}
+
+/// A WS2812 LED driven by RMT.
+pub struct Ws2812<'d> {
+    tx: TxRmtDriver<'d>,
+}
+
+impl<'d> Ws2812<'d> {
+    pub fn new<C: RmtChannel>(
+        channel: impl Peripheral<P = C> + 'd,
+        pin: impl Peripheral<P = impl OutputPin> + 'd,
+    ) -> Result<Self> {
+        let config = TransmitConfig::new().clock_divider(1);
+        let tx = TxRmtDriver::new(channel, pin, &config)?;
+        Ok(Ws2812 { tx })
+    }
+}
+
+impl Indicator for Ws2812<'_> {
+    fn set_color(&mut self, color: Rgb) -> Result<()> {
+        // The LED takes 24 bits in GRB order, MSB first
+        let bits = (color.g as u32) << 16 | (color.r as u32) << 8 | color.b as u32;
+        let ticks_hz = self.tx.counter_clock()?;
+        let t0h = Pulse::new_with_duration(ticks_hz, PinState::High, &Duration::from_nanos(350))?;
+        let t0l = Pulse::new_with_duration(ticks_hz, PinState::Low, &Duration::from_nanos(800))?;
+        let t1h = Pulse::new_with_duration(ticks_hz, PinState::High, &Duration::from_nanos(700))?;
+        let t1l = Pulse::new_with_duration(ticks_hz, PinState::Low, &Duration::from_nanos(600))?;
+
+        let mut signal = FixedLengthSignal::<24>::new();
+        for i in 0..24 {
+            let bit = bits & (1 << (23 - i)) != 0;
+            let pulses = if bit { (t1h, t1l) } else { (t0h, t0l) };
+            signal.set(i, &pulses)?;
+        }
+        self.tx.start_blocking(&signal)?;
+        Ok(())
+    }
+}
+
+/// Start a task that shows the current state on the LED.
+pub fn spawn(led: Ws2812<'static>) -> Result<()> {
+    let mut led = StatusLed::new(led);
+    std::thread::Builder::new()
+        .name("status_led".into())
+        .stack_size(4096)
+        .spawn(move || loop {
+            let now = now_ms();
+            let status = Status::select(state::current(), has_fault(now));
+            if let Err(e) = led.update(status, now) {
+                log::error!("Failed to update the LED: {:?}", e);
+            }
+            std::thread::sleep(Duration::from_millis(UPDATE_PERIOD_MS));
+        })?;
+    Ok(())
+}
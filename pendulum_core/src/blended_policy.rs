@@ -0,0 +1,109 @@
+//! Mixing of the actions of an operator and an automatic policy.
+use std::f32::consts::PI;
+
+/// How the automatic policy is weighted against the operator.
+///
+/// Angles are those of the calibrated environment: 0 at the hanging position and +-PI at
+/// upright.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Blend {
+    /// Constant weight of the automatic policy in [0, 1].
+    Fixed(f32),
+
+    /// The automatic policy takes over near the upright position.
+    ///
+    /// The weight is 1 when the pendulum is within `threshold` radians of upright and decreases
+    /// linearly to 0 over the next `width` radians.
+    Upright { threshold: f32, width: f32 },
+}
+
+impl Blend {
+    /// Weight of the automatic policy for the given angle.
+    pub fn weight(&self, angle: f32) -> f32 {
+        match self {
+            Blend::Fixed(weight) => weight.clamp(0.0, 1.0),
+            Blend::Upright { threshold, width } => {
+                let distance = PI - angle.abs();
+                if distance <= *threshold {
+                    1.0
+                } else if *width <= 0.0 {
+                    0.0
+                } else {
+                    (1.0 - (distance - threshold) / width).clamp(0.0, 1.0)
+                }
+            }
+        }
+    }
+
+    /// Mix the actions as `w * auto + (1 - w) * human`, clamped to [-1, 1].
+    pub fn mix(&self, angle: f32, human: f32, auto: f32) -> f32 {
+        let weight = self.weight(angle);
+        (weight * auto + (1.0 - weight) * human).clamp(-1.0, 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UPRIGHT: Blend = Blend::Upright {
+        threshold: 0.3,
+        width: 0.5,
+    };
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-5,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn fixed_weights_are_clamped() {
+        assert_eq!(Blend::Fixed(0.25).weight(1.0), 0.25);
+        assert_eq!(Blend::Fixed(-0.5).weight(1.0), 0.0);
+        assert_eq!(Blend::Fixed(1.5).weight(1.0), 1.0);
+    }
+
+    #[test]
+    fn upright_weights() {
+        // The automatic policy is in control within the threshold on either side
+        assert_eq!(UPRIGHT.weight(PI), 1.0);
+        assert_eq!(UPRIGHT.weight(-PI), 1.0);
+        assert_eq!(UPRIGHT.weight(PI - 0.3), 1.0);
+        assert_eq!(UPRIGHT.weight(-(PI - 0.3)), 1.0);
+
+        // and hands over linearly to the operator over the width
+        assert_close(UPRIGHT.weight(PI - 0.55), 0.5);
+        assert_close(UPRIGHT.weight(-(PI - 0.55)), 0.5);
+        assert_close(UPRIGHT.weight(PI - 0.8), 0.0);
+
+        // The operator is in control near the hanging position
+        assert_eq!(UPRIGHT.weight(0.0), 0.0);
+        assert_eq!(UPRIGHT.weight(1.0), 0.0);
+    }
+
+    #[test]
+    fn zero_width_switches_at_the_threshold() {
+        let blend = Blend::Upright {
+            threshold: 0.3,
+            width: 0.0,
+        };
+        assert_eq!(blend.weight(PI - 0.29), 1.0);
+        assert_eq!(blend.weight(PI - 0.31), 0.0);
+    }
+
+    #[test]
+    fn mixed_actions() {
+        assert_close(Blend::Fixed(0.25).mix(0.0, 0.4, -0.4), 0.2);
+        assert_eq!(UPRIGHT.mix(0.0, 0.4, -0.4), 0.4);
+        assert_eq!(UPRIGHT.mix(PI, 0.4, -0.4), -0.4);
+        assert_close(UPRIGHT.mix(PI - 0.55, 0.4, -0.2), 0.1);
+
+        // Actions out of range are clamped
+        assert_eq!(Blend::Fixed(0.5).mix(0.0, 2.0, 2.0), 1.0);
+        assert_eq!(Blend::Fixed(0.5).mix(0.0, -2.0, -2.0), -1.0);
+    }
+}
@@ -0,0 +1,404 @@
+//! Debouncing and gesture recognition for the buttons.
+//!
+//! The interrupt handlers only push timestamped edges into [`EdgeQueue`]. A task then feeds
+//! the edges to a [`ButtonInput`] per button, which debounces them and recognizes gestures.
+use crate::state::Event;
+use std::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+/// A level change must be stable for this time to be accepted.
+pub const DEBOUNCE_MS: u32 = 20;
+
+/// A press held for this time is a long press.
+pub const LONG_PRESS_MS: u32 = 800;
+
+/// A second press starting within this time after the first release is a double press.
+///
+/// On buttons with a double press, a short press is reported only after this time has passed
+/// without a second press.
+pub const DOUBLE_PRESS_MS: u32 = 300;
+
+/// Number of edges the queue can hold.
+const QUEUE_SIZE: usize = 32;
+
+/// A button gesture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gesture {
+    Short,
+    Long,
+    Double,
+}
+
+/// Map a gesture on a button to an event, or `None` if the gesture has no meaning.
+///
+/// | button | short    | long                | double          |
+/// |--------|----------|---------------------|-----------------|
+/// | 1      | Cancel   | Clear calibration   |                 |
+/// | 2      | Confirm  | Send episodes       |                 |
+/// | 3      | Run      | Receive parameters  |                 |
+/// | 4      |          |                     | Clear episodes  |
+///
+/// Clearing episodes needs a double press so that it is not triggered by accident. The other
+/// buttons have no double press, so their short presses, including Cancel, are not delayed.
+pub fn event_for(button: usize, gesture: Gesture) -> Option<Event> {
+    match (button, gesture) {
+        (0, Gesture::Short) => Some(Event::Cancel),
+        (0, Gesture::Long) => Some(Event::ClearCalibration),
+        (1, Gesture::Short) => Some(Event::Confirm),
+        (1, Gesture::Long) => Some(Event::SendEpisodes),
+        (2, Gesture::Short) => Some(Event::Run),
+        (2, Gesture::Long) => Some(Event::ReceiveParameters),
+        (3, Gesture::Double) => Some(Event::ClearEpisodes),
+        _ => None,
+    }
+}
+
+/// Gesture recognizer for a button, waiting for double presses only if it has one.
+pub fn input_for(button: usize) -> ButtonInput {
+    ButtonInput::new(event_for(button, Gesture::Double).is_some())
+}
+
+/// A raw edge of a button.
+#[derive(Debug, Clone, Copy)]
+pub struct Edge {
+    pub button: u8,
+    pub pressed: bool,
+    pub time_ms: u32,
+}
+
+/// A fixed-size queue of edges from the interrupt handlers to a task.
+///
+/// The queue is lock-free with a single producer and a single consumer. The GPIO interrupt
+/// handlers of all buttons are called from the same interrupt and never preempt each other,
+/// so they count as a single producer. Edges pushed while the queue is full are dropped.
+pub struct EdgeQueue {
+    times: [AtomicU32; QUEUE_SIZE],
+    infos: [AtomicU8; QUEUE_SIZE],
+    head: AtomicU32,
+    tail: AtomicU32,
+}
+
+impl EdgeQueue {
+    pub const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const TIME: AtomicU32 = AtomicU32::new(0);
+        #[allow(clippy::declare_interior_mutable_const)]
+        const INFO: AtomicU8 = AtomicU8::new(0);
+        EdgeQueue {
+            times: [TIME; QUEUE_SIZE],
+            infos: [INFO; QUEUE_SIZE],
+            head: AtomicU32::new(0),
+            tail: AtomicU32::new(0),
+        }
+    }
+
+    /// Push an edge. Returns `false` if the queue is full.
+    pub fn push(&self, edge: Edge) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head.wrapping_sub(tail) as usize >= QUEUE_SIZE {
+            return false;
+        }
+        let slot = head as usize % QUEUE_SIZE;
+        self.times[slot].store(edge.time_ms, Ordering::Relaxed);
+        self.infos[slot].store(edge.button << 1 | edge.pressed as u8, Ordering::Relaxed);
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Pop the oldest edge.
+    pub fn pop(&self) -> Option<Edge> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let slot = tail as usize % QUEUE_SIZE;
+        let time_ms = self.times[slot].load(Ordering::Relaxed);
+        let info = self.infos[slot].load(Ordering::Relaxed);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(Edge {
+            button: info >> 1,
+            pressed: info & 1 == 1,
+            time_ms,
+        })
+    }
+}
+
+impl Default for EdgeQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Debouncer and gesture recognizer for a single button.
+///
+/// Times are in milliseconds and may wrap around.
+#[derive(Debug, Default)]
+pub struct ButtonInput {
+    double_press: bool,
+    raw: bool,
+    raw_time: u32,
+    stable: bool,
+    pressed_at: Option<u32>,
+    long_reported: bool,
+    second_press: bool,
+    released_at: Option<u32>,
+}
+
+impl ButtonInput {
+    /// Create a button that recognizes double presses if `double_press` is true. Otherwise a
+    /// short press is reported on release.
+    pub fn new(double_press: bool) -> Self {
+        ButtonInput {
+            double_press,
+            ..Default::default()
+        }
+    }
+
+    /// Record a raw edge.
+    pub fn edge(&mut self, pressed: bool, time_ms: u32) {
+        if pressed != self.raw {
+            self.raw = pressed;
+            self.raw_time = time_ms;
+        }
+    }
+
+    /// Update the debounced state at time `now_ms` and return a recognized gesture, if any.
+    ///
+    /// This should be called periodically, at least every `DEBOUNCE_MS`.
+    pub fn poll(&mut self, now_ms: u32) -> Option<Gesture> {
+        // Accept the raw level once it has been stable long enough
+        if self.raw != self.stable && now_ms.wrapping_sub(self.raw_time) >= DEBOUNCE_MS {
+            self.stable = self.raw;
+            let time = self.raw_time;
+
+            if self.stable {
+                self.pressed_at = Some(time);
+                self.long_reported = false;
+                if let Some(released_at) = self.released_at.take() {
+                    if time.wrapping_sub(released_at) <= DOUBLE_PRESS_MS {
+                        self.second_press = true;
+                    } else {
+                        // The first press was not followed in time, report it now
+                        return Some(Gesture::Short);
+                    }
+                }
+            } else if self.pressed_at.take().is_some() && !self.long_reported {
+                if self.second_press {
+                    self.second_press = false;
+                    return Some(Gesture::Double);
+                }
+                if !self.double_press {
+                    return Some(Gesture::Short);
+                }
+                self.released_at = Some(time);
+            }
+        }
+
+        // Report a long press while the button is still held
+        if let (true, Some(pressed_at)) = (self.stable, self.pressed_at) {
+            if !self.long_reported && now_ms.wrapping_sub(pressed_at) >= LONG_PRESS_MS {
+                self.long_reported = true;
+                self.second_press = false;
+                return Some(Gesture::Long);
+            }
+        }
+
+        // Report a short press once a second press can no longer follow
+        if let Some(released_at) = self.released_at {
+            if now_ms.wrapping_sub(released_at) > DOUBLE_PRESS_MS {
+                self.released_at = None;
+                return Some(Gesture::Short);
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `edges` as `(pressed, time_ms)` to a button polled every 5 ms until `end_ms`, and
+    /// return the recognized gestures with their times.
+    fn simulate(
+        mut input: ButtonInput,
+        start_ms: u32,
+        edges: &[(bool, u32)],
+        end_ms: u32,
+    ) -> Vec<(Gesture, u32)> {
+        let mut edges = edges.iter().peekable();
+        let mut gestures = Vec::new();
+        for t in (0..=end_ms).step_by(5) {
+            while let Some((pressed, time)) = edges.next_if(|(_, time)| *time <= t) {
+                input.edge(*pressed, start_ms.wrapping_add(*time));
+            }
+            if let Some(gesture) = input.poll(start_ms.wrapping_add(t)) {
+                gestures.push((gesture, t));
+            }
+        }
+        gestures
+    }
+
+    fn gestures(edges: &[(bool, u32)]) -> Vec<Gesture> {
+        simulate(ButtonInput::new(true), 0, edges, 3000)
+            .into_iter()
+            .map(|(g, _)| g)
+            .collect()
+    }
+
+    #[test]
+    fn short_press_waits_for_a_second_press() {
+        let gestures = simulate(
+            ButtonInput::new(true),
+            0,
+            &[(true, 100), (false, 200)],
+            1000,
+        );
+        assert_eq!(gestures.len(), 1);
+        let (gesture, time) = gestures[0];
+        assert_eq!(gesture, Gesture::Short);
+        assert!((200 + DOUBLE_PRESS_MS + 1..=200 + DOUBLE_PRESS_MS + 10).contains(&time));
+    }
+
+    #[test]
+    fn short_press_is_immediate_without_double_press() {
+        let gestures = simulate(
+            ButtonInput::new(false),
+            0,
+            &[(true, 100), (false, 200)],
+            1000,
+        );
+        assert_eq!(gestures.len(), 1);
+        let (gesture, time) = gestures[0];
+        assert_eq!(gesture, Gesture::Short);
+        assert!((200 + DEBOUNCE_MS..200 + DEBOUNCE_MS + 10).contains(&time));
+
+        // Quick presses are separate short presses
+        let edges = [(true, 100), (false, 200), (true, 350), (false, 450)];
+        let gestures: Vec<_> = simulate(ButtonInput::new(false), 0, &edges, 1000)
+            .into_iter()
+            .map(|(g, _)| g)
+            .collect();
+        assert_eq!(gestures, vec![Gesture::Short, Gesture::Short]);
+    }
+
+    #[test]
+    fn bounces_are_ignored() {
+        let bouncy = [
+            (true, 100),
+            (false, 103),
+            (true, 105),
+            (false, 300),
+            (true, 302),
+            (false, 306),
+        ];
+        assert_eq!(gestures(&bouncy), vec![Gesture::Short]);
+        // A glitch shorter than the debounce time is not a press
+        assert_eq!(gestures(&[(true, 100), (false, 110)]), vec![]);
+    }
+
+    #[test]
+    fn long_press_is_reported_once_while_held() {
+        let gestures = simulate(
+            ButtonInput::new(true),
+            0,
+            &[(true, 100), (false, 2500)],
+            3000,
+        );
+        assert_eq!(gestures.len(), 1);
+        let (gesture, time) = gestures[0];
+        assert_eq!(gesture, Gesture::Long);
+        assert!((100 + LONG_PRESS_MS..2500).contains(&time));
+    }
+
+    #[test]
+    fn double_press() {
+        let edges = [(true, 100), (false, 200), (true, 350), (false, 450)];
+        assert_eq!(gestures(&edges), vec![Gesture::Double]);
+    }
+
+    #[test]
+    fn slow_second_press_is_two_short_presses() {
+        let edges = [(true, 100), (false, 200), (true, 700), (false, 800)];
+        assert_eq!(gestures(&edges), vec![Gesture::Short, Gesture::Short]);
+    }
+
+    #[test]
+    fn long_second_press_is_long() {
+        let edges = [(true, 100), (false, 200), (true, 350), (false, 1500)];
+        assert_eq!(gestures(&edges), vec![Gesture::Long]);
+    }
+
+    #[test]
+    fn times_may_wrap_around() {
+        let start = u32::MAX - 150;
+        let edges = [(true, 100), (false, 200), (true, 350), (false, 450)];
+        let gestures: Vec<_> = simulate(ButtonInput::new(true), start, &edges, 2000)
+            .into_iter()
+            .map(|(g, _)| g)
+            .collect();
+        assert_eq!(gestures, vec![Gesture::Double]);
+    }
+
+    #[test]
+    fn queue_is_fifo_and_drops_when_full() {
+        let queue = EdgeQueue::new();
+        assert!(queue.pop().is_none());
+        for i in 0..QUEUE_SIZE as u32 {
+            assert!(queue.push(Edge {
+                button: (i % 4) as u8,
+                pressed: i % 2 == 0,
+                time_ms: i,
+            }));
+        }
+        assert!(!queue.push(Edge {
+            button: 0,
+            pressed: true,
+            time_ms: 999,
+        }));
+        for i in 0..QUEUE_SIZE as u32 {
+            let edge = queue.pop().unwrap();
+            assert_eq!(edge.button, (i % 4) as u8);
+            assert_eq!(edge.pressed, i % 2 == 0);
+            assert_eq!(edge.time_ms, i);
+        }
+        assert!(queue.pop().is_none());
+
+        // Slots are reused after the indices pass the end of the buffer
+        for i in 0..3 * QUEUE_SIZE as u32 {
+            let edge = Edge {
+                button: 3,
+                pressed: true,
+                time_ms: i,
+            };
+            assert!(queue.push(edge));
+            assert_eq!(queue.pop().unwrap().time_ms, i);
+        }
+    }
+
+    #[test]
+    fn gestures_map_to_events() {
+        assert_eq!(event_for(0, Gesture::Short), Some(Event::Cancel));
+        assert_eq!(event_for(0, Gesture::Long), Some(Event::ClearCalibration));
+        assert_eq!(event_for(1, Gesture::Short), Some(Event::Confirm));
+        assert_eq!(event_for(1, Gesture::Long), Some(Event::SendEpisodes));
+        assert_eq!(event_for(2, Gesture::Short), Some(Event::Run));
+        assert_eq!(event_for(2, Gesture::Long), Some(Event::ReceiveParameters));
+        assert_eq!(event_for(3, Gesture::Double), Some(Event::ClearEpisodes));
+        // Clearing episodes is not triggered by a single press
+        assert_eq!(event_for(3, Gesture::Short), None);
+        assert_eq!(event_for(3, Gesture::Long), None);
+        assert_eq!(event_for(0, Gesture::Double), None);
+        assert_eq!(event_for(4, Gesture::Short), None);
+    }
+
+    #[test]
+    fn only_button_4_waits_for_double_presses() {
+        let double_press: Vec<_> = (0..4)
+            .map(|button| input_for(button).double_press)
+            .collect();
+        assert_eq!(double_press, [false, false, false, true]);
+    }
+}
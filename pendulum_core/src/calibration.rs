@@ -0,0 +1,130 @@
+//! Calibration values persisted across reboots.
+use anyhow::{bail, Result};
+
+/// Version of the serialized layout. Increase it when the layout changes, so that values
+/// saved by older firmware are ignored instead of misread.
+pub const SCHEMA_VERSION: u8 = 1;
+
+/// Size of the serialized calibration.
+pub const SIZE: usize = 12;
+
+/// Offset and direction of the rotary encoder and the potentiometer limits.
+///
+/// The serialized layout is (little endian):
+///
+/// | field     | type                   |
+/// |-----------|------------------------|
+/// | version   | `u8`, `SCHEMA_VERSION` |
+/// | direction | `i8`, 1 or -1          |
+/// | reserved  | `u8; 2`                |
+/// | offset    | `f32`, radians         |
+/// | min_limit | `u16`, raw ADC value   |
+/// | max_limit | `u16`, raw ADC value   |
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    pub offset: f32,
+    pub direction: f32,
+    pub min_limit: u16,
+    pub max_limit: u16,
+}
+
+impl Calibration {
+    pub fn to_bytes(&self) -> [u8; SIZE] {
+        let mut bytes = [0; SIZE];
+        bytes[0] = SCHEMA_VERSION;
+        bytes[1] = if self.direction < 0.0 { -1i8 } else { 1 } as u8;
+        bytes[4..8].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[8..10].copy_from_slice(&self.min_limit.to_le_bytes());
+        bytes[10..12].copy_from_slice(&self.max_limit.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != SIZE {
+            bail!(
+                "Expected {} bytes of calibration, got {}",
+                SIZE,
+                bytes.len()
+            );
+        }
+        if bytes[0] != SCHEMA_VERSION {
+            bail!(
+                "Calibration schema version {} is not supported (expected {})",
+                bytes[0],
+                SCHEMA_VERSION
+            );
+        }
+        let direction = match bytes[1] as i8 {
+            1 => 1.0,
+            -1 => -1.0,
+            d => bail!("Invalid direction {}", d),
+        };
+        let offset = f32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        if !offset.is_finite() {
+            bail!("Invalid offset {}", offset);
+        }
+
+        Ok(Calibration {
+            offset,
+            direction,
+            min_limit: u16::from_le_bytes([bytes[8], bytes[9]]),
+            max_limit: u16::from_le_bytes([bytes[10], bytes[11]]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CALIBRATION: Calibration = Calibration {
+        offset: 1.25,
+        direction: -1.0,
+        min_limit: 300,
+        max_limit: 3800,
+    };
+
+    #[test]
+    fn round_trip() {
+        let bytes = CALIBRATION.to_bytes();
+        assert_eq!(Calibration::from_bytes(&bytes).unwrap(), CALIBRATION);
+
+        let forward = Calibration {
+            direction: 1.0,
+            ..CALIBRATION
+        };
+        assert_eq!(
+            Calibration::from_bytes(&forward.to_bytes()).unwrap(),
+            forward
+        );
+    }
+
+    #[test]
+    fn layout() {
+        let bytes = CALIBRATION.to_bytes();
+        assert_eq!(bytes[0], SCHEMA_VERSION);
+        assert_eq!(bytes[1], 0xff);
+        assert_eq!(&bytes[2..4], &[0, 0]);
+        assert_eq!(&bytes[4..8], &1.25f32.to_le_bytes());
+        assert_eq!(&bytes[8..12], &[0x2c, 0x01, 0xd8, 0x0e]);
+    }
+
+    #[test]
+    fn invalid_bytes() {
+        let bytes = CALIBRATION.to_bytes();
+        assert!(Calibration::from_bytes(&bytes[..SIZE - 1]).is_err());
+        assert!(Calibration::from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+
+        let mut other_version = bytes;
+        other_version[0] = SCHEMA_VERSION + 1;
+        assert!(Calibration::from_bytes(&other_version).is_err());
+
+        let mut direction = bytes;
+        direction[1] = 0;
+        assert!(Calibration::from_bytes(&direction).is_err());
+
+        let mut offset = bytes;
+        offset[4..8].copy_from_slice(&f32::NAN.to_le_bytes());
+        assert!(Calibration::from_bytes(&offset).is_err());
+    }
+}
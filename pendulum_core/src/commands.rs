@@ -0,0 +1,423 @@
+//! Parser for the commands of the serial console.
+use crate::blended_policy::Blend;
+use crate::noisy_policy::{Noise, Schedule};
+use crate::signal_policy::Signal;
+use crate::state::Event;
+use anyhow::{bail, Context, Result};
+
+/// Help text printed by the `help` command.
+pub const HELP: &str = "\
+Commands:
+  help                     Show this help
+  state                    Print the current state
+  cancel                   Same as a short press on button 1
+  confirm                  Same as a short press on button 2
+  calibrate                Start the calibration from the idle state
+  run                      Same as a short press on button 3
+  run auto [<steps>]       Run the automatic policy, for <steps> steps if given
+  send                     Send the episodes to the server
+  receive                  Receive model parameters from the server
+  clear episodes           Clear the buffered episodes
+  clear calibration        Remove the saved calibration
+  set <name> <value>       Set scale, deadband, expo, smoothing or oversampling
+  signal <waveform> ...    Set the waveform of the signal policy, see below
+  noise <kind> ...         Set the exploration noise of the automatic policy, see below
+  noise seed <seed>        Seed the noise and count the episodes from it again
+  noise schedule ...       Set the annealing of the noise scale, see below
+  blend fixed <weight>     Weight the automatic policy by <weight> in blended runs
+  blend upright <threshold> <width>
+                           Hand over to the automatic policy within <threshold> rad
+                           of upright, blending over the next <width> rad
+  dump episode [<index>]   Print an episode, the last one by default
+  dump demo [<index>]      Print a manual episode for bc_trainer, the last one by default
+
+Waveforms (frequencies in Hz, times in seconds):
+  sine <f>, chirp <f0> <f1> <duration>, square <f> <duty>, step <delay>,
+  prbs <bit period> [<seed>], multisine <f> [<f> ...]
+
+Noises (off at boot):
+  off, gaussian <sigma>, ou <theta> <sigma>
+
+Schedules (the steps count across episodes):
+  constant, linear <final scale> <steps>, exponential <decay> <min scale>";
+
+/// A parameter that can be changed from the console.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Setting {
+    /// Scale of the action applied to the servo, see `PendulumEnv`.
+    Scale(f32),
+    Deadband(f32),
+    Expo(f32),
+    Smoothing(f32),
+    Oversampling(usize),
+}
+
+/// A console command.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Help,
+    State,
+
+    /// Dispatch an event, as a button gesture does.
+    Event(Event),
+
+    /// Start the calibration. Unlike `confirm`, this is only accepted in the idle state.
+    Calibrate,
+
+    /// Run the automatic policy from the idle state, for a number of steps if given.
+    RunAuto {
+        steps: Option<usize>,
+    },
+    Set(Setting),
+
+    /// Replace the waveform of the automatic policy while it is a signal policy.
+    Signal(Signal),
+
+    /// Set the exploration noise of the automatic policy, `None` to turn it off.
+    Noise(Option<Noise>),
+    NoiseSeed(u64),
+    NoiseSchedule(Schedule),
+
+    /// Set how blended runs weight the automatic policy against the operator.
+    Blend(Blend),
+
+    /// Print an episode, the last one if `index` is `None`.
+    DumpEpisode {
+        index: Option<usize>,
+    },
+
+    /// Print a manual episode as a demonstration, the last one if `index` is `None`.
+    DumpDemonstration {
+        index: Option<usize>,
+    },
+}
+
+/// Parse a line. Words are separated by whitespace and are case insensitive.
+///
+/// Returns `None` for an empty line.
+pub fn parse(line: &str) -> Result<Option<Command>> {
+    let line = line.trim().to_ascii_lowercase();
+    let words: Vec<&str> = line.split_whitespace().collect();
+
+    let command = match words.as_slice() {
+        [] => return Ok(None),
+        ["help"] | ["?"] => Command::Help,
+        ["state"] => Command::State,
+        ["cancel"] => Command::Event(Event::Cancel),
+        ["confirm"] => Command::Event(Event::Confirm),
+        ["calibrate"] => Command::Calibrate,
+        ["run"] => Command::Event(Event::Run),
+        ["run", "auto"] => Command::RunAuto { steps: None },
+        ["run", "auto", steps] => Command::RunAuto {
+            steps: Some(parse_value(steps, "steps")?),
+        },
+        ["send"] => Command::Event(Event::SendEpisodes),
+        ["receive"] => Command::Event(Event::ReceiveParameters),
+        ["clear", "episodes"] => Command::Event(Event::ClearEpisodes),
+        ["clear", "calibration"] => Command::Event(Event::ClearCalibration),
+        ["set", name, value] => Command::Set(match *name {
+            "scale" => Setting::Scale(parse_value(value, name)?),
+            "deadband" => Setting::Deadband(parse_value(value, name)?),
+            "expo" => Setting::Expo(parse_value(value, name)?),
+            "smoothing" => Setting::Smoothing(parse_value(value, name)?),
+            "oversampling" => Setting::Oversampling(parse_value(value, name)?),
+            _ => bail!("Unknown setting: {}", name),
+        }),
+        ["signal", waveform, args @ ..] => Command::Signal(parse_signal(waveform, args)?),
+        ["noise", "off"] => Command::Noise(None),
+        ["noise", "gaussian", sigma] => Command::Noise(Some(Noise::Gaussian {
+            sigma: parse_value(sigma, "sigma")?,
+        })),
+        ["noise", "ou", theta, sigma] => Command::Noise(Some(Noise::OrnsteinUhlenbeck {
+            theta: parse_value(theta, "theta")?,
+            sigma: parse_value(sigma, "sigma")?,
+        })),
+        ["noise", "seed", seed] => Command::NoiseSeed(parse_value(seed, "seed")?),
+        ["noise", "schedule", "constant"] => Command::NoiseSchedule(Schedule::Constant),
+        ["noise", "schedule", "linear", final_scale, steps] => {
+            Command::NoiseSchedule(Schedule::Linear {
+                final_scale: parse_value(final_scale, "final scale")?,
+                steps: parse_value(steps, "steps")?,
+            })
+        }
+        ["noise", "schedule", "exponential", decay, min_scale] => {
+            Command::NoiseSchedule(Schedule::Exponential {
+                decay: parse_value(decay, "decay")?,
+                min_scale: parse_value(min_scale, "min scale")?,
+            })
+        }
+        ["blend", "fixed", weight] => Command::Blend(Blend::Fixed(parse_value(weight, "weight")?)),
+        ["blend", "upright", threshold, width] => Command::Blend(Blend::Upright {
+            threshold: parse_value(threshold, "threshold")?,
+            width: parse_value(width, "width")?,
+        }),
+        ["dump", "episode"] => Command::DumpEpisode { index: None },
+        ["dump", "episode", index] => Command::DumpEpisode {
+            index: Some(parse_value(index, "index")?),
+        },
+        ["dump", "demo"] => Command::DumpDemonstration { index: None },
+        ["dump", "demo", index] => Command::DumpDemonstration {
+            index: Some(parse_value(index, "index")?),
+        },
+        _ => bail!("Unknown command: {} (type help for commands)", line.trim()),
+    };
+    Ok(Some(command))
+}
+
+fn parse_signal(waveform: &str, args: &[&str]) -> Result<Signal> {
+    let signal = match (waveform, args) {
+        ("sine", [frequency]) => Signal::Sine {
+            frequency: parse_value(frequency, "frequency")?,
+        },
+        ("chirp", [f0, f1, duration]) => Signal::Chirp {
+            f0: parse_value(f0, "f0")?,
+            f1: parse_value(f1, "f1")?,
+            duration: parse_value(duration, "duration")?,
+        },
+        ("square", [frequency, duty]) => Signal::Square {
+            frequency: parse_value(frequency, "frequency")?,
+            duty: parse_value(duty, "duty")?,
+        },
+        ("step", [delay]) => Signal::Step {
+            delay: parse_value(delay, "delay")?,
+        },
+        ("prbs", [bit_period]) => Signal::Prbs {
+            bit_period: parse_value(bit_period, "bit period")?,
+            seed: 0,
+        },
+        ("prbs", [bit_period, seed]) => Signal::Prbs {
+            bit_period: parse_value(bit_period, "bit period")?,
+            seed: parse_value(seed, "seed")?,
+        },
+        ("multisine", frequencies) if !frequencies.is_empty() => Signal::Multisine {
+            frequencies: frequencies
+                .iter()
+                .map(|f| parse_value(f, "frequency"))
+                .collect::<Result<_>>()?,
+        },
+        ("sine" | "chirp" | "square" | "step" | "prbs" | "multisine", _) => {
+            bail!(
+                "Wrong number of arguments for {} (type help for commands)",
+                waveform
+            )
+        }
+        _ => bail!("Unknown waveform: {}", waveform),
+    };
+    Ok(signal)
+}
+
+fn parse_value<T>(value: &str, name: &str) -> Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    value
+        .parse()
+        .with_context(|| format!("Invalid value for {}: {}", name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(line: &str) -> Command {
+        parse(line).unwrap().unwrap()
+    }
+
+    #[test]
+    fn empty_lines() {
+        assert!(parse("").unwrap().is_none());
+        assert!(parse("   ").unwrap().is_none());
+        assert!(parse("\t\r").unwrap().is_none());
+    }
+
+    #[test]
+    fn every_command() {
+        let cases = [
+            ("help", Command::Help),
+            ("?", Command::Help),
+            ("state", Command::State),
+            ("cancel", Command::Event(Event::Cancel)),
+            ("confirm", Command::Event(Event::Confirm)),
+            ("calibrate", Command::Calibrate),
+            ("run", Command::Event(Event::Run)),
+            ("run auto", Command::RunAuto { steps: None }),
+            ("run auto 500", Command::RunAuto { steps: Some(500) }),
+            ("send", Command::Event(Event::SendEpisodes)),
+            ("receive", Command::Event(Event::ReceiveParameters)),
+            ("clear episodes", Command::Event(Event::ClearEpisodes)),
+            ("clear calibration", Command::Event(Event::ClearCalibration)),
+            ("set scale 0.5", Command::Set(Setting::Scale(0.5))),
+            ("set deadband 0.05", Command::Set(Setting::Deadband(0.05))),
+            ("set expo 0.3", Command::Set(Setting::Expo(0.3))),
+            ("set smoothing 1", Command::Set(Setting::Smoothing(1.0))),
+            ("set oversampling 8", Command::Set(Setting::Oversampling(8))),
+            (
+                "signal sine 0.2",
+                Command::Signal(Signal::Sine { frequency: 0.2 }),
+            ),
+            ("noise off", Command::Noise(None)),
+            (
+                "noise gaussian 0.1",
+                Command::Noise(Some(Noise::Gaussian { sigma: 0.1 })),
+            ),
+            (
+                "noise ou 1 0.2",
+                Command::Noise(Some(Noise::OrnsteinUhlenbeck {
+                    theta: 1.0,
+                    sigma: 0.2,
+                })),
+            ),
+            ("noise seed 12", Command::NoiseSeed(12)),
+            (
+                "noise schedule constant",
+                Command::NoiseSchedule(Schedule::Constant),
+            ),
+            (
+                "noise schedule linear 0.1 5000",
+                Command::NoiseSchedule(Schedule::Linear {
+                    final_scale: 0.1,
+                    steps: 5000,
+                }),
+            ),
+            (
+                "noise schedule exponential 0.999 0.05",
+                Command::NoiseSchedule(Schedule::Exponential {
+                    decay: 0.999,
+                    min_scale: 0.05,
+                }),
+            ),
+            ("blend fixed 0.5", Command::Blend(Blend::Fixed(0.5))),
+            (
+                "blend upright 0.3 0.5",
+                Command::Blend(Blend::Upright {
+                    threshold: 0.3,
+                    width: 0.5,
+                }),
+            ),
+            ("dump episode", Command::DumpEpisode { index: None }),
+            ("dump episode 3", Command::DumpEpisode { index: Some(3) }),
+            ("dump demo", Command::DumpDemonstration { index: None }),
+            ("dump demo 2", Command::DumpDemonstration { index: Some(2) }),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(command(line), expected, "{}", line);
+        }
+    }
+
+    #[test]
+    fn case_and_whitespace_are_ignored() {
+        assert_eq!(
+            command("  Run   AUTO\t42 \r"),
+            Command::RunAuto { steps: Some(42) }
+        );
+        assert_eq!(
+            command("SET Scale 0.25"),
+            Command::Set(Setting::Scale(0.25))
+        );
+    }
+
+    #[test]
+    fn bad_numbers() {
+        for line in [
+            "run auto -1",
+            "run auto 1.5",
+            "run auto many",
+            "set scale half",
+            "set oversampling -2",
+            "set oversampling 2.5",
+            "noise seed -1",
+            "noise schedule linear 0.1 many",
+            "blend fixed half",
+            "dump episode last",
+        ] {
+            let error = parse(line).unwrap_err().to_string();
+            assert!(error.starts_with("Invalid value"), "{}: {}", line, error);
+        }
+    }
+
+    #[test]
+    fn extra_arguments() {
+        for line in [
+            "help me",
+            "state now",
+            "cancel 1",
+            "calibrate again",
+            "run auto 10 20",
+            "send all",
+            "clear episodes now",
+            "set scale 0.5 1",
+            "noise",
+            "noise ou 0.2",
+            "noise schedule cosine",
+            "blend upright 0.3",
+            "dump episode 1 2",
+            "dump demo 1 2",
+        ] {
+            let error = parse(line).unwrap_err().to_string();
+            assert!(error.starts_with("Unknown command"), "{}: {}", line, error);
+        }
+    }
+
+    #[test]
+    fn signal_waveforms() {
+        let cases = [
+            (
+                "signal chirp 0.1 2 10",
+                Signal::Chirp {
+                    f0: 0.1,
+                    f1: 2.0,
+                    duration: 10.0,
+                },
+            ),
+            (
+                "signal square 0.5 0.25",
+                Signal::Square {
+                    frequency: 0.5,
+                    duty: 0.25,
+                },
+            ),
+            ("signal step 1.5", Signal::Step { delay: 1.5 }),
+            (
+                "signal prbs 0.2",
+                Signal::Prbs {
+                    bit_period: 0.2,
+                    seed: 0,
+                },
+            ),
+            (
+                "signal prbs 0.2 7",
+                Signal::Prbs {
+                    bit_period: 0.2,
+                    seed: 7,
+                },
+            ),
+            (
+                "signal multisine 0.1 0.3 0.7",
+                Signal::Multisine {
+                    frequencies: vec![0.1, 0.3, 0.7],
+                },
+            ),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(command(line), Command::Signal(expected), "{}", line);
+        }
+
+        for line in ["signal sine", "signal chirp 0.1 2", "signal multisine"] {
+            let error = parse(line).unwrap_err().to_string();
+            assert!(error.starts_with("Wrong number"), "{}: {}", line, error);
+        }
+        assert!(parse("signal prbs 0.2 70000").is_err());
+        let error = parse("signal noise 1").unwrap_err().to_string();
+        assert!(error.starts_with("Unknown waveform"), "{}", error);
+    }
+
+    #[test]
+    fn unknown_commands_and_settings() {
+        assert!(parse("jump").is_err());
+        assert!(parse("clear").is_err());
+        assert!(parse("set scale").is_err());
+        let error = parse("set gain 2").unwrap_err().to_string();
+        assert!(error.starts_with("Unknown setting"), "{}", error);
+    }
+}
@@ -0,0 +1,250 @@
+//! Status indication with a single RGB LED.
+//!
+//! The LED is accessed through the [`Indicator`] trait, so that the patterns can be checked on
+//! the host with a mock.
+use crate::state::AppState;
+use anyhow::Result;
+
+/// A color of the LED.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Rgb { r, g, b }
+    }
+
+    pub const OFF: Rgb = Rgb::new(0, 0, 0);
+}
+
+/// A device that shows a color.
+pub trait Indicator {
+    fn set_color(&mut self, color: Rgb) -> Result<()>;
+}
+
+/// What the LED shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Idle,
+    Calibrating,
+    RunningAuto,
+    RunningManual,
+    Uploading,
+    Fault,
+    Off,
+}
+
+/// How a status is shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    Solid(Rgb),
+
+    /// On for half of `period_ms`, then off.
+    Blink {
+        color: Rgb,
+        period_ms: u32,
+    },
+}
+
+impl Status {
+    /// Status for the application state.
+    ///
+    /// `*Start` states are shown as the episode that is still running, and communication is
+    /// shown as uploading in both directions.
+    pub fn from_state(state: AppState) -> Self {
+        use AppState::*;
+        match state {
+            Idle | ClearEpisodes | ClearCalibration => Status::Idle,
+            OffsetCorrection | OffsetCorrectionEnd | OffsetCorrectionCancel => Status::Calibrating,
+            PotentiometerMin | PotentiometerMax | PotentiometerCancel => Status::Calibrating,
+            AutoPolicy | ManualPolicyStart | ReplayPolicy => Status::RunningAuto,
+            ManualPolicy | BlendedPolicyStart | BlendedPolicy | ReplayPolicyStart => {
+                Status::RunningManual
+            }
+            SendEpisodes | ReceiveParameters => Status::Uploading,
+            Terminate => Status::Off,
+        }
+    }
+
+    /// Status to show, where a recently reported fault takes precedence over the state.
+    pub fn select(state: AppState, fault: bool) -> Self {
+        if fault {
+            Status::Fault
+        } else {
+            Status::from_state(state)
+        }
+    }
+
+    /// Colors are dim, since the LED is very bright at full scale.
+    pub fn pattern(self) -> Pattern {
+        match self {
+            Status::Idle => Pattern::Solid(Rgb::new(0, 16, 0)),
+            Status::Calibrating => Pattern::Blink {
+                color: Rgb::new(24, 16, 0),
+                period_ms: 1000,
+            },
+            Status::RunningAuto => Pattern::Solid(Rgb::new(0, 0, 32)),
+            Status::RunningManual => Pattern::Solid(Rgb::new(24, 0, 24)),
+            Status::Uploading => Pattern::Blink {
+                color: Rgb::new(0, 16, 16),
+                period_ms: 400,
+            },
+            Status::Fault => Pattern::Blink {
+                color: Rgb::new(32, 0, 0),
+                period_ms: 200,
+            },
+            Status::Off => Pattern::Solid(Rgb::OFF),
+        }
+    }
+}
+
+impl Pattern {
+    /// Color at time `now_ms`.
+    pub fn color(self, now_ms: u32) -> Rgb {
+        match self {
+            Pattern::Solid(color) => color,
+            Pattern::Blink { color, period_ms } => {
+                if now_ms % period_ms < period_ms / 2 {
+                    color
+                } else {
+                    Rgb::OFF
+                }
+            }
+        }
+    }
+}
+
+/// Shows a status on an indicator, writing only when the color changes.
+pub struct StatusLed<I> {
+    indicator: I,
+    color: Option<Rgb>,
+}
+
+impl<I: Indicator> StatusLed<I> {
+    pub fn new(indicator: I) -> Self {
+        StatusLed {
+            indicator,
+            color: None,
+        }
+    }
+
+    /// Show `status` at time `now_ms`. Call this periodically for blinking patterns.
+    pub fn update(&mut self, status: Status, now_ms: u32) -> Result<()> {
+        let color = status.pattern().color(now_ms);
+        if self.color != Some(color) {
+            self.indicator.set_color(color)?;
+            self.color = Some(color);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records the colors written to the LED.
+    #[derive(Default)]
+    struct MockIndicator {
+        colors: Vec<Rgb>,
+        fail: bool,
+    }
+
+    impl Indicator for &mut MockIndicator {
+        fn set_color(&mut self, color: Rgb) -> Result<()> {
+            if self.fail {
+                anyhow::bail!("LED is not connected");
+            }
+            self.colors.push(color);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn states_map_to_statuses() {
+        use AppState::*;
+        let cases = [
+            (Idle, Status::Idle),
+            (ClearEpisodes, Status::Idle),
+            (ClearCalibration, Status::Idle),
+            (OffsetCorrection, Status::Calibrating),
+            (OffsetCorrectionEnd, Status::Calibrating),
+            (OffsetCorrectionCancel, Status::Calibrating),
+            (PotentiometerMin, Status::Calibrating),
+            (PotentiometerMax, Status::Calibrating),
+            (PotentiometerCancel, Status::Calibrating),
+            (AutoPolicy, Status::RunningAuto),
+            (ManualPolicyStart, Status::RunningAuto),
+            (ReplayPolicy, Status::RunningAuto),
+            (ManualPolicy, Status::RunningManual),
+            (BlendedPolicyStart, Status::RunningManual),
+            (BlendedPolicy, Status::RunningManual),
+            (ReplayPolicyStart, Status::RunningManual),
+            (SendEpisodes, Status::Uploading),
+            (ReceiveParameters, Status::Uploading),
+            (Terminate, Status::Off),
+        ];
+        for (state, status) in cases {
+            assert_eq!(Status::from_state(state), status, "{:?}", state);
+            assert_eq!(Status::select(state, false), status, "{:?}", state);
+            assert_eq!(Status::select(state, true), Status::Fault, "{:?}", state);
+        }
+    }
+
+    #[test]
+    fn statuses_are_distinct() {
+        let statuses = [
+            Status::Idle,
+            Status::Calibrating,
+            Status::RunningAuto,
+            Status::RunningManual,
+            Status::Uploading,
+            Status::Fault,
+            Status::Off,
+        ];
+        for (i, a) in statuses.iter().enumerate() {
+            for b in &statuses[i + 1..] {
+                assert_ne!(a.pattern(), b.pattern(), "{:?} and {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn fault_blinks_red() {
+        let mut mock = MockIndicator::default();
+        let mut led = StatusLed::new(&mut mock);
+        for now in (0..400).step_by(50) {
+            led.update(Status::Fault, now).unwrap();
+        }
+        let red = Rgb::new(32, 0, 0);
+        assert_eq!(mock.colors, vec![red, Rgb::OFF, red, Rgb::OFF]);
+    }
+
+    #[test]
+    fn solid_pattern_is_written_once() {
+        let mut mock = MockIndicator::default();
+        let mut led = StatusLed::new(&mut mock);
+        for now in (0..1000).step_by(50) {
+            led.update(Status::RunningAuto, now).unwrap();
+        }
+        led.update(Status::Idle, 1000).unwrap();
+        assert_eq!(mock.colors, vec![Rgb::new(0, 0, 32), Rgb::new(0, 16, 0)]);
+    }
+
+    #[test]
+    fn failed_write_is_retried() {
+        let mut mock = MockIndicator {
+            fail: true,
+            ..Default::default()
+        };
+        let mut led = StatusLed::new(&mut mock);
+        assert!(led.update(Status::Idle, 0).is_err());
+        led.indicator.fail = false;
+        led.update(Status::Idle, 50).unwrap();
+        assert_eq!(led.indicator.colors, vec![Rgb::new(0, 16, 0)]);
+    }
+}
@@ -0,0 +1,16 @@
+//! Parts of `pendulum1` that do not depend on ESP-IDF, so that they can be tested on the host.
+//!
+//! The policies here only compute actions from observations given as `f32`. `pendulum1`
+//! implements `border_core::Policy` for them on its environment.
+pub mod blended_policy;
+pub mod button_events;
+pub mod calibration;
+pub mod commands;
+pub mod indicator;
+pub mod mlp_policy;
+pub mod noisy_policy;
+pub mod potentiometer;
+pub mod replay_policy;
+pub mod signal_policy;
+pub mod state;
+pub mod supervisor;
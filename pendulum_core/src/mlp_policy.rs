@@ -0,0 +1,212 @@
+use anyhow::{bail, ensure, Context, Result};
+use std::collections::VecDeque;
+
+/// Magic bytes at the head of a weight blob.
+const MAGIC: &[u8; 4] = b"MLP1";
+
+/// Size of the header of a weight blob.
+const HEADER_SIZE: usize = 8;
+
+/// Weights of a multilayer perceptron with one hidden layer.
+///
+/// `bc_trainer` writes them and [`MlpPolicy`] reads them as a blob with the following layout
+/// (little endian):
+///
+/// | field   | type              |
+/// |---------|-------------------|
+/// | magic   | `b"MLP1"`         |
+/// | history | `u16`             |
+/// | hidden  | `u16`             |
+/// | w1      | `f32; hidden * history` (row major) |
+/// | b1      | `f32; hidden`     |
+/// | w2      | `f32; hidden`     |
+/// | b2      | `f32`             |
+#[derive(Debug, Clone, PartialEq)]
+pub struct MlpWeights {
+    pub history: usize,
+    pub hidden: usize,
+    /// w1, b1, w2 and b2 in the order of the blob.
+    pub params: Vec<f32>,
+}
+
+impl MlpWeights {
+    /// Number of parameters of a network with the given shape.
+    pub fn num_params(history: usize, hidden: usize) -> usize {
+        hidden * history + 2 * hidden + 1
+    }
+
+    /// Write the weight blob.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let history = u16::try_from(self.history).context("History does not fit in u16")?;
+        let hidden = u16::try_from(self.hidden).context("Hidden size does not fit in u16")?;
+        ensure!(
+            self.params.len() == Self::num_params(self.history, self.hidden),
+            "Expected {} parameters, got {}",
+            Self::num_params(self.history, self.hidden),
+            self.params.len()
+        );
+
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + 4 * self.params.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&history.to_le_bytes());
+        bytes.extend_from_slice(&hidden.to_le_bytes());
+        for p in &self.params {
+            bytes.extend_from_slice(&p.to_le_bytes());
+        }
+        Ok(bytes)
+    }
+
+    /// Read and validate a weight blob.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_SIZE || &bytes[..4] != MAGIC {
+            bail!("Not an MLP weight blob");
+        }
+        let history = u16::from_le_bytes([bytes[4], bytes[5]]) as usize;
+        let hidden = u16::from_le_bytes([bytes[6], bytes[7]]) as usize;
+        if history == 0 || hidden == 0 {
+            bail!("Invalid MLP shape ({}, {})", history, hidden);
+        }
+
+        let num_params = Self::num_params(history, hidden);
+        let body = &bytes[HEADER_SIZE..];
+        if body.len() != 4 * num_params {
+            bail!(
+                "Expected {} bytes of parameters, got {}",
+                4 * num_params,
+                body.len()
+            );
+        }
+        let params = body
+            .chunks_exact(4)
+            .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect();
+
+        Ok(MlpWeights {
+            history,
+            hidden,
+            params,
+        })
+    }
+}
+
+/// A policy with a multilayer perceptron with one hidden layer.
+///
+/// The input of the network is the latest `history` observations, newest first, and the
+/// output is squashed into [-1, 1] with `tanh`.
+pub struct MlpPolicy {
+    history: usize,
+    hidden: usize,
+    w1: Vec<f32>,
+    b1: Vec<f32>,
+    w2: Vec<f32>,
+    b2: f32,
+    inputs: VecDeque<f32>,
+}
+
+impl MlpPolicy {
+    pub fn new(weights: &MlpWeights) -> Self {
+        let (history, hidden) = (weights.history, weights.hidden);
+        let (w1, rest) = weights.params.split_at(hidden * history);
+        let (b1, rest) = rest.split_at(hidden);
+        let (w2, b2) = rest.split_at(hidden);
+        MlpPolicy {
+            history,
+            hidden,
+            w1: w1.to_vec(),
+            b1: b1.to_vec(),
+            w2: w2.to_vec(),
+            b2: b2[0],
+            inputs: VecDeque::with_capacity(history),
+        }
+    }
+
+    /// Load a policy from a weight blob, see [`MlpWeights`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(Self::new(&MlpWeights::from_bytes(bytes)?))
+    }
+
+    /// Forget past observations.
+    ///
+    /// Call this before each episode. The history is then filled with the first observation.
+    pub fn reset(&mut self) {
+        self.inputs.clear();
+    }
+
+    /// Action for the observation `obs`, which is added to the history.
+    pub fn next_action(&mut self, obs: f32) -> f32 {
+        if self.inputs.is_empty() {
+            self.inputs.resize(self.history, obs);
+        } else {
+            self.inputs.pop_back();
+            self.inputs.push_front(obs);
+        }
+        self.forward()
+    }
+
+    fn forward(&self) -> f32 {
+        let mut y = self.b2;
+        for j in 0..self.hidden {
+            let row = &self.w1[j * self.history..(j + 1) * self.history];
+            let h: f32 = row.iter().zip(self.inputs.iter()).map(|(w, x)| w * x).sum();
+            y += self.w2[j] * (h + self.b1[j]).tanh();
+        }
+        y.tanh()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weights() -> MlpWeights {
+        // history 2, hidden 2
+        MlpWeights {
+            history: 2,
+            hidden: 2,
+            params: vec![0.5, -0.25, 1.0, 0.0, 0.1, -0.1, 2.0, -1.0, 0.05],
+        }
+    }
+
+    #[test]
+    fn blob_round_trip() {
+        let bytes = weights().to_bytes().unwrap();
+        assert_eq!(&bytes[..8], b"MLP1\x02\x00\x02\x00");
+        assert_eq!(bytes.len(), 8 + 4 * 9);
+        assert_eq!(MlpWeights::from_bytes(&bytes).unwrap(), weights());
+    }
+
+    #[test]
+    fn reject_invalid_weights() {
+        let mut wide = weights();
+        wide.history = 70000;
+        assert!(wide.to_bytes().is_err());
+
+        let mut short = weights();
+        short.params.pop();
+        assert!(short.to_bytes().is_err());
+
+        let bytes = weights().to_bytes().unwrap();
+        assert!(MlpWeights::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(MlpWeights::from_bytes(b"MLP2\x02\x00\x02\x00").is_err());
+        assert!(MlpWeights::from_bytes(b"MLP1\x00\x00\x02\x00\x00\x00\x00\x00").is_err());
+        assert!(MlpWeights::from_bytes(b"MLP1").is_err());
+    }
+
+    #[test]
+    fn forward_with_history() {
+        let mut policy = MlpPolicy::new(&weights());
+        let expected = |x0: f32, x1: f32| {
+            let h0 = (0.5 * x0 - 0.25 * x1 + 0.1).tanh();
+            let h1 = (1.0 * x0 + 0.0 * x1 - 0.1).tanh();
+            (2.0 * h0 - 1.0 * h1 + 0.05).tanh()
+        };
+
+        // The history is filled with the first observation, newest first afterwards
+        assert!((policy.next_action(0.4) - expected(0.4, 0.4)).abs() < 1e-6);
+        assert!((policy.next_action(-0.2) - expected(-0.2, 0.4)).abs() < 1e-6);
+        assert!((policy.next_action(0.3) - expected(0.3, -0.2)).abs() < 1e-6);
+
+        policy.reset();
+        assert!((policy.next_action(0.3) - expected(0.3, 0.3)).abs() < 1e-6);
+    }
+}
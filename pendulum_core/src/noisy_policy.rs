@@ -0,0 +1,280 @@
+use rand::{rngs::StdRng, SeedableRng};
+use rand_distr::{Distribution, StandardNormal};
+
+/// Exploration noise added by [`NoisyPolicy`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Noise {
+    /// Independent Gaussian noise with standard deviation `sigma`.
+    Gaussian { sigma: f32 },
+
+    /// Ornstein-Uhlenbeck process `dx = -theta * x * dt + sigma * dW`.
+    ///
+    /// The noise is temporally correlated, which excites the slow dynamics of the pendulum
+    /// better than white noise at the same amplitude.
+    OrnsteinUhlenbeck { theta: f32, sigma: f32 },
+}
+
+/// Annealing schedule for the noise scale, as a function of the number of sampled steps.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schedule {
+    /// Keep the noise scale at 1.
+    Constant,
+
+    /// Decrease the scale linearly from 1 to `final_scale` over `steps` steps.
+    Linear { final_scale: f32, steps: usize },
+
+    /// Multiply the scale by `decay` on each step, but not below `min_scale`.
+    Exponential { decay: f32, min_scale: f32 },
+}
+
+impl Schedule {
+    /// Noise scale after `step` steps.
+    fn scale(&self, step: usize) -> f32 {
+        match self {
+            Schedule::Constant => 1.0,
+            Schedule::Linear { final_scale, steps } => {
+                let progress = if *steps == 0 {
+                    1.0
+                } else {
+                    (step as f32 / *steps as f32).min(1.0)
+                };
+                1.0 + (final_scale - 1.0) * progress
+            }
+            Schedule::Exponential { decay, min_scale } => decay
+                .powi(step.min(i32::MAX as usize) as i32)
+                .max(*min_scale),
+        }
+    }
+}
+
+/// A policy wrapper that adds exploration noise to the actions of another policy.
+///
+/// There is no noise until one is set with [`NoisyPolicy::set_noise`]. The noisy action is
+/// clamped to the action range [-1, 1].
+///
+/// The random number generator is reseeded on each [`NoisyPolicy::reset`] with `seed + n`,
+/// where `n` counts the episodes since the seed was set. A data collection run is reproduced by
+/// setting the same seed before it.
+pub struct NoisyPolicy<P> {
+    policy: P,
+    noise: Option<Noise>,
+    schedule: Schedule,
+    dt: f32,
+    seed: u64,
+    episode: u64,
+    rng: StdRng,
+    state: f32,
+    step: usize,
+}
+
+impl<P> NoisyPolicy<P> {
+    /// Wrap `policy`, where `dt` is the step period used by the Ornstein-Uhlenbeck process.
+    pub fn new(policy: P, seed: u64, dt: f32) -> Self {
+        NoisyPolicy {
+            policy,
+            noise: None,
+            schedule: Schedule::Constant,
+            dt,
+            seed,
+            episode: 0,
+            rng: StdRng::seed_from_u64(seed),
+            state: 0.0,
+            step: 0,
+        }
+    }
+
+    /// Set the noise, or turn it off with `None`.
+    pub fn set_noise(&mut self, noise: Option<Noise>) {
+        self.noise = noise;
+        self.state = 0.0;
+    }
+
+    /// Set the annealing schedule of the noise scale and restart it.
+    pub fn set_schedule(&mut self, schedule: Schedule) {
+        self.schedule = schedule;
+        self.step = 0;
+    }
+
+    /// Set the seed and count the episodes from it again.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.episode = 0;
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn noise(&self) -> Option<&Noise> {
+        self.noise.as_ref()
+    }
+
+    /// Change the step period used by the Ornstein-Uhlenbeck process.
+    pub fn set_dt(&mut self, dt: f32) {
+        self.dt = dt;
+    }
+
+    /// Start an episode: reseed the random number generator and reset the state of the
+    /// Ornstein-Uhlenbeck process.
+    ///
+    /// The annealing schedule is not reset, so it keeps progressing across episodes.
+    pub fn reset(&mut self) {
+        self.rng = StdRng::seed_from_u64(self.seed.wrapping_add(self.episode));
+        self.episode = self.episode.wrapping_add(1);
+        self.state = 0.0;
+    }
+
+    /// Get a mutable reference to the wrapped policy.
+    pub fn inner_mut(&mut self) -> &mut P {
+        &mut self.policy
+    }
+
+    /// Add the noise of the current step to `action` of the wrapped policy.
+    pub fn add_noise(&mut self, action: f32) -> f32 {
+        let Some(noise) = self.sample_noise() else {
+            return action.clamp(-1.0, 1.0);
+        };
+        let scale = self.schedule.scale(self.step);
+        self.step = self.step.saturating_add(1);
+        (action + scale * noise).clamp(-1.0, 1.0)
+    }
+
+    fn sample_noise(&mut self) -> Option<f32> {
+        let noise = self.noise.as_ref()?;
+        let z: f32 = StandardNormal.sample(&mut self.rng);
+        let value = match *noise {
+            Noise::Gaussian { sigma } => sigma * z,
+            Noise::OrnsteinUhlenbeck { theta, sigma } => {
+                // Euler-Maruyama discretization
+                self.state += -theta * self.state * self.dt + sigma * self.dt.sqrt() * z;
+                self.state
+            }
+        };
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.02;
+
+    fn run(policy: &mut NoisyPolicy<()>, action: f32, steps: usize) -> Vec<f32> {
+        (0..steps).map(|_| policy.add_noise(action)).collect()
+    }
+
+    fn gaussian(sigma: f32, seed: u64) -> NoisyPolicy<()> {
+        let mut policy = NoisyPolicy::new((), seed, DT);
+        policy.set_noise(Some(Noise::Gaussian { sigma }));
+        policy.reset();
+        policy
+    }
+
+    #[test]
+    fn no_noise_by_default() {
+        let mut policy = NoisyPolicy::new((), 0, DT);
+        policy.reset();
+        assert_eq!(policy.noise(), None);
+        assert_eq!(run(&mut policy, 0.3, 3), [0.3, 0.3, 0.3]);
+        assert_eq!(policy.add_noise(1.5), 1.0);
+    }
+
+    #[test]
+    fn seeded_episodes_are_reproducible() {
+        let mut a = gaussian(0.1, 42);
+        let mut b = gaussian(0.1, 42);
+        let first = run(&mut a, 0.0, 50);
+        assert_eq!(first, run(&mut b, 0.0, 50));
+        assert_ne!(first, run(&mut gaussian(0.1, 43), 0.0, 50));
+
+        // The next episode draws other noise, but the same for the same seed
+        a.reset();
+        b.reset();
+        let second = run(&mut a, 0.0, 50);
+        assert_ne!(first, second);
+        assert_eq!(second, run(&mut b, 0.0, 50));
+
+        // Setting the seed again replays the run
+        a.set_seed(42);
+        a.reset();
+        assert_eq!(run(&mut a, 0.0, 50), first);
+    }
+
+    #[test]
+    fn ornstein_uhlenbeck_update() {
+        let (theta, sigma) = (2.0, 0.5);
+        let mut policy = NoisyPolicy::new((), 7, DT);
+        policy.set_noise(Some(Noise::OrnsteinUhlenbeck { theta, sigma }));
+        policy.reset();
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut state = 0.0f32;
+        for _ in 0..100 {
+            let z: f32 = StandardNormal.sample(&mut rng);
+            state += -theta * state * DT + sigma * DT.sqrt() * z;
+            assert!((policy.add_noise(0.0) - state.clamp(-1.0, 1.0)).abs() < 1e-6);
+        }
+
+        // Without diffusion the process decays geometrically
+        policy.set_noise(Some(Noise::OrnsteinUhlenbeck { theta, sigma: 0.0 }));
+        policy.state = 0.5;
+        let values = run(&mut policy, 0.0, 3);
+        for (k, value) in values.iter().enumerate() {
+            let expected = 0.5 * (1.0 - theta * DT).powi(k as i32 + 1);
+            assert!((value - expected).abs() < 1e-6, "{} {}", value, expected);
+        }
+    }
+
+    #[test]
+    fn actions_are_clamped() {
+        let mut policy = gaussian(10.0, 1);
+        let values = run(&mut policy, 0.9, 200);
+        assert!(values.iter().all(|value| (-1.0..=1.0).contains(value)));
+        assert!(values.contains(&1.0));
+        assert!(values.contains(&-1.0));
+    }
+
+    #[test]
+    fn schedules() {
+        assert_eq!(Schedule::Constant.scale(1000), 1.0);
+
+        let linear = Schedule::Linear {
+            final_scale: 0.2,
+            steps: 10,
+        };
+        for (step, scale) in [(0, 1.0), (5, 0.6), (10, 0.2), (20, 0.2)] {
+            assert!((linear.scale(step) - scale).abs() < 1e-6, "{}", step);
+        }
+        let immediate = Schedule::Linear {
+            final_scale: 0.2,
+            steps: 0,
+        };
+        assert!((immediate.scale(0) - 0.2).abs() < 1e-6);
+
+        let exponential = Schedule::Exponential {
+            decay: 0.5,
+            min_scale: 0.1,
+        };
+        for (step, scale) in [(0, 1.0), (1, 0.5), (2, 0.25), (10, 0.1), (usize::MAX, 0.1)] {
+            assert!((exponential.scale(step) - scale).abs() < 1e-6, "{}", step);
+        }
+    }
+
+    #[test]
+    fn schedule_scales_the_noise() {
+        let mut policy = gaussian(0.1, 3);
+        let unscaled = run(&mut policy, 0.0, 4);
+
+        let mut policy = gaussian(0.1, 3);
+        policy.set_schedule(Schedule::Linear {
+            final_scale: 0.0,
+            steps: 2,
+        });
+        let scaled = run(&mut policy, 0.0, 4);
+        assert!((scaled[0] - unscaled[0]).abs() < 1e-6);
+        assert!((scaled[1] - 0.5 * unscaled[1]).abs() < 1e-6);
+        assert_eq!(scaled[2..], [0.0, 0.0]);
+
+        // The schedule keeps progressing across episodes
+        policy.reset();
+        assert_eq!(policy.add_noise(0.0), 0.0);
+    }
+}
@@ -0,0 +1,216 @@
+//! Conversion of raw potentiometer readings into actions.
+
+/// Converts averaged raw ADC readings into actions in [-1, 1].
+///
+/// The conversion is applied in the following order:
+///
+/// 1. Normalization from [`min_limit`, `max_limit`] to [-1, 1]. `min_limit` may be larger than
+///    `max_limit` for a potentiometer wired in the opposite direction.
+/// 2. Deadband around the center, rescaled so that the output is continuous.
+/// 3. Expo curve `(1 - expo) * x + expo * x^3`, which reduces the sensitivity around the center.
+/// 4. Exponential moving average with coefficient `smoothing`, where 1 disables smoothing.
+#[derive(Debug, Clone)]
+pub struct PotentiometerInput {
+    min_limit: u16,
+    max_limit: u16,
+    deadband: f32,
+    expo: f32,
+    smoothing: f32,
+    filtered: Option<f32>,
+}
+
+impl Default for PotentiometerInput {
+    fn default() -> Self {
+        PotentiometerInput {
+            min_limit: 0,
+            max_limit: 2048,
+            deadband: 0.0,
+            expo: 0.0,
+            smoothing: 1.0,
+            filtered: None,
+        }
+    }
+}
+
+impl PotentiometerInput {
+    pub fn set_min_limit(&mut self, min_limit: u16) {
+        self.min_limit = min_limit;
+    }
+
+    pub fn set_max_limit(&mut self, max_limit: u16) {
+        self.max_limit = max_limit;
+    }
+
+    pub fn min_limit(&self) -> u16 {
+        self.min_limit
+    }
+
+    pub fn max_limit(&self) -> u16 {
+        self.max_limit
+    }
+
+    /// Set the half width of the deadband in the normalized range, clamped to [0, 0.99].
+    pub fn set_deadband(&mut self, deadband: f32) {
+        self.deadband = deadband.clamp(0.0, 0.99);
+    }
+
+    /// Set the weight of the cubic term of the expo curve, clamped to [0, 1].
+    pub fn set_expo(&mut self, expo: f32) {
+        self.expo = expo.clamp(0.0, 1.0);
+    }
+
+    /// Set the coefficient of the moving average, clamped to [0.01, 1].
+    pub fn set_smoothing(&mut self, smoothing: f32) {
+        self.smoothing = smoothing.clamp(0.01, 1.0);
+    }
+
+    /// Forget the state of the smoothing filter.
+    pub fn reset(&mut self) {
+        self.filtered = None;
+    }
+
+    /// Convert an averaged raw reading into an action.
+    pub fn process(&mut self, raw: f32) -> f32 {
+        let x = normalize(raw, self.min_limit, self.max_limit);
+        let x = apply_deadband(x, self.deadband);
+        let x = apply_expo(x, self.expo);
+
+        let y = match self.filtered {
+            Some(y) => y + self.smoothing * (x - y),
+            None => x,
+        };
+        self.filtered = Some(y);
+        y
+    }
+}
+
+/// Map `raw` from [`min_limit`, `max_limit`] to [-1, 1], saturating outside of the range.
+///
+/// The computation is done in `f32`, so an inverted range (`min_limit > max_limit`) is mapped
+/// in the opposite direction instead of underflowing. Returns 0 if the limits are equal.
+pub fn normalize(raw: f32, min_limit: u16, max_limit: u16) -> f32 {
+    let range = max_limit as f32 - min_limit as f32;
+    if range == 0.0 {
+        return 0.0;
+    }
+    let normalized = ((raw - min_limit as f32) / range).clamp(0.0, 1.0);
+    normalized * 2.0 - 1.0 // Scale to [-1, 1]
+}
+
+/// Zero `x` inside [-`deadband`, `deadband`] and rescale the rest to keep [-1, 1].
+pub fn apply_deadband(x: f32, deadband: f32) -> f32 {
+    if x.abs() <= deadband {
+        0.0
+    } else {
+        x.signum() * (x.abs() - deadband) / (1.0 - deadband)
+    }
+}
+
+/// Blend `x` with `x^3`, keeping -1, 0 and 1 fixed.
+pub fn apply_expo(x: f32, expo: f32) -> f32 {
+    (1.0 - expo) * x + expo * x * x * x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    #[test]
+    fn normalize_maps_and_saturates() {
+        assert!(close(normalize(1000.0, 1000, 3000), -1.0));
+        assert!(close(normalize(2000.0, 1000, 3000), 0.0));
+        assert!(close(normalize(3000.0, 1000, 3000), 1.0));
+        assert!(close(normalize(0.0, 1000, 3000), -1.0));
+        assert!(close(normalize(4095.0, 1000, 3000), 1.0));
+    }
+
+    #[test]
+    fn normalize_inverted_range() {
+        assert!(close(normalize(3000.0, 3000, 1000), -1.0));
+        assert!(close(normalize(2500.0, 3000, 1000), -0.5));
+        assert!(close(normalize(1000.0, 3000, 1000), 1.0));
+        assert!(close(normalize(0.0, 3000, 1000), 1.0));
+    }
+
+    #[test]
+    fn normalize_equal_limits() {
+        assert_eq!(normalize(0.0, 1500, 1500), 0.0);
+        assert_eq!(normalize(1500.0, 1500, 1500), 0.0);
+        assert_eq!(normalize(4095.0, 1500, 1500), 0.0);
+    }
+
+    #[test]
+    fn deadband_is_continuous() {
+        assert_eq!(apply_deadband(0.05, 0.1), 0.0);
+        assert_eq!(apply_deadband(-0.1, 0.1), 0.0);
+        assert!(close(apply_deadband(0.55, 0.1), 0.5));
+        assert!(close(apply_deadband(-0.55, 0.1), -0.5));
+        assert!(close(apply_deadband(1.0, 0.1), 1.0));
+        assert!(close(apply_deadband(-1.0, 0.1), -1.0));
+        // Just outside of the deadband the output starts from 0
+        assert!(apply_deadband(0.1001, 0.1) < 1e-3);
+    }
+
+    #[test]
+    fn expo_curve() {
+        for expo in [0.0, 0.3, 1.0] {
+            assert!(close(apply_expo(-1.0, expo), -1.0));
+            assert!(close(apply_expo(0.0, expo), 0.0));
+            assert!(close(apply_expo(1.0, expo), 1.0));
+        }
+        assert!(close(apply_expo(0.5, 0.0), 0.5));
+        assert!(close(apply_expo(0.5, 1.0), 0.125));
+        assert!(close(apply_expo(0.5, 0.3), 0.7 * 0.5 + 0.3 * 0.125));
+        assert!(close(apply_expo(-0.5, 0.3), -apply_expo(0.5, 0.3)));
+    }
+
+    #[test]
+    fn smoothing_moves_toward_input() {
+        let mut input = PotentiometerInput::default();
+        input.set_min_limit(0);
+        input.set_max_limit(2000);
+        input.set_smoothing(0.5);
+
+        // The first reading is taken as is
+        assert!(close(input.process(2000.0), 1.0));
+        assert!(close(input.process(1000.0), 0.5));
+        assert!(close(input.process(1000.0), 0.25));
+
+        input.reset();
+        assert!(close(input.process(0.0), -1.0));
+    }
+
+    #[test]
+    fn settings_are_clamped() {
+        let mut input = PotentiometerInput::default();
+        input.set_deadband(2.0);
+        input.set_expo(-1.0);
+        input.set_smoothing(0.0);
+        input.set_min_limit(0);
+        input.set_max_limit(2000);
+
+        // A deadband of 0.99 still lets the end of the range through
+        assert!(close(input.process(2000.0), 1.0));
+        // The smoothing coefficient is at least 0.01
+        assert!(close(input.process(1000.0), 0.99));
+    }
+
+    #[test]
+    fn pipeline_with_inverted_range() {
+        let mut input = PotentiometerInput::default();
+        input.set_min_limit(3000);
+        input.set_max_limit(1000);
+        input.set_deadband(0.1);
+        input.set_expo(1.0);
+
+        assert!(close(input.process(2000.0), 0.0));
+        assert!(close(input.process(1000.0), 1.0));
+        // normalize -> -0.55, deadband -> -0.5, expo -> -0.125
+        input.reset();
+        assert!(close(input.process(2550.0), -0.125));
+    }
+}
@@ -0,0 +1,161 @@
+//! Playback of a recorded action sequence.
+use anyhow::{Context, Result};
+
+/// A policy that plays back a recorded action sequence, ignoring observations.
+///
+/// It is used to apply exactly the same excitation on the real rig, for example to compare
+/// responses across firmware versions or hardware changes. After the last action, the policy
+/// keeps returning it.
+///
+/// The actions are replayed in time, so a sequence recorded at another control rate is
+/// resampled to the step period given by `set_dt`.
+pub struct ReplayPolicy {
+    actions: Vec<f32>,
+    /// Step period the actions were recorded at.
+    recorded_dt: f32,
+    /// Step period of the playback.
+    dt: f32,
+    step: usize,
+}
+
+impl ReplayPolicy {
+    /// Replay `actions` recorded every `dt` seconds.
+    pub fn new(actions: Vec<f32>, dt: f32) -> Self {
+        ReplayPolicy {
+            actions,
+            recorded_dt: dt,
+            dt,
+            step: 0,
+        }
+    }
+
+    /// Parse an action sequence with one action per line.
+    ///
+    /// Empty lines and lines starting with `#` are skipped. If a line has several
+    /// comma-separated fields, as in the `DEMO` lines printed by the device, the last field is
+    /// taken as the action. The actions are taken to be recorded every `dt` seconds.
+    pub fn from_text(text: &str, dt: f32) -> Result<Self> {
+        let mut actions = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let field = line.rsplit(',').next().unwrap_or(line).trim();
+            let action: f32 = field
+                .parse()
+                .with_context(|| format!("Invalid action at line {}: {}", i + 1, line))?;
+            actions.push(action.clamp(-1.0, 1.0));
+        }
+        Ok(Self::new(actions, dt))
+    }
+
+    /// Change the step period of the playback.
+    pub fn set_dt(&mut self, dt: f32) {
+        self.dt = dt;
+    }
+
+    /// Number of actions in the sequence.
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    /// Number of steps to play back the whole sequence.
+    pub fn num_steps(&self) -> usize {
+        if self.dt <= 0.0 {
+            return self.actions.len();
+        }
+        (self.actions.len() as f64 * self.recorded_dt as f64 / self.dt as f64 - 1e-6).ceil()
+            as usize
+    }
+
+    /// Restart the playback from the first action.
+    pub fn reset(&mut self) {
+        self.step = 0;
+    }
+
+    /// Action for the current step, advancing to the next one.
+    pub fn next_action(&mut self) -> f32 {
+        let action = match self.actions.get(self.index()) {
+            Some(action) => *action,
+            None => self.actions.last().copied().unwrap_or(0.0),
+        };
+        self.step = self.step.saturating_add(1);
+        action
+    }
+
+    /// Index of the action recorded at the time of the current step.
+    fn index(&self) -> usize {
+        if self.recorded_dt <= 0.0 {
+            return self.step;
+        }
+        // The margin keeps the index exact when the step periods are equal
+        (self.step as f64 * self.dt as f64 / self.recorded_dt as f64 + 1e-6) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(policy: &mut ReplayPolicy, steps: usize) -> Vec<f32> {
+        (0..steps).map(|_| policy.next_action()).collect()
+    }
+
+    #[test]
+    fn replay_at_the_recorded_rate() {
+        let mut policy = ReplayPolicy::new(vec![0.1, 0.2, 0.3], 0.02);
+        assert_eq!(policy.num_steps(), 3);
+        assert_eq!(play(&mut policy, 5), [0.1, 0.2, 0.3, 0.3, 0.3]);
+        policy.reset();
+        assert_eq!(play(&mut policy, 1), [0.1]);
+    }
+
+    #[test]
+    fn replay_at_another_rate() {
+        let actions: Vec<f32> = (0..100).map(|i| i as f32 / 100.0).collect();
+
+        // Twice the control rate holds each action for two steps
+        let mut policy = ReplayPolicy::new(actions.clone(), 0.02);
+        policy.set_dt(0.01);
+        assert_eq!(policy.num_steps(), 200);
+        let played = play(&mut policy, 200);
+        assert_eq!(played[..4], [0.0, 0.0, 0.01, 0.01]);
+        assert_eq!(played[199], 0.99);
+
+        // Half the control rate skips every other action
+        let mut policy = ReplayPolicy::new(actions.clone(), 0.02);
+        policy.set_dt(0.04);
+        assert_eq!(policy.num_steps(), 50);
+        let played = play(&mut policy, 50);
+        assert_eq!(played[..3], [0.0, 0.02, 0.04]);
+        assert_eq!(played[49], 0.98);
+
+        // Rates that do not divide each other
+        let mut policy = ReplayPolicy::new(actions, 0.02);
+        policy.set_dt(0.03);
+        assert_eq!(policy.num_steps(), 67);
+        assert_eq!(play(&mut policy, 4), [0.0, 0.01, 0.03, 0.04]);
+    }
+
+    #[test]
+    fn parse_text() {
+        let text = "# recorded\n\n0.5\nDEMO,1,0.1,-0.25\n2.0\n";
+        let mut policy = ReplayPolicy::from_text(text, 0.02).unwrap();
+        assert_eq!(policy.len(), 3);
+        assert_eq!(play(&mut policy, 3), [0.5, -0.25, 1.0]);
+        assert!(ReplayPolicy::from_text("0.5\nx\n", 0.02).is_err());
+    }
+
+    #[test]
+    fn empty_sequence() {
+        let mut policy = ReplayPolicy::new(vec![], 0.02);
+        assert!(policy.is_empty());
+        assert_eq!(policy.num_steps(), 0);
+        assert_eq!(play(&mut policy, 2), [0.0, 0.0]);
+    }
+}
@@ -0,0 +1,329 @@
+use std::f32::consts::PI;
+
+/// Waveform generated by [`SignalPolicy`].
+///
+/// Frequencies are given in Hz and durations in seconds. Every waveform takes values in
+/// [-1, 1] before amplitude and offset are applied.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Signal {
+    /// Sine wave.
+    Sine { frequency: f32 },
+
+    /// Linear frequency sweep from `f0` to `f1` over `duration`, repeated afterwards.
+    Chirp { f0: f32, f1: f32, duration: f32 },
+
+    /// Square wave, where `duty` is the fraction of the period spent at +1.
+    Square { frequency: f32, duty: f32 },
+
+    /// -1 until `delay`, then +1.
+    Step { delay: f32 },
+
+    /// Pseudo-random binary sequence from a 16-bit LFSR, switching every `bit_period`.
+    Prbs { bit_period: f32, seed: u16 },
+
+    /// Sum of sines with Schroeder phases, normalized by the number of components.
+    Multisine { frequencies: Vec<f32> },
+}
+
+/// A policy that outputs a predefined excitation signal, ignoring observations.
+///
+/// It is intended for system identification runs. The action is
+/// `offset + amplitude * signal(t)` clamped to [-1, 1], where `t` is the number of calls to
+/// `next_action()` times `dt` seconds. `dt` should be the step period of the control loop.
+pub struct SignalPolicy {
+    signal: Signal,
+    amplitude: f32,
+    offset: f32,
+    dt: f32,
+    step: u32,
+    lfsr: u16,
+    prbs_level: f32,
+    prbs_next: f32,
+}
+
+impl SignalPolicy {
+    pub fn new(signal: Signal, amplitude: f32, offset: f32, dt: f32) -> Self {
+        let mut policy = SignalPolicy {
+            signal,
+            amplitude,
+            offset,
+            dt,
+            step: 0,
+            lfsr: 0,
+            prbs_level: 0.0,
+            prbs_next: 0.0,
+        };
+        policy.reset();
+        policy
+    }
+
+    /// Create a sine policy with unit amplitude and zero offset.
+    pub fn sine(frequency: f32, dt: f32) -> Self {
+        Self::new(Signal::Sine { frequency }, 1.0, 0.0, dt)
+    }
+
+    /// Replace the waveform and restart it.
+    pub fn set_signal(&mut self, signal: Signal) {
+        self.signal = signal;
+        self.reset();
+    }
+
+    /// Change the step period, e.g. when the control rate changes. Call `reset` afterwards.
+    pub fn set_dt(&mut self, dt: f32) {
+        self.dt = dt;
+    }
+
+    /// Restart the signal from `t = 0`.
+    ///
+    /// Call this before each episode so that every run applies the same excitation.
+    pub fn reset(&mut self) {
+        self.step = 0;
+        self.prbs_next = 0.0;
+        self.lfsr = match self.signal {
+            // The LFSR must never be all zeros
+            Signal::Prbs { seed, .. } if seed != 0 => seed,
+            _ => 0xACE1,
+        };
+    }
+
+    /// Value of the normalized waveform at the current time.
+    fn waveform(&mut self) -> f32 {
+        // Counting steps instead of accumulating `dt` keeps the time free of rounding drift
+        let t = self.step as f32 * self.dt;
+        match &self.signal {
+            Signal::Sine { frequency } => (2.0 * PI * frequency * t).sin(),
+            Signal::Chirp { f0, f1, duration } => {
+                let t = if *duration > 0.0 { t % duration } else { t };
+                let rate = if *duration > 0.0 {
+                    (f1 - f0) / duration
+                } else {
+                    0.0
+                };
+                // Phase is the integral of the instantaneous frequency f0 + rate * t
+                (2.0 * PI * (f0 * t + 0.5 * rate * t * t)).sin()
+            }
+            Signal::Square { frequency, duty } => {
+                let phase = (frequency * t).fract();
+                if phase < *duty {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Signal::Step { delay } => {
+                if t < *delay {
+                    -1.0
+                } else {
+                    1.0
+                }
+            }
+            Signal::Prbs { bit_period, .. } => {
+                let bit_period = *bit_period;
+                // Switch on the step nearest to the bit boundary
+                while t + 0.5 * self.dt >= self.prbs_next {
+                    self.prbs_level = if self.next_prbs_bit() { 1.0 } else { -1.0 };
+                    self.prbs_next += bit_period.max(self.dt);
+                }
+                self.prbs_level
+            }
+            Signal::Multisine { frequencies } => {
+                let n = frequencies.len();
+                if n == 0 {
+                    return 0.0;
+                }
+                let sum: f32 = frequencies
+                    .iter()
+                    .enumerate()
+                    .map(|(k, f)| {
+                        let k = (k + 1) as f32;
+                        let phase = -PI * k * (k - 1.0) / n as f32;
+                        (2.0 * PI * f * t + phase).sin()
+                    })
+                    .sum();
+                sum / n as f32
+            }
+        }
+    }
+
+    /// Next action of the excitation, advancing the time by one step.
+    pub fn next_action(&mut self) -> f32 {
+        let value = self.offset + self.amplitude * self.waveform();
+        self.step = self.step.wrapping_add(1);
+        value.clamp(-1.0, 1.0)
+    }
+
+    /// Advance the Galois LFSR (x^16 + x^14 + x^13 + x^11 + 1) and return the output bit.
+    fn next_prbs_bit(&mut self) -> bool {
+        let bit = self.lfsr & 1 == 1;
+        self.lfsr >>= 1;
+        if bit {
+            self.lfsr ^= 0xB400;
+        }
+        bit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.02;
+
+    fn run(policy: &mut SignalPolicy, steps: usize) -> Vec<f32> {
+        (0..steps).map(|_| policy.next_action()).collect()
+    }
+
+    fn zero_crossings(values: &[f32]) -> usize {
+        values
+            .windows(2)
+            .filter(|w| (w[0] < 0.0) != (w[1] < 0.0))
+            .count()
+    }
+
+    #[test]
+    fn sine_period_and_amplitude() {
+        let mut policy = SignalPolicy::new(Signal::Sine { frequency: 2.5 }, 0.5, 0.1, DT);
+        let values = run(&mut policy, 100);
+
+        // The period of 0.4 s is 20 steps, with the peaks at steps 5 and 15
+        assert!((values[0] - 0.1).abs() < 1e-6);
+        for k in 0..80 {
+            assert!((values[k + 20] - values[k]).abs() < 1e-4);
+        }
+        assert!((values[5] - 0.6).abs() < 1e-4);
+        assert!((values[15] + 0.4).abs() < 1e-4);
+        let max = values.iter().cloned().fold(f32::MIN, f32::max);
+        let min = values.iter().cloned().fold(f32::MAX, f32::min);
+        assert!((max - 0.6).abs() < 1e-4);
+        assert!((min + 0.4).abs() < 1e-4);
+    }
+
+    #[test]
+    fn time_comes_from_step_period() {
+        let signal = Signal::Sine { frequency: 0.7 };
+        let mut fine = SignalPolicy::new(signal.clone(), 1.0, 0.0, DT / 2.0);
+        let mut coarse = SignalPolicy::new(signal, 1.0, 0.0, DT);
+        let fine = run(&mut fine, 200);
+        let coarse = run(&mut coarse, 100);
+        for (k, value) in coarse.iter().enumerate() {
+            assert!((fine[2 * k] - value).abs() < 1e-4, "step {}", k);
+        }
+    }
+
+    #[test]
+    fn chirp_sweeps_and_repeats() {
+        let signal = Signal::Chirp {
+            f0: 1.0,
+            f1: 5.0,
+            duration: 2.0,
+        };
+        let mut policy = SignalPolicy::new(signal, 1.0, 0.0, 0.001);
+        let values = run(&mut policy, 4000);
+        let (first, second) = values.split_at(2000);
+
+        // The phase advances by f0 * T + (f1 - f0) * T / 2 = 6 cycles over the sweep
+        assert!((11..=13).contains(&zero_crossings(first)));
+        // The frequency increases, so the second half has more crossings than the first
+        let crossings_early = zero_crossings(&first[..1000]);
+        let crossings_late = zero_crossings(&first[1000..]);
+        assert!(crossings_late > crossings_early);
+        // The sweep restarts after the duration
+        for (a, b) in first.iter().zip(second).take(1000) {
+            assert!((a - b).abs() < 1e-2);
+        }
+        assert!(values.iter().all(|v| v.abs() <= 1.0));
+    }
+
+    #[test]
+    fn square_duty_and_amplitude() {
+        let signal = Signal::Square {
+            frequency: 1.0,
+            duty: 0.25,
+        };
+        let mut policy = SignalPolicy::new(signal, 0.8, 0.0, 0.01);
+        let values = run(&mut policy, 200);
+        assert!(values.iter().all(|v| *v == 0.8 || *v == -0.8));
+        let high = values[..100].iter().filter(|v| **v > 0.0).count();
+        assert_eq!(high, 25);
+        assert_eq!(&values[..100], &values[100..]);
+    }
+
+    #[test]
+    fn step_switches_after_delay() {
+        let mut policy = SignalPolicy::new(Signal::Step { delay: 0.09 }, 1.0, 0.0, DT);
+        let values = run(&mut policy, 10);
+        assert_eq!(&values[..5], &[-1.0; 5]);
+        assert_eq!(&values[5..], &[1.0; 5]);
+    }
+
+    #[test]
+    fn prbs_holds_bits_and_is_reproducible() {
+        let signal = Signal::Prbs {
+            bit_period: 0.1,
+            seed: 0x1234,
+        };
+        let mut policy = SignalPolicy::new(signal, 0.5, 0.0, DT);
+        let values = run(&mut policy, 500);
+        assert!(values.iter().all(|v| *v == 0.5 || *v == -0.5));
+        assert!(values.contains(&0.5) && values.contains(&-0.5));
+        // Each bit is held for 5 steps
+        for bit in values.chunks(5) {
+            assert!(bit.iter().all(|v| *v == bit[0]));
+        }
+
+        policy.reset();
+        assert_eq!(run(&mut policy, 500), values);
+    }
+
+    #[test]
+    fn prbs_has_maximal_length() {
+        let signal = Signal::Prbs {
+            bit_period: DT,
+            seed: 1,
+        };
+        let mut policy = SignalPolicy::new(signal, 1.0, 0.0, DT);
+        for n in 1..=65535 {
+            policy.next_prbs_bit();
+            if policy.lfsr == 1 {
+                assert_eq!(n, 65535);
+                return;
+            }
+        }
+        panic!("The LFSR did not return to the seed");
+    }
+
+    #[test]
+    fn prbs_zero_seed_is_replaced() {
+        let signal = Signal::Prbs {
+            bit_period: DT,
+            seed: 0,
+        };
+        let mut policy = SignalPolicy::new(signal, 1.0, 0.0, DT);
+        assert!(run(&mut policy, 100).contains(&1.0));
+    }
+
+    #[test]
+    fn multisine_is_normalized() {
+        let signal = Signal::Multisine {
+            frequencies: vec![0.5, 1.0, 1.5, 2.0],
+        };
+        let mut policy = SignalPolicy::new(signal, 1.0, 0.0, 0.005);
+        let values = run(&mut policy, 800);
+        assert!(values.iter().all(|v| v.abs() <= 1.0));
+        assert!(values.iter().any(|v| v.abs() > 0.3));
+        // The fundamental is 0.5 Hz, so the sum repeats every 2 s
+        for (a, b) in values[..400].iter().zip(&values[400..]) {
+            assert!((a - b).abs() < 1e-3);
+        }
+
+        let mut policy = SignalPolicy::new(
+            Signal::Multisine {
+                frequencies: vec![],
+            },
+            1.0,
+            0.0,
+            DT,
+        );
+        assert_eq!(run(&mut policy, 3), vec![0.0; 3]);
+    }
+}
@@ -0,0 +1,340 @@
+//! Application state and its transitions.
+//!
+//! The current state is kept in a global atomic so that it can be read by the control loop and
+//! changed by the button task. All changes go through [`transition`], which
+//! defines every valid transition explicitly.
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// State of the application.
+///
+/// The discriminants are the values stored in the global atomic.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppState {
+    Idle = 0,
+
+    /// Send the buffered episodes to the server (long press on button 2).
+    SendEpisodes = 2,
+
+    /// Receive model parameters from the server (long press on button 3).
+    ReceiveParameters = 3,
+
+    /// Clear the buffered episodes (double press on button 4).
+    ///
+    /// Only accepted in the idle state, so that a running episode is never cleared.
+    ClearEpisodes = 4,
+
+    /// Remove the saved calibration (long press on button 1).
+    ClearCalibration = 5,
+
+    /// Wait for the pendulum to be rotated counter-clockwise to set the offset and direction.
+    OffsetCorrection = 10,
+    OffsetCorrectionEnd = 11,
+    OffsetCorrectionCancel = 12,
+
+    /// Take the potentiometer values for the minimum and maximum actions.
+    PotentiometerMin = 13,
+    PotentiometerMax = 14,
+    PotentiometerCancel = 15,
+
+    /// Run an episode. `*Start` states are requested while another episode is running, and
+    /// change to the running state once the running episode has finished.
+    AutoPolicy = 21,
+    ManualPolicyStart = 22,
+    ManualPolicy = 23,
+    BlendedPolicyStart = 24,
+    BlendedPolicy = 25,
+    ReplayPolicyStart = 26,
+    ReplayPolicy = 27,
+
+    /// Terminate the program.
+    Terminate = 255,
+}
+
+impl AppState {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        use AppState::*;
+        let state = match value {
+            0 => Idle,
+            2 => SendEpisodes,
+            3 => ReceiveParameters,
+            4 => ClearEpisodes,
+            5 => ClearCalibration,
+            10 => OffsetCorrection,
+            11 => OffsetCorrectionEnd,
+            12 => OffsetCorrectionCancel,
+            13 => PotentiometerMin,
+            14 => PotentiometerMax,
+            15 => PotentiometerCancel,
+            21 => AutoPolicy,
+            22 => ManualPolicyStart,
+            23 => ManualPolicy,
+            24 => BlendedPolicyStart,
+            25 => BlendedPolicy,
+            26 => ReplayPolicyStart,
+            27 => ReplayPolicy,
+            255 => Terminate,
+            _ => return None,
+        };
+        Some(state)
+    }
+}
+
+/// Events that change the state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// Cancel the current operation, or terminate the program in the idle state (button 1).
+    Cancel,
+
+    /// Start the calibration, or confirm the current calibration step (button 2).
+    Confirm,
+
+    /// Run an episode, or switch to the next policy while an episode is running (button 3).
+    Run,
+
+    /// Clear the buffered episodes (double press on button 4).
+    ClearEpisodes,
+
+    /// Send the buffered episodes to the server (long press on button 2).
+    SendEpisodes,
+
+    /// Receive model parameters from the server (long press on button 3).
+    ReceiveParameters,
+
+    /// Remove the saved calibration (long press on button 1).
+    ClearCalibration,
+
+    /// A requested episode has started after the previous one finished.
+    Started,
+
+    /// The main loop has finished the work of the current state.
+    Done,
+
+    /// The current state took too long, e.g. the operator left during calibration.
+    Timeout,
+}
+
+/// The transition table. Returns `None` if `event` is not valid in `state`.
+pub fn transition(state: AppState, event: Event) -> Option<AppState> {
+    use AppState::*;
+    let next = match (state, event) {
+        // Idle
+        (Idle, Event::Cancel) => Terminate,
+        (Idle, Event::Confirm) => OffsetCorrection,
+        (Idle, Event::Run) => AutoPolicy,
+        (Idle, Event::SendEpisodes) => AppState::SendEpisodes,
+        (Idle, Event::ReceiveParameters) => AppState::ReceiveParameters,
+        (Idle, Event::ClearEpisodes) => AppState::ClearEpisodes,
+        (Idle, Event::ClearCalibration) => AppState::ClearCalibration,
+
+        // Communication and clearing
+        (AppState::SendEpisodes, Event::Done) => Idle,
+        (AppState::ReceiveParameters, Event::Done) => Idle,
+        (AppState::ClearEpisodes, Event::Done) => Idle,
+        (AppState::ClearCalibration, Event::Done) => Idle,
+
+        // Offset correction
+        (OffsetCorrection, Event::Confirm) => OffsetCorrectionEnd,
+        (OffsetCorrection, Event::Cancel | Event::Timeout) => OffsetCorrectionCancel,
+        (OffsetCorrectionEnd, Event::Done) => PotentiometerMin,
+        (OffsetCorrectionCancel, Event::Done) => Idle,
+
+        // Potentiometer limits
+        (PotentiometerMin, Event::Confirm) => PotentiometerMax,
+        (PotentiometerMax, Event::Confirm) => Idle,
+        (PotentiometerMin | PotentiometerMax, Event::Cancel | Event::Timeout) => {
+            PotentiometerCancel
+        }
+        (PotentiometerCancel, Event::Done) => Idle,
+
+        // Episodes
+        (AutoPolicy, Event::Run) => ManualPolicyStart,
+        (ManualPolicy, Event::Run) => BlendedPolicyStart,
+        (BlendedPolicy, Event::Run) => ReplayPolicyStart,
+        (ManualPolicyStart, Event::Started) => ManualPolicy,
+        (BlendedPolicyStart, Event::Started) => BlendedPolicy,
+        (ReplayPolicyStart, Event::Started) => ReplayPolicy,
+        (ManualPolicyStart | BlendedPolicyStart | ReplayPolicyStart, Event::Cancel) => Idle,
+        (AutoPolicy | ManualPolicy | BlendedPolicy | ReplayPolicy, Event::Cancel | Event::Done) => {
+            Idle
+        }
+
+        _ => return None,
+    };
+    Some(next)
+}
+
+static STATE: AtomicU8 = AtomicU8::new(AppState::Idle as u8);
+
+/// Get the current state.
+pub fn current() -> AppState {
+    AppState::from_u8(STATE.load(Ordering::Acquire)).unwrap_or(AppState::Idle)
+}
+
+/// Apply `event` to the current state atomically.
+///
+/// Returns the previous and the new state, or the current state if the event is invalid.
+fn apply(event: Event) -> Result<(AppState, AppState), AppState> {
+    let mut value = STATE.load(Ordering::Acquire);
+    loop {
+        let state = AppState::from_u8(value).unwrap_or(AppState::Idle);
+        let next = transition(state, event).ok_or(state)?;
+        match STATE.compare_exchange_weak(value, next as u8, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => return Ok((state, next)),
+            Err(actual) => value = actual,
+        }
+    }
+}
+
+/// Apply `event` to the current state and log the result.
+///
+/// Returns the new state, or `None` if the event is invalid in the current state.
+pub fn dispatch(event: Event) -> Option<AppState> {
+    match apply(event) {
+        Ok((state, next)) => {
+            log::info!("State {:?} -> {:?} on {:?}", state, next, event);
+            Some(next)
+        }
+        Err(state) => {
+            log::warn!("Invalid event {:?} in state {:?}, ignored", event, state);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATES: [AppState; 19] = [
+        AppState::Idle,
+        AppState::SendEpisodes,
+        AppState::ReceiveParameters,
+        AppState::ClearEpisodes,
+        AppState::ClearCalibration,
+        AppState::OffsetCorrection,
+        AppState::OffsetCorrectionEnd,
+        AppState::OffsetCorrectionCancel,
+        AppState::PotentiometerMin,
+        AppState::PotentiometerMax,
+        AppState::PotentiometerCancel,
+        AppState::AutoPolicy,
+        AppState::ManualPolicyStart,
+        AppState::ManualPolicy,
+        AppState::BlendedPolicyStart,
+        AppState::BlendedPolicy,
+        AppState::ReplayPolicyStart,
+        AppState::ReplayPolicy,
+        AppState::Terminate,
+    ];
+
+    const EVENTS: [Event; 10] = [
+        Event::Cancel,
+        Event::Confirm,
+        Event::Run,
+        Event::ClearEpisodes,
+        Event::SendEpisodes,
+        Event::ReceiveParameters,
+        Event::ClearCalibration,
+        Event::Started,
+        Event::Done,
+        Event::Timeout,
+    ];
+
+    /// Apply `events` from `state`, panicking on an invalid event.
+    fn run(state: AppState, events: &[Event]) -> AppState {
+        events.iter().fold(state, |state, event| {
+            transition(state, *event)
+                .unwrap_or_else(|| panic!("{:?} is invalid in {:?}", event, state))
+        })
+    }
+
+    #[test]
+    fn from_u8_round_trip() {
+        for state in STATES {
+            assert_eq!(AppState::from_u8(state as u8), Some(state));
+        }
+        let valid = STATES.map(|state| state as u8);
+        for value in 0..=u8::MAX {
+            if !valid.contains(&value) {
+                assert_eq!(AppState::from_u8(value), None, "{}", value);
+            }
+        }
+    }
+
+    #[test]
+    fn idle_commands() {
+        use AppState::*;
+        assert_eq!(run(Idle, &[Event::Run]), AutoPolicy);
+        assert_eq!(run(Idle, &[Event::Cancel]), Terminate);
+        assert_eq!(run(Idle, &[Event::Confirm]), OffsetCorrection);
+        for (event, state) in [
+            (Event::SendEpisodes, SendEpisodes),
+            (Event::ReceiveParameters, ReceiveParameters),
+            (Event::ClearEpisodes, ClearEpisodes),
+            (Event::ClearCalibration, ClearCalibration),
+        ] {
+            assert_eq!(run(Idle, &[event]), state);
+            assert_eq!(run(Idle, &[event, Event::Done]), Idle);
+        }
+        assert_eq!(transition(Idle, Event::Done), None);
+        assert_eq!(transition(Idle, Event::Started), None);
+        assert_eq!(transition(Idle, Event::Timeout), None);
+    }
+
+    #[test]
+    fn calibration_sequence() {
+        use AppState::*;
+        let state = run(OffsetCorrection, &[Event::Confirm, Event::Done]);
+        assert_eq!(state, PotentiometerMin);
+        assert_eq!(run(state, &[Event::Confirm, Event::Confirm]), Idle);
+
+        for event in [Event::Cancel, Event::Timeout] {
+            assert_eq!(run(OffsetCorrection, &[event, Event::Done]), Idle);
+            assert_eq!(run(PotentiometerMin, &[event, Event::Done]), Idle);
+            assert_eq!(run(PotentiometerMax, &[event, Event::Done]), Idle);
+        }
+    }
+
+    #[test]
+    fn episodes_cycle_through_policies() {
+        use AppState::*;
+        let state = run(Idle, &[Event::Run, Event::Run]);
+        assert_eq!(state, ManualPolicyStart);
+        let state = run(state, &[Event::Started, Event::Run]);
+        assert_eq!(state, BlendedPolicyStart);
+        let state = run(state, &[Event::Started, Event::Run]);
+        assert_eq!(state, ReplayPolicyStart);
+        assert_eq!(run(state, &[Event::Started]), ReplayPolicy);
+        assert_eq!(transition(ReplayPolicy, Event::Run), None);
+
+        for state in [AutoPolicy, ManualPolicy, BlendedPolicy, ReplayPolicy] {
+            assert_eq!(run(state, &[Event::Cancel]), Idle);
+            assert_eq!(run(state, &[Event::Done]), Idle);
+        }
+        for state in [ManualPolicyStart, BlendedPolicyStart, ReplayPolicyStart] {
+            assert_eq!(run(state, &[Event::Cancel]), Idle);
+        }
+    }
+
+    #[test]
+    fn commands_are_only_accepted_when_idle() {
+        for state in STATES.into_iter().filter(|state| *state != AppState::Idle) {
+            for event in [
+                Event::SendEpisodes,
+                Event::ReceiveParameters,
+                Event::ClearEpisodes,
+                Event::ClearCalibration,
+            ] {
+                assert_eq!(transition(state, event), None, "{:?} {:?}", state, event);
+            }
+        }
+    }
+
+    #[test]
+    fn terminate_is_final() {
+        for event in EVENTS {
+            assert_eq!(transition(AppState::Terminate, event), None);
+        }
+    }
+}
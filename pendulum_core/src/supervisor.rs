@@ -0,0 +1,314 @@
+//! Checks of the pendulum and the actions during an episode.
+//!
+//! Driving the servo to the safe state is done by `safe_state` of `pendulum1`.
+use std::f32::consts::PI;
+use std::sync::Mutex;
+
+/// Why the supervisor stopped an episode.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trip {
+    /// The rotation from the hanging position was outside the limit, or the angle was not a
+    /// number.
+    AngleLimit { angle: f32 },
+
+    /// The policy returned an action that is not a number.
+    InvalidAction,
+
+    /// The angle did not follow a change of the action for `steps` steps.
+    Stalled { steps: usize },
+
+    /// The control loop did not step within the watchdog timeout.
+    Watchdog,
+
+    /// The program panicked.
+    Panic(String),
+
+    /// An error was returned during an episode.
+    Error(String),
+}
+
+/// Limits enforced by [`Supervisor`].
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Maximum absolute rotation from the hanging position in radians.
+    ///
+    /// The observed angle wraps around at +-PI, so the rotation is tracked across steps and
+    /// full turns count. The default allows the upright position on either side, but stops a
+    /// pendulum that keeps spinning.
+    pub max_angle: f32,
+
+    /// Actions are clamped to [-`max_action`, `max_action`].
+    pub max_action: f32,
+
+    /// Time in seconds after which a change of the action that the angle has not followed is
+    /// considered a stall.
+    pub stall_time: f32,
+
+    /// Change of the angle in radians below which the pendulum is considered not moving.
+    pub stall_threshold: f32,
+
+    /// Change of the action since the pendulum last moved above which it is expected to move.
+    ///
+    /// The action is a servo position, so holding any action does not move the pendulum.
+    pub stall_min_change: f32,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_angle: 3.0 * PI,
+            max_action: 1.0,
+            stall_time: 2.0,
+            stall_threshold: 0.005,
+            stall_min_change: 0.3,
+        }
+    }
+}
+
+/// Wrap an angle difference into [-PI, PI].
+fn wrap(angle: f32) -> f32 {
+    (angle + PI).rem_euclid(2.0 * PI) - PI
+}
+
+/// Number of steps of `dt` seconds in `time`, at least 1.
+fn stall_steps(time: f32, dt: f32) -> usize {
+    if dt > 0.0 {
+        ((time / dt).round() as usize).max(1)
+    } else {
+        1
+    }
+}
+
+/// Enforces [`Limits`] on each step of an episode.
+pub struct Supervisor {
+    limits: Limits,
+    /// `stall_time` in steps of the control loop.
+    stall_steps: usize,
+    /// Observed angle and rotation on the last step.
+    last: Option<(f32, f32)>,
+    /// Rotation and action when the pendulum last moved.
+    moved: Option<(f32, f32)>,
+    still_steps: usize,
+}
+
+impl Supervisor {
+    /// Create a supervisor for a control loop stepping every `dt` seconds.
+    pub fn new(limits: Limits, dt: f32) -> Self {
+        Supervisor {
+            limits,
+            stall_steps: stall_steps(limits.stall_time, dt),
+            last: None,
+            moved: None,
+            still_steps: 0,
+        }
+    }
+
+    /// Change the step period of the control loop.
+    pub fn set_step_period(&mut self, dt: f32) {
+        self.stall_steps = stall_steps(self.limits.stall_time, dt);
+    }
+
+    /// Call this before each episode.
+    pub fn reset(&mut self) {
+        self.last = None;
+        self.moved = None;
+        self.still_steps = 0;
+    }
+
+    /// Check the observed angle and the action for it.
+    ///
+    /// Returns the action clamped to the limit, or the reason to stop the episode.
+    pub fn check(&mut self, angle: f32, action: f32) -> Result<f32, Trip> {
+        if !angle.is_finite() {
+            return Err(Trip::AngleLimit { angle });
+        }
+        let rotation = match self.last {
+            Some((last_angle, last_rotation)) => last_rotation + wrap(angle - last_angle),
+            None => angle,
+        };
+        self.last = Some((angle, rotation));
+        if rotation.abs() > self.limits.max_angle {
+            return Err(Trip::AngleLimit { angle: rotation });
+        }
+        if !action.is_finite() {
+            return Err(Trip::InvalidAction);
+        }
+        let action = action.clamp(-self.limits.max_action, self.limits.max_action);
+
+        // The action has changed since the pendulum last moved, but the pendulum does not move
+        let (moved_rotation, moved_action) = *self.moved.get_or_insert((rotation, action));
+        if (rotation - moved_rotation).abs() >= self.limits.stall_threshold {
+            self.moved = Some((rotation, action));
+            self.still_steps = 0;
+        } else if (action - moved_action).abs() >= self.limits.stall_min_change {
+            self.still_steps += 1;
+            if self.still_steps >= self.stall_steps {
+                return Err(Trip::Stalled {
+                    steps: self.still_steps,
+                });
+            }
+        } else {
+            self.still_steps = 0;
+        }
+
+        Ok(action)
+    }
+}
+
+static LAST_TRIP: Mutex<Option<Trip>> = Mutex::new(None);
+
+/// Record why the supervisor tripped.
+///
+/// This never blocks, so that it can be called from the panic hook. The trip is not recorded
+/// if the last one is being read or written at the same time.
+pub fn record_trip(trip: Trip) {
+    if let Ok(mut last) = LAST_TRIP.try_lock() {
+        *last = Some(trip);
+    }
+}
+
+/// The reason of the last trip since boot.
+pub fn last_trip() -> Option<Trip> {
+    LAST_TRIP.lock().ok().and_then(|last| last.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn supervisor() -> Supervisor {
+        // Stalls after 10 steps
+        Supervisor::new(
+            Limits {
+                stall_time: 0.2,
+                ..Limits::default()
+            },
+            0.02,
+        )
+    }
+
+    #[test]
+    fn held_action_at_rest_does_not_trip() {
+        let mut supervisor = supervisor();
+        for action in [0.0, 0.5, 1.0, -1.0] {
+            supervisor.reset();
+            for _ in 0..1000 {
+                assert_eq!(supervisor.check(0.0, action), Ok(action));
+            }
+        }
+    }
+
+    #[test]
+    fn followed_change_does_not_trip() {
+        let mut supervisor = supervisor();
+        for _ in 0..20 {
+            supervisor.check(0.0, 0.0).unwrap();
+        }
+        // The servo moves and the pendulum swings with it, then settles
+        for t in 0..20 {
+            supervisor.check(0.01 * t as f32, 0.8).unwrap();
+        }
+        for _ in 0..1000 {
+            supervisor.check(0.2, 0.8).unwrap();
+        }
+    }
+
+    #[test]
+    fn unfollowed_change_trips() {
+        let mut supervisor = supervisor();
+        for _ in 0..20 {
+            supervisor.check(0.0, 0.0).unwrap();
+        }
+        for _ in 0..9 {
+            supervisor.check(0.001, 0.8).unwrap();
+        }
+        assert_eq!(
+            supervisor.check(0.001, 0.8),
+            Err(Trip::Stalled { steps: 10 })
+        );
+
+        // Going back to the action of the last movement clears the count
+        supervisor.reset();
+        supervisor.check(0.0, 0.0).unwrap();
+        for _ in 0..9 {
+            supervisor.check(0.0, 0.8).unwrap();
+        }
+        supervisor.check(0.0, 0.1).unwrap();
+        for _ in 0..9 {
+            supervisor.check(0.0, 0.8).unwrap();
+        }
+    }
+
+    #[test]
+    fn upright_is_allowed_on_both_sides() {
+        let mut supervisor = supervisor();
+        // Swing up through the wrap-around at +-PI and balance there
+        let mut angle: f32 = 0.0;
+        for _ in 0..40 {
+            angle += 0.1;
+            supervisor.check(wrap(angle), 0.5).unwrap();
+        }
+        for t in 0..100 {
+            let wobble = if t % 2 == 0 { 0.05 } else { -0.05 };
+            supervisor.check(wrap(PI + wobble), 0.5).unwrap();
+        }
+    }
+
+    #[test]
+    fn spinning_trips_the_angle_limit() {
+        let mut supervisor = supervisor();
+        let mut angle: f32 = 0.0;
+        for _ in 0..1000 {
+            angle += 0.3;
+            if let Err(trip) = supervisor.check(wrap(angle), 0.5) {
+                let Trip::AngleLimit { angle: rotation } = trip else {
+                    panic!("Unexpected trip {:?}", trip);
+                };
+                assert!(rotation > 3.0 * PI && rotation < 3.0 * PI + 0.31);
+                return;
+            }
+        }
+        panic!("Spinning pendulum did not trip");
+    }
+
+    #[test]
+    fn invalid_values_trip() {
+        let mut supervisor = supervisor();
+        assert!(matches!(
+            supervisor.check(f32::NAN, 0.0),
+            Err(Trip::AngleLimit { .. })
+        ));
+        supervisor.reset();
+        assert_eq!(supervisor.check(0.0, f32::NAN), Err(Trip::InvalidAction));
+        assert_eq!(supervisor.check(0.0, 2.0), Ok(1.0));
+        assert_eq!(supervisor.check(0.0, -2.0), Ok(-1.0));
+    }
+
+    #[test]
+    fn stall_time_follows_the_step_period() {
+        let stall = |supervisor: &mut Supervisor| {
+            supervisor.reset();
+            supervisor.check(0.0, 0.0).unwrap();
+            (1..).find(|_| supervisor.check(0.0, 0.8).is_err()).unwrap()
+        };
+        let mut supervisor = Supervisor::new(Limits::default(), 0.02);
+        assert_eq!(stall(&mut supervisor), 100);
+        supervisor.set_step_period(0.01);
+        assert_eq!(stall(&mut supervisor), 200);
+        supervisor.set_step_period(0.1);
+        assert_eq!(stall(&mut supervisor), 20);
+        supervisor.set_step_period(10.0);
+        assert_eq!(stall(&mut supervisor), 1);
+    }
+
+    #[test]
+    fn recording_a_trip_does_not_block() {
+        record_trip(Trip::Watchdog);
+        assert_eq!(last_trip(), Some(Trip::Watchdog));
+
+        let guard = LAST_TRIP.lock().unwrap();
+        record_trip(Trip::InvalidAction);
+        drop(guard);
+        assert_eq!(last_trip(), Some(Trip::Watchdog));
+    }
+}
@@ -0,0 +1,250 @@
+//! Configuration synchronized through the device shadow.
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Map, Value};
+
+/// Weights of the terms of the reward, which is
+/// `-(w_angle * angle^2 + w_action * action^2)`, where `w_angle` and `w_action` are the fields
+/// `angle` and `action`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RewardWeights {
+    pub angle: f32,
+    pub action: f32,
+}
+
+impl Default for RewardWeights {
+    fn default() -> Self {
+        RewardWeights {
+            angle: 1.0,
+            action: 0.01,
+        }
+    }
+}
+
+impl RewardWeights {
+    pub fn reward(&self, angle: f32, action: f32) -> f32 {
+        -(self.angle * angle * angle + self.action * action * action)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub reward: RewardWeights,
+    /// Frequency of the control loop.
+    pub control_rate_hz: u32,
+    /// Scale of the action applied to the servo.
+    pub servo_scale: f32,
+    /// Version of the model parameters in use, 0 for the signal policy.
+    pub policy_version: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            reward: RewardWeights::default(),
+            control_rate_hz: 50,
+            servo_scale: 0.6,
+            policy_version: 0,
+        }
+    }
+}
+
+/// Range of the control rate. The loop takes a few milliseconds per step.
+const CONTROL_RATE_RANGE: std::ops::RangeInclusive<u32> = 10..=100;
+
+/// Upper bound of the reward weights.
+const MAX_WEIGHT: f32 = 100.0;
+
+fn as_f32(value: &Value, key: &str) -> Result<f32> {
+    let x = value
+        .as_f64()
+        .with_context(|| format!("{key} must be a number"))? as f32;
+    if !x.is_finite() {
+        bail!("{key} must be finite");
+    }
+    Ok(x)
+}
+
+fn as_u32(value: &Value, key: &str) -> Result<u32> {
+    let x = value
+        .as_u64()
+        .with_context(|| format!("{key} must be a non-negative integer"))?;
+    u32::try_from(x).with_context(|| format!("{key} is too large"))
+}
+
+fn as_object<'a>(value: &'a Value, key: &str) -> Result<&'a Map<String, Value>> {
+    value
+        .as_object()
+        .with_context(|| format!("{key} must be an object"))
+}
+
+fn weight(value: &Value, key: &str) -> Result<f32> {
+    let x = as_f32(value, key)?;
+    if !(0.0..=MAX_WEIGHT).contains(&x) {
+        bail!("{key} must be in [0, {MAX_WEIGHT}]: {x}");
+    }
+    Ok(x)
+}
+
+/// Convert to the shortest decimal, e.g. 0.8 instead of 0.800000011920929, so that the
+/// reported values are equal to the desired ones and no delta is left.
+fn decimal(x: f32) -> f64 {
+    x.to_string().parse().unwrap_or(x as f64)
+}
+
+impl Config {
+    /// Period of a step of the control loop. The control rate divides 1000, so this is exact.
+    pub fn step_period_ms(&self) -> u32 {
+        1000 / self.control_rate_hz
+    }
+
+    /// Values as reported to the shadow.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "reward": {
+                "angle": decimal(self.reward.angle),
+                "action": decimal(self.reward.action),
+            },
+            "control_rate_hz": self.control_rate_hz,
+            "servo_scale": decimal(self.servo_scale),
+            "policy_version": self.policy_version,
+        })
+    }
+
+    /// Apply the desired values of a shadow delta.
+    ///
+    /// Nothing is applied if any value is invalid. `policy_version` is not applied here since
+    /// the parameters have to be downloaded first, and the requested version is returned
+    /// instead.
+    pub fn apply(&mut self, desired: &Value) -> Result<Option<u32>> {
+        let mut config = self.clone();
+        let mut policy_version = None;
+        for (key, value) in as_object(desired, "state")? {
+            match key.as_str() {
+                "reward" => {
+                    for (key, value) in as_object(value, "reward")? {
+                        match key.as_str() {
+                            "angle" => config.reward.angle = weight(value, "reward.angle")?,
+                            "action" => config.reward.action = weight(value, "reward.action")?,
+                            _ => bail!("Unknown key reward.{key}"),
+                        }
+                    }
+                }
+                "control_rate_hz" => {
+                    let rate = as_u32(value, key)?;
+                    if !CONTROL_RATE_RANGE.contains(&rate) {
+                        bail!("{key} must be in {CONTROL_RATE_RANGE:?}: {rate}");
+                    }
+                    if 1000 % rate != 0 {
+                        bail!("{key} must divide 1000 for a whole step period in ms: {rate}");
+                    }
+                    config.control_rate_hz = rate;
+                }
+                "servo_scale" => {
+                    let scale = as_f32(value, key)?;
+                    if !(0.0..=1.0).contains(&scale) {
+                        bail!("{key} must be in [0, 1]: {scale}");
+                    }
+                    config.servo_scale = scale;
+                }
+                "policy_version" => policy_version = Some(as_u32(value, key)?),
+                _ => bail!("Unknown key {key}"),
+            }
+        }
+
+        *self = config;
+        Ok(policy_version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reward() {
+        let weights = RewardWeights {
+            angle: 2.0,
+            action: 0.5,
+        };
+        assert_eq!(weights.reward(0.0, 0.0), 0.0);
+        assert_eq!(weights.reward(1.0, 2.0), -4.0);
+        assert_eq!(weights.reward(-1.0, -2.0), -4.0);
+    }
+
+    #[test]
+    fn step_period() {
+        let mut config = Config::default();
+        assert_eq!(config.step_period_ms(), 20);
+        config.control_rate_hz = 100;
+        assert_eq!(config.step_period_ms(), 10);
+        config.control_rate_hz = 25;
+        assert_eq!(config.step_period_ms(), 40);
+    }
+
+    #[test]
+    fn reported_values_are_short_decimals() {
+        let config = Config {
+            servo_scale: 0.8,
+            ..Config::default()
+        };
+        assert_eq!(
+            config.to_json(),
+            json!({
+                "reward": { "angle": 1.0, "action": 0.01 },
+                "control_rate_hz": 50,
+                "servo_scale": 0.8,
+                "policy_version": 0,
+            })
+        );
+    }
+
+    #[test]
+    fn apply_valid_values() {
+        let mut config = Config::default();
+        let version = config
+            .apply(&json!({
+                "reward": { "action": 0.1 },
+                "control_rate_hz": 100,
+                "servo_scale": 0.8,
+            }))
+            .unwrap();
+        assert_eq!(version, None);
+        assert_eq!(config.reward.angle, 1.0);
+        assert_eq!(config.reward.action, 0.1);
+        assert_eq!(config.control_rate_hz, 100);
+        assert_eq!(config.servo_scale, 0.8);
+
+        // The version is returned, not applied
+        assert_eq!(
+            config.apply(&json!({ "policy_version": 3 })).unwrap(),
+            Some(3)
+        );
+        assert_eq!(config.policy_version, 0);
+    }
+
+    #[test]
+    fn invalid_values_apply_nothing() {
+        let invalid = [
+            json!({ "control_rate_hz": 9 }),
+            json!({ "control_rate_hz": 101 }),
+            json!({ "control_rate_hz": 30 }),
+            json!({ "control_rate_hz": 50.5 }),
+            json!({ "control_rate_hz": -1 }),
+            json!({ "servo_scale": 1.5 }),
+            json!({ "servo_scale": "0.5" }),
+            json!({ "reward": { "angle": -1.0 } }),
+            json!({ "reward": { "angle": 101.0 } }),
+            json!({ "reward": { "unknown": 1.0 } }),
+            json!({ "reward": 1.0 }),
+            json!({ "policy_version": 5_000_000_000u64 }),
+            json!({ "unknown": 1 }),
+            json!({ "servo_scale": 0.8, "control_rate_hz": 1000 }),
+            json!(null),
+        ];
+        for desired in invalid {
+            let mut config = Config::default();
+            assert!(config.apply(&desired).is_err(), "{}", desired);
+            assert_eq!(config, Config::default(), "{}", desired);
+        }
+    }
+}
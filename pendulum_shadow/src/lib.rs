@@ -0,0 +1,10 @@
+//! Configuration of `pendulum1` synchronized through the AWS IoT Device Shadow.
+//!
+//! This crate does not depend on ESP-IDF, so the validation of the configuration and the
+//! handling of the shadow topics are tested on the host, the latter against a stand-in of the
+//! broker.
+mod config;
+mod shadow;
+
+pub use config::{Config, RewardWeights};
+pub use shadow::{Response, Shadow};
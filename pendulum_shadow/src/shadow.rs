@@ -0,0 +1,435 @@
+//! Handling of the AWS IoT Device Shadow topics.
+//!
+//! The server sets `desired` values in the shadow, and AWS IoT publishes the difference from
+//! `reported` on the delta topic. The device applies valid deltas to [`Config`] and reports
+//! the applied values. Invalid deltas are rejected by reporting the current values and
+//! removing the desired ones, so that the delta is not sent again.
+//!
+//! To try it without AWS IoT, set `endpoint` in the secrets partition to a local broker and
+//! publish a delta by hand:
+//!
+//! ```text
+//! mosquitto_pub -t '$aws/things/pendulum1/shadow/update/delta' -m '{"state":{"servo_scale":0.8}}'
+//! ```
+use crate::config::Config;
+use anyhow::{Context, Result};
+use serde_json::{json, Map, Value};
+
+/// Result of handling a message on a shadow topic.
+#[derive(Debug, Default, PartialEq)]
+pub struct Response {
+    /// Document to publish on the update topic.
+    pub update: Option<String>,
+    /// Whether the configuration changed.
+    pub changed: bool,
+    /// Version of the model parameters requested by the delta.
+    pub policy_version: Option<u32>,
+}
+
+/// Topics of the classic shadow of a thing.
+pub struct Shadow {
+    prefix: String,
+}
+
+impl Shadow {
+    pub fn new(thing_name: &str) -> Self {
+        Shadow {
+            prefix: format!("$aws/things/{thing_name}/shadow"),
+        }
+    }
+
+    /// Filter of all the topics to subscribe to.
+    pub fn filter(&self) -> String {
+        format!("{}/#", self.prefix)
+    }
+
+    /// Publish an empty message here to get the current shadow.
+    pub fn get_topic(&self) -> String {
+        format!("{}/get", self.prefix)
+    }
+
+    pub fn update_topic(&self) -> String {
+        format!("{}/update", self.prefix)
+    }
+
+    /// Document reporting `config`, removing the desired values of `rejected` keys.
+    pub fn report(config: &Config, rejected: &[&str]) -> String {
+        let mut state = json!({ "reported": config.to_json() });
+        if !rejected.is_empty() {
+            let desired: Map<String, Value> = rejected
+                .iter()
+                .map(|key| (key.to_string(), Value::Null))
+                .collect();
+            state["desired"] = Value::Object(desired);
+        }
+        json!({ "state": state }).to_string()
+    }
+
+    /// Handle a message received on one of the shadow topics.
+    pub fn handle(&self, config: &mut Config, topic: &str, payload: &[u8]) -> Result<Response> {
+        let Some(suffix) = topic.strip_prefix(&self.prefix) else {
+            return Ok(Response::default());
+        };
+        let document: Value = serde_json::from_slice(payload).context("Invalid shadow document")?;
+
+        let delta = match suffix {
+            "/update/delta" => document.get("state"),
+            // The current shadow at startup, with a delta if desired and reported differ
+            "/get/accepted" => document.get("state").and_then(|state| state.get("delta")),
+            "/update/rejected" | "/get/rejected" => {
+                log::error!("Shadow request rejected: {}", document);
+                return Ok(Response::default());
+            }
+            _ => return Ok(Response::default()),
+        };
+        let Some(delta) = delta else {
+            // Nothing desired, just make sure the current values are reported
+            return Ok(Response {
+                update: Some(Self::report(config, &[])),
+                ..Default::default()
+            });
+        };
+
+        let before = config.clone();
+        match config.apply(delta) {
+            Ok(policy_version) => {
+                log::info!("Applied shadow delta: {}", delta);
+                let changed = *config != before;
+                let policy_version = policy_version.filter(|&v| v != config.policy_version);
+                // The version is reported once the parameters are downloaded
+                let update = if changed || policy_version.is_none() {
+                    Some(Self::report(config, &[]))
+                } else {
+                    None
+                };
+                Ok(Response {
+                    update,
+                    changed,
+                    policy_version,
+                })
+            }
+            Err(e) => {
+                log::warn!("Rejected shadow delta {}: {}", delta, e);
+                let keys: Vec<&str> = delta
+                    .as_object()
+                    .map(|delta| delta.keys().map(String::as_str).collect())
+                    .unwrap_or_default();
+                Ok(Response {
+                    update: Some(Self::report(config, &keys)),
+                    ..Default::default()
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THING: &str = "pendulum1";
+
+    /// Merge `update` into `state` as AWS IoT does, where `null` removes a key.
+    fn merge(state: &mut Map<String, Value>, update: &Map<String, Value>) {
+        for (key, value) in update {
+            match (state.get_mut(key), value) {
+                (_, Value::Null) => {
+                    state.remove(key);
+                }
+                (Some(Value::Object(state)), Value::Object(update)) => merge(state, update),
+                _ => {
+                    state.insert(key.clone(), value.clone());
+                }
+            }
+        }
+    }
+
+    /// Desired values that differ from the reported ones.
+    fn difference(
+        desired: &Map<String, Value>,
+        reported: &Map<String, Value>,
+    ) -> Map<String, Value> {
+        let mut delta = Map::new();
+        for (key, value) in desired {
+            match (value, reported.get(key)) {
+                (Value::Object(desired), Some(Value::Object(reported))) => {
+                    let nested = difference(desired, reported);
+                    if !nested.is_empty() {
+                        delta.insert(key.clone(), Value::Object(nested));
+                    }
+                }
+                (value, reported) if reported != Some(value) => {
+                    delta.insert(key.clone(), value.clone());
+                }
+                _ => {}
+            }
+        }
+        delta
+    }
+
+    /// Stand-in of the classic shadow of AWS IoT on the broker.
+    #[derive(Default)]
+    struct Broker {
+        desired: Map<String, Value>,
+        reported: Map<String, Value>,
+        /// Messages to be delivered to the device.
+        outbox: Vec<(String, Vec<u8>)>,
+    }
+
+    impl Broker {
+        fn topic(suffix: &str) -> String {
+            format!("$aws/things/{THING}/shadow{suffix}")
+        }
+
+        fn send(&mut self, suffix: &str, document: Value) {
+            self.outbox
+                .push((Self::topic(suffix), document.to_string().into_bytes()));
+        }
+
+        fn delta(&self) -> Map<String, Value> {
+            difference(&self.desired, &self.reported)
+        }
+
+        /// Update the shadow as the server or the device does.
+        fn update(&mut self, document: &Value) {
+            let state = &document["state"];
+            let desired = state.get("desired").and_then(Value::as_object);
+            let reported = state.get("reported").and_then(Value::as_object);
+            merge(&mut self.desired, desired.unwrap_or(&Map::new()));
+            merge(&mut self.reported, reported.unwrap_or(&Map::new()));
+            self.send("/update/accepted", document.clone());
+
+            // The delta is published only when the desired values change
+            let delta = self.delta();
+            if desired.is_some() && !delta.is_empty() {
+                self.send("/update/delta", json!({ "version": 1, "state": delta }));
+            }
+        }
+
+        /// Handle a message published by the device.
+        fn publish(&mut self, topic: &str, payload: &[u8]) {
+            if topic == Self::topic("/get") {
+                let mut state = json!({ "desired": self.desired, "reported": self.reported });
+                let delta = self.delta();
+                if !delta.is_empty() {
+                    state["delta"] = Value::Object(delta);
+                }
+                self.send("/get/accepted", json!({ "state": state }));
+            } else if topic == Self::topic("/update") {
+                self.update(&serde_json::from_slice(payload).unwrap());
+            } else {
+                panic!("Unexpected topic {}", topic);
+            }
+        }
+    }
+
+    /// The device side, as driven by `Server::sync_shadow`.
+    struct Device {
+        shadow: Shadow,
+        config: Config,
+    }
+
+    impl Device {
+        fn new() -> Self {
+            Device {
+                shadow: Shadow::new(THING),
+                config: Config::default(),
+            }
+        }
+
+        /// Deliver the messages of the broker until there are none, publishing the updates.
+        fn sync(&mut self, broker: &mut Broker) -> Response {
+            let mut result = Response::default();
+            while !broker.outbox.is_empty() {
+                for (topic, payload) in std::mem::take(&mut broker.outbox) {
+                    let response = self
+                        .shadow
+                        .handle(&mut self.config, &topic, &payload)
+                        .unwrap();
+                    if let Some(update) = &response.update {
+                        broker.publish(&self.shadow.update_topic(), update.as_bytes());
+                    }
+                    result.changed |= response.changed;
+                    result.policy_version = response.policy_version.or(result.policy_version);
+                }
+            }
+            result
+        }
+
+        /// Connect and get the current shadow.
+        fn connect(&mut self, broker: &mut Broker) -> Response {
+            broker.publish(&self.shadow.get_topic(), b"");
+            self.sync(broker)
+        }
+    }
+
+    #[test]
+    fn topics() {
+        let shadow = Shadow::new(THING);
+        assert_eq!(shadow.filter(), "$aws/things/pendulum1/shadow/#");
+        assert_eq!(shadow.get_topic(), "$aws/things/pendulum1/shadow/get");
+        assert_eq!(shadow.update_topic(), "$aws/things/pendulum1/shadow/update");
+    }
+
+    #[test]
+    fn report_removes_rejected_keys() {
+        let config = Config::default();
+        let report: Value = serde_json::from_str(&Shadow::report(&config, &[])).unwrap();
+        assert_eq!(report, json!({ "state": { "reported": config.to_json() } }));
+
+        let report = Shadow::report(&config, &["servo_scale", "reward"]);
+        let report: Value = serde_json::from_str(&report).unwrap();
+        assert_eq!(
+            report["state"]["desired"],
+            json!({ "servo_scale": null, "reward": null })
+        );
+    }
+
+    #[test]
+    fn first_connection_reports_the_config() {
+        let (mut broker, mut device) = (Broker::default(), Device::new());
+        assert_eq!(device.connect(&mut broker), Response::default());
+        assert_eq!(
+            Value::Object(broker.reported.clone()),
+            Config::default().to_json()
+        );
+        assert!(broker.delta().is_empty());
+    }
+
+    #[test]
+    fn delta_is_applied() {
+        let (mut broker, mut device) = (Broker::default(), Device::new());
+        device.connect(&mut broker);
+
+        broker.update(&json!({ "state": { "desired": {
+            "servo_scale": 0.8,
+            "control_rate_hz": 100,
+            "reward": { "angle": 2.0 },
+        }}}));
+        let response = device.sync(&mut broker);
+        assert!(response.changed);
+        assert_eq!(response.policy_version, None);
+        assert_eq!(device.config.servo_scale, 0.8);
+        assert_eq!(device.config.control_rate_hz, 100);
+        assert_eq!(device.config.reward.angle, 2.0);
+        assert_eq!(device.config.reward.action, 0.01);
+
+        // The applied values are reported and no delta is left
+        assert!(broker.delta().is_empty(), "{:?}", broker.delta());
+        assert_eq!(broker.reported["servo_scale"], json!(0.8));
+    }
+
+    #[test]
+    fn delta_is_rejected() {
+        let (mut broker, mut device) = (Broker::default(), Device::new());
+        device.connect(&mut broker);
+
+        // One invalid value rejects the whole delta
+        broker.update(&json!({ "state": { "desired": {
+            "servo_scale": 0.8,
+            "control_rate_hz": 1000,
+        }}}));
+        let response = device.sync(&mut broker);
+        assert_eq!(response, Response::default());
+        assert_eq!(device.config, Config::default());
+
+        // The desired values are removed with null, so the delta is not sent again
+        assert!(broker.desired.is_empty(), "{:?}", broker.desired);
+        assert!(broker.delta().is_empty());
+        assert_eq!(
+            Value::Object(broker.reported.clone()),
+            Config::default().to_json()
+        );
+    }
+
+    #[test]
+    fn get_accepted_carries_a_delta() {
+        let (mut broker, mut device) = (Broker::default(), Device::new());
+        device.connect(&mut broker);
+
+        // Desired values set while the device is offline are not delivered as a delta
+        broker.update(&json!({ "state": { "desired": { "servo_scale": 0.3 } } }));
+        broker.outbox.clear();
+
+        let mut device = Device::new();
+        let response = device.connect(&mut broker);
+        assert!(response.changed);
+        assert_eq!(device.config.servo_scale, 0.3);
+        assert!(broker.delta().is_empty());
+    }
+
+    #[test]
+    fn policy_version_is_held_back_until_downloaded() {
+        let (mut broker, mut device) = (Broker::default(), Device::new());
+        device.connect(&mut broker);
+
+        broker.update(&json!({ "state": { "desired": { "policy_version": 3 } } }));
+        let response = device.sync(&mut broker);
+        assert_eq!(response.policy_version, Some(3));
+        assert!(!response.changed);
+        assert_eq!(device.config.policy_version, 0);
+        // Nothing is reported, so the version is still pending
+        assert_eq!(broker.reported["policy_version"], json!(0));
+        assert_eq!(broker.delta()["policy_version"], json!(3));
+
+        // The version is requested again after a reconnect while downloading
+        let response = device.connect(&mut broker);
+        assert_eq!(response.policy_version, Some(3));
+
+        // Once the parameters are downloaded, the version is reported
+        device.config.policy_version = 3;
+        broker.publish(
+            &device.shadow.update_topic(),
+            Shadow::report(&device.config, &[]).as_bytes(),
+        );
+        assert_eq!(device.sync(&mut broker), Response::default());
+        assert!(broker.delta().is_empty());
+
+        // The version in use is not requested again
+        broker.update(&json!({ "state": { "desired": { "policy_version": 3 } } }));
+        assert_eq!(device.sync(&mut broker).policy_version, None);
+    }
+
+    #[test]
+    fn policy_version_with_other_values() {
+        let (mut broker, mut device) = (Broker::default(), Device::new());
+        device.connect(&mut broker);
+
+        broker.update(&json!({ "state": { "desired": {
+            "servo_scale": 0.8,
+            "policy_version": 2,
+        }}}));
+        let response = device.sync(&mut broker);
+        assert!(response.changed);
+        assert_eq!(response.policy_version, Some(2));
+        assert_eq!(broker.reported["servo_scale"], json!(0.8));
+        assert_eq!(broker.reported["policy_version"], json!(0));
+        assert_eq!(
+            Value::Object(broker.delta()),
+            json!({ "policy_version": 2 })
+        );
+    }
+
+    #[test]
+    fn other_messages() {
+        let shadow = Shadow::new(THING);
+        let mut config = Config::default();
+        let none = Response::default();
+
+        assert_eq!(
+            shadow
+                .handle(&mut config, "pendulum1/status", b"x")
+                .unwrap(),
+            none
+        );
+        let rejected = Broker::topic("/update/rejected");
+        let error = br#"{"code":400,"message":"Invalid JSON"}"#;
+        assert_eq!(shadow.handle(&mut config, &rejected, error).unwrap(), none);
+        let accepted = Broker::topic("/update/accepted");
+        assert_eq!(shadow.handle(&mut config, &accepted, b"{}").unwrap(), none);
+
+        let delta = Broker::topic("/update/delta");
+        assert!(shadow.handle(&mut config, &delta, b"not json").is_err());
+        assert_eq!(config, Config::default());
+    }
+}
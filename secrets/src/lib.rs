@@ -0,0 +1,280 @@
+//! Format of the secrets partition.
+//!
+//! Certificates, keys and endpoints are written to a dedicated data partition instead of being
+//! embedded in the firmware, so that the same firmware can be flashed to every device. The
+//! image is generated on the host by `secrets_tool` and read by the devices at runtime.
+//!
+//! The image has the following layout (little endian):
+//!
+//! | field   | type                              |
+//! |---------|-----------------------------------|
+//! | magic   | `b"SEC1"`                         |
+//! | length  | `u32`, size of the entries        |
+//! | crc32   | `u32`, CRC-32 of the entries      |
+//! | entries | `length` bytes                    |
+//!
+//! Each entry is a key (`u8` length and UTF-8 bytes) followed by a value (`u32` length and
+//! bytes). The rest of the partition is filled with `0xFF`.
+use anyhow::{bail, Context, Result};
+
+#[cfg(feature = "esp")]
+mod mqtt;
+#[cfg(feature = "esp")]
+mod partition;
+mod pem;
+
+#[cfg(feature = "esp")]
+pub use mqtt::{spawn_pump, ConnectionState, MqttClient};
+pub use pem::{Pem, TlsCredentials};
+
+/// Magic bytes at the head of the image.
+const MAGIC: &[u8; 4] = b"SEC1";
+
+/// Size of the header of the image.
+pub const HEADER_SIZE: usize = 12;
+
+/// Label of the partition in `partitions.csv`.
+pub const PARTITION_LABEL: &str = "secrets";
+
+/// Subtype of the partition in `partitions.csv`, in the range for custom data partitions.
+pub const PARTITION_SUBTYPE: u8 = 0x40;
+
+/// Key of the root CA certificate of the broker.
+pub const ROOT_CA: &str = "root_ca";
+
+/// Key of the device certificate.
+pub const CERTIFICATE: &str = "certificate";
+
+/// Key of the private key of the device.
+pub const PRIVATE_KEY: &str = "private_key";
+
+/// Key of the URL of the broker, e.g. `mqtts://???:8883`.
+pub const ENDPOINT: &str = "endpoint";
+
+/// Keys of the Wi-Fi credentials, for devices without provisioning.
+pub const WIFI_SSID: &str = "wifi_ssid";
+pub const WIFI_PASS: &str = "wifi_pass";
+
+/// Secrets as pairs of a key and a value.
+#[derive(Debug, Clone, Default)]
+pub struct Secrets {
+    entries: Vec<(String, Vec<u8>)>,
+}
+
+impl Secrets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add or replace a value.
+    pub fn insert(&mut self, key: &str, value: Vec<u8>) -> Result<()> {
+        if key.is_empty() || key.len() > u8::MAX as usize {
+            bail!("Invalid key: {:?}", key);
+        }
+        match self.entries.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value,
+            None => self.entries.push((key.to_string(), value)),
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    /// Get a value that must exist, e.g. a certificate needed to connect.
+    pub fn require(&self, key: &str) -> Result<&[u8]> {
+        self.get(key)
+            .with_context(|| format!("Secret {:?} is not in the secrets partition", key))
+    }
+
+    /// Get a value as a string with surrounding whitespace removed.
+    pub fn require_str(&self, key: &str) -> Result<&str> {
+        let value = std::str::from_utf8(self.require(key)?)
+            .with_context(|| format!("Secret {:?} is not UTF-8", key))?;
+        Ok(value.trim())
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|(k, _)| k.as_str())
+    }
+
+    /// Serialize into an image of `size` bytes.
+    pub fn to_image(&self, size: usize) -> Result<Vec<u8>> {
+        let mut body = Vec::new();
+        for (key, value) in &self.entries {
+            body.push(key.len() as u8);
+            body.extend_from_slice(key.as_bytes());
+            body.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            body.extend_from_slice(value);
+        }
+        if HEADER_SIZE + body.len() > size {
+            bail!(
+                "Secrets take {} bytes, larger than the partition of {} bytes",
+                HEADER_SIZE + body.len(),
+                size
+            );
+        }
+
+        let mut image = Vec::with_capacity(size);
+        image.extend_from_slice(MAGIC);
+        image.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        image.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+        image.extend_from_slice(&body);
+        image.resize(size, 0xFF);
+        Ok(image)
+    }
+
+    /// Length of the entries given the header of an image, to read only the used part of a
+    /// partition.
+    pub fn entries_len(header: &[u8]) -> Result<usize> {
+        if header.len() < HEADER_SIZE || &header[..4] != MAGIC {
+            bail!("No secrets image found, flash one generated by secrets_tool");
+        }
+        Ok(u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize)
+    }
+
+    /// Parse and validate an image.
+    pub fn from_image(image: &[u8]) -> Result<Self> {
+        let len = Self::entries_len(image)?;
+        let crc = u32::from_le_bytes([image[8], image[9], image[10], image[11]]);
+        // The lengths come from the image, so they may overflow on a 32-bit target
+        let body = HEADER_SIZE
+            .checked_add(len)
+            .and_then(|end| image.get(HEADER_SIZE..end))
+            .context("Secrets image is truncated")?;
+        if crc32fast::hash(body) != crc {
+            bail!("Checksum mismatch in the secrets image");
+        }
+
+        let mut secrets = Secrets::new();
+        let mut rest = body;
+        while !rest.is_empty() {
+            let key_len = rest[0] as usize;
+            let key = rest.get(1..1 + key_len).context("Truncated key")?;
+            let key = std::str::from_utf8(key).context("Key is not UTF-8")?;
+            rest = &rest[1 + key_len..];
+
+            let value_len = rest.get(..4).context("Truncated value length")?;
+            let value_len =
+                u32::from_le_bytes([value_len[0], value_len[1], value_len[2], value_len[3]])
+                    as usize;
+            let end = 4usize.checked_add(value_len).context("Truncated value")?;
+            let value = rest.get(4..end).context("Truncated value")?;
+            secrets.insert(key, value.to_vec())?;
+            rest = &rest[end..];
+        }
+        Ok(secrets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secrets() -> Secrets {
+        let mut secrets = Secrets::new();
+        secrets
+            .insert(ENDPOINT, b"mqtts://example.com:8883\n".to_vec())
+            .unwrap();
+        secrets.insert(WIFI_SSID, b"home".to_vec()).unwrap();
+        secrets.insert(WIFI_PASS, Vec::new()).unwrap();
+        secrets
+    }
+
+    /// An image with `body` as the entries and a valid checksum.
+    fn image_with_body(body: &[u8]) -> Vec<u8> {
+        let mut image = MAGIC.to_vec();
+        image.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        image.extend_from_slice(&crc32fast::hash(body).to_le_bytes());
+        image.extend_from_slice(body);
+        image
+    }
+
+    #[test]
+    fn round_trip() {
+        let image = secrets().to_image(256).unwrap();
+        assert_eq!(image.len(), 256);
+        let len = Secrets::entries_len(&image).unwrap();
+        assert!(image[HEADER_SIZE + len..].iter().all(|b| *b == 0xFF));
+
+        let loaded = Secrets::from_image(&image).unwrap();
+        assert_eq!(
+            loaded.keys().collect::<Vec<_>>(),
+            secrets().keys().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            loaded.require_str(ENDPOINT).unwrap(),
+            "mqtts://example.com:8883"
+        );
+        assert_eq!(loaded.get(WIFI_PASS), Some(&[][..]));
+        assert!(loaded.require(ROOT_CA).is_err());
+
+        // Only the used part of a partition is needed
+        let loaded = Secrets::from_image(&image[..HEADER_SIZE + len]).unwrap();
+        assert_eq!(loaded.get(WIFI_SSID), Some(&b"home"[..]));
+    }
+
+    #[test]
+    fn empty_secrets() {
+        let image = Secrets::new().to_image(HEADER_SIZE).unwrap();
+        assert_eq!(Secrets::from_image(&image).unwrap().keys().count(), 0);
+    }
+
+    #[test]
+    fn too_large_for_the_partition() {
+        let image = secrets().to_image(1024).unwrap();
+        let len = Secrets::entries_len(&image).unwrap();
+        assert!(secrets().to_image(HEADER_SIZE + len).is_ok());
+        assert!(secrets().to_image(HEADER_SIZE + len - 1).is_err());
+    }
+
+    #[test]
+    fn truncated_images() {
+        let image = secrets().to_image(256).unwrap();
+        let len = Secrets::entries_len(&image).unwrap();
+        assert!(Secrets::from_image(&image[..HEADER_SIZE + len - 1]).is_err());
+        assert!(Secrets::from_image(&image[..HEADER_SIZE - 1]).is_err());
+        assert!(Secrets::from_image(&[]).is_err());
+
+        // Lengths beyond the image, up to the maximum, are rejected without overflowing
+        let mut huge = image.clone();
+        huge[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Secrets::from_image(&huge).is_err());
+    }
+
+    #[test]
+    fn corrupt_images() {
+        let image = secrets().to_image(256).unwrap();
+
+        let mut magic = image.clone();
+        magic[0] = b'X';
+        assert!(Secrets::from_image(&magic).is_err());
+
+        let mut entry = image.clone();
+        entry[HEADER_SIZE + 3] ^= 1;
+        let error = Secrets::from_image(&entry).err().unwrap().to_string();
+        assert!(error.contains("Checksum"), "{}", error);
+
+        // An erased partition
+        assert!(Secrets::from_image(&[0xFF; 64]).is_err());
+    }
+
+    #[test]
+    fn malformed_entries_with_a_valid_checksum() {
+        let mut body = vec![3];
+        body.extend_from_slice(b"key");
+        body.extend_from_slice(&u32::MAX.to_le_bytes());
+        body.extend_from_slice(b"value");
+        assert!(Secrets::from_image(&image_with_body(&body)).is_err());
+
+        assert!(Secrets::from_image(&image_with_body(&[3, b'k', b'e'])).is_err());
+        assert!(Secrets::from_image(&image_with_body(&[1, b'k', 0, 0])).is_err());
+        assert!(Secrets::from_image(&image_with_body(&[1, 0xFF, 0, 0, 0, 0])).is_err());
+        // A key must not be empty
+        assert!(Secrets::from_image(&image_with_body(&[0, 0, 0, 0, 0])).is_err());
+    }
+}
@@ -0,0 +1,52 @@
+use anyhow::{bail, Context, Result};
+use secrets::{Pem, Secrets};
+use std::fs;
+
+/// Default size of the secrets partition, see `partitions.csv` of the projects.
+const DEFAULT_SIZE: usize = 0x4000;
+
+const USAGE: &str = "Usage: secrets_tool <output image> <key>=<file>... [--size <bytes>]";
+
+fn parse_size(value: &str) -> Result<usize> {
+    let size = match value.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16)?,
+        None => value.parse()?,
+    };
+    Ok(size)
+}
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let Some(output) = args.next() else {
+        bail!(USAGE);
+    };
+
+    let mut secrets = Secrets::new();
+    let mut size = DEFAULT_SIZE;
+    while let Some(arg) = args.next() {
+        if arg == "--size" {
+            let value = args.next().context("Missing value for --size")?;
+            size = parse_size(&value).with_context(|| format!("Invalid size: {value}"))?;
+            continue;
+        }
+        let Some((key, path)) = arg.split_once('=') else {
+            bail!(USAGE);
+        };
+        let value = fs::read(path).with_context(|| format!("Failed to read {path}"))?;
+        println!("{key}: {path} ({} bytes)", value.len());
+        if [secrets::ROOT_CA, secrets::CERTIFICATE, secrets::PRIVATE_KEY].contains(&key) {
+            Pem::parse(&value).with_context(|| format!("Invalid PEM: {path}"))?;
+        }
+        secrets.insert(key, value)?;
+    }
+    if secrets.keys().next().is_none() {
+        bail!(USAGE);
+    }
+
+    let image = secrets.to_image(size)?;
+    Secrets::from_image(&image)?;
+    fs::write(&output, image).with_context(|| format!("Failed to write {output}"))?;
+    println!("Saved {size} bytes to {output}");
+
+    Ok(())
+}
@@ -0,0 +1,197 @@
+//! MQTT client that owns its TLS credentials, and the thread pumping its connection.
+use crate::TlsCredentials;
+use anyhow::Result;
+use esp_idf_svc::mqtt::client::{
+    Details, EspMqttClient, EspMqttConnection, EventPayload, MessageId, MqttClientConfiguration,
+    QoS,
+};
+use esp_idf_svc::tls::X509;
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+fn create_client<'a>(
+    url: &str,
+    client_id: &str,
+    credentials: &'a TlsCredentials,
+) -> Result<(EspMqttClient<'a>, EspMqttConnection)> {
+    let pem = |pem: &'a crate::Pem| X509::pem_until_nul(pem.as_bytes_with_nul());
+    let (client, conn) = EspMqttClient::new(
+        url,
+        &MqttClientConfiguration {
+            client_id: Some(client_id),
+            crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+            server_certificate: Some(pem(&credentials.root_ca)),
+            client_certificate: Some(pem(&credentials.certificate)),
+            private_key: Some(pem(&credentials.private_key)),
+            ..Default::default()
+        },
+    )?;
+
+    Ok((client, conn))
+}
+
+/// MQTT client over mutual TLS.
+///
+/// ESP-MQTT keeps pointers to the certificates for reconnecting, so they are kept here as long
+/// as the client instead of being leaked. The client is not exposed so that it cannot outlive
+/// them.
+pub struct MqttClient {
+    // Declared before `credentials` so that it is dropped first
+    client: EspMqttClient<'static>,
+    credentials: Box<TlsCredentials>,
+    url: String,
+    client_id: String,
+}
+
+impl MqttClient {
+    /// Create a client. Events must be pumped from the returned connection.
+    pub fn connect(
+        url: &str,
+        client_id: &str,
+        credentials: TlsCredentials,
+    ) -> Result<(Self, EspMqttConnection)> {
+        let credentials = Box::new(credentials);
+        let (client, conn) = create_client(url, client_id, &credentials)?;
+        // SAFETY: the credentials are on the heap and not moved or dropped while the client
+        // exists, see the field order and `rotate`.
+        let client =
+            unsafe { std::mem::transmute::<EspMqttClient<'_>, EspMqttClient<'static>>(client) };
+
+        Ok((
+            Self {
+                client,
+                credentials,
+                url: url.to_string(),
+                client_id: client_id.to_string(),
+            },
+            conn,
+        ))
+    }
+
+    /// Reconnect with new credentials. The previous connection is closed and the returned one
+    /// must be pumped instead. On error, the current client is kept.
+    pub fn rotate(&mut self, credentials: TlsCredentials) -> Result<EspMqttConnection> {
+        let credentials = Box::new(credentials);
+        let (client, conn) = create_client(&self.url, &self.client_id, &credentials)?;
+        // SAFETY: same as `connect`
+        let client =
+            unsafe { std::mem::transmute::<EspMqttClient<'_>, EspMqttClient<'static>>(client) };
+
+        // Drop the old client before its credentials
+        self.client = client;
+        self.credentials = credentials;
+        log::info!("Rotated the certificate of {}", self.client_id);
+
+        Ok(conn)
+    }
+
+    pub fn credentials(&self) -> &TlsCredentials {
+        &self.credentials
+    }
+
+    pub fn subscribe(&mut self, topic: &str, qos: QoS) -> Result<MessageId> {
+        Ok(self.client.subscribe(topic, qos)?)
+    }
+
+    pub fn unsubscribe(&mut self, topic: &str) -> Result<MessageId> {
+        Ok(self.client.unsubscribe(topic)?)
+    }
+
+    pub fn publish(
+        &mut self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: &[u8],
+    ) -> Result<MessageId> {
+        Ok(self.client.publish(topic, qos, retain, payload)?)
+    }
+
+    pub fn enqueue(
+        &mut self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: &[u8],
+    ) -> Result<MessageId> {
+        Ok(self.client.enqueue(topic, qos, retain, payload)?)
+    }
+}
+
+/// State of a connection, updated by the thread pumping it.
+#[derive(Debug, Default)]
+pub struct ConnectionState {
+    connected: AtomicBool,
+    sessions: AtomicU32,
+}
+
+impl ConnectionState {
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Acquire)
+    }
+
+    /// Number of `Connected` events so far. Subscriptions of a clean session are lost when
+    /// this changes.
+    pub fn sessions(&self) -> u32 {
+        self.sessions.load(Ordering::Acquire)
+    }
+}
+
+/// Pump `conn` in a background thread until the client is dropped, or else `publish` will not
+/// work. Complete messages are passed to `on_message` with their topic.
+///
+/// The client must not be used in `on_message`, as ESP-MQTT holds its lock until the event is
+/// consumed.
+pub fn spawn_pump(
+    mut conn: EspMqttConnection,
+    state: Arc<ConnectionState>,
+    mut on_message: impl FnMut(&str, &[u8]) + Send + 'static,
+) -> Result<JoinHandle<()>> {
+    let pump = std::thread::Builder::new()
+        .name("mqtt".into())
+        .stack_size(6000)
+        .spawn(move || {
+            // Large messages are delivered in chunks, with the topic only in the first one
+            let mut topic = String::new();
+            let mut message = Vec::new();
+            while let Ok(event) = conn.next() {
+                match event.payload() {
+                    EventPayload::Connected(_) => {
+                        state.connected.store(true, Ordering::Release);
+                        state.sessions.fetch_add(1, Ordering::AcqRel);
+                        info!("Connected to the broker");
+                    }
+                    EventPayload::Disconnected => {
+                        state.connected.store(false, Ordering::Release);
+                        warn!("Disconnected from the broker");
+                    }
+                    EventPayload::Received {
+                        topic: received,
+                        data,
+                        details,
+                        ..
+                    } => {
+                        let total = match details {
+                            Details::Complete => data.len(),
+                            Details::InitialChunk(chunk) => chunk.total_data_size,
+                            Details::SubsequentChunk(chunk) => chunk.total_data_size,
+                        };
+                        if let Some(received) = received {
+                            topic = received.to_string();
+                            message.clear();
+                        }
+                        message.extend_from_slice(data);
+                        if message.len() >= total {
+                            on_message(&topic, &std::mem::take(&mut message));
+                        }
+                    }
+                    payload => info!("[Queue] Event: {}", payload),
+                }
+            }
+            state.connected.store(false, Ordering::Release);
+            info!("Connection closed");
+        })?;
+    Ok(pump)
+}
@@ -0,0 +1,46 @@
+//! Read the secrets partition on the device.
+use crate::{Secrets, HEADER_SIZE, PARTITION_LABEL, PARTITION_SUBTYPE};
+use anyhow::{bail, Result};
+use esp_idf_svc::sys::{
+    esp, esp_partition_find_first, esp_partition_read, esp_partition_subtype_t,
+    esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+};
+use std::ffi::CString;
+
+impl Secrets {
+    /// Load the secrets flashed to the partition labeled [`PARTITION_LABEL`].
+    pub fn load() -> Result<Self> {
+        let label = CString::new(PARTITION_LABEL)?;
+        let partition = unsafe {
+            esp_partition_find_first(
+                esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+                PARTITION_SUBTYPE as esp_partition_subtype_t,
+                label.as_ptr(),
+            )
+        };
+        if partition.is_null() {
+            bail!(
+                "Partition {:?} not found, check partitions.csv",
+                PARTITION_LABEL
+            );
+        }
+        let size = unsafe { (*partition).size } as usize;
+
+        let mut header = [0u8; HEADER_SIZE];
+        esp!(unsafe { esp_partition_read(partition, 0, header.as_mut_ptr() as _, header.len()) })?;
+        let len = Self::entries_len(&header)?;
+        let Some(end) = HEADER_SIZE.checked_add(len).filter(|end| *end <= size) else {
+            bail!("Secrets image is larger than the partition");
+        };
+
+        let mut image = vec![0u8; end];
+        esp!(unsafe { esp_partition_read(partition, 0, image.as_mut_ptr() as _, image.len()) })?;
+        let secrets = Self::from_image(&image)?;
+        log::info!(
+            "Loaded secrets: {}",
+            secrets.keys().collect::<Vec<_>>().join(", ")
+        );
+
+        Ok(secrets)
+    }
+}
@@ -0,0 +1,219 @@
+//! Dispatch incoming MQTT messages to handlers by topic filter.
+//!
+//! This crate does not depend on ESP-IDF so that it can be used both on the devices and on the
+//! host (`mqtt_aws_macbook`), and the matching can be checked on the host.
+use anyhow::{bail, Result};
+
+/// Check that a topic filter is valid: `#` only as the whole last level and `+` only as a
+/// whole level.
+pub fn validate_filter(filter: &str) -> Result<()> {
+    if filter.is_empty() {
+        bail!("Empty topic filter");
+    }
+    let levels: Vec<&str> = filter.split('/').collect();
+    for (i, level) in levels.iter().enumerate() {
+        if level.contains('#') && (*level != "#" || i != levels.len() - 1) {
+            bail!("'#' must be the last level of {:?}", filter);
+        }
+        if level.contains('+') && *level != "+" {
+            bail!("'+' must occupy a whole level of {:?}", filter);
+        }
+    }
+    Ok(())
+}
+
+/// Whether `topic` matches `filter`.
+///
+/// `+` matches a single level and `#` matches any number of levels including the parent, so
+/// `a/#` matches `a`. Topics starting with `$` (e.g. `$aws/things/...`) are not matched by
+/// filters starting with a wildcard.
+pub fn matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+type Handler = Box<dyn FnMut(&str, &[u8]) + Send>;
+
+/// Handlers registered by topic filter.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<(String, Handler)>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a handler called with the topic and the payload of matching messages.
+    pub fn add(
+        &mut self,
+        filter: &str,
+        handler: impl FnMut(&str, &[u8]) + Send + 'static,
+    ) -> Result<()> {
+        validate_filter(filter)?;
+        self.routes.push((filter.to_string(), Box::new(handler)));
+        Ok(())
+    }
+
+    /// Filters of the handlers, e.g. to subscribe to them.
+    pub fn filters(&self) -> impl Iterator<Item = &str> {
+        self.routes.iter().map(|(f, _)| f.as_str())
+    }
+
+    /// Call every handler whose filter matches the topic, in the order they were added.
+    /// Returns the number of handlers called.
+    pub fn dispatch(&mut self, topic: &str, payload: &[u8]) -> usize {
+        let mut called = 0;
+        for (filter, handler) in &mut self.routes {
+            if matches(filter, topic) {
+                handler(topic, payload);
+                called += 1;
+            }
+        }
+        called
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn exact_match() {
+        assert!(matches("a/b", "a/b"));
+        assert!(!matches("a/b", "a/c"));
+        assert!(!matches("a/b", "a"));
+        assert!(!matches("a/b", "a/b/c"));
+        assert!(!matches("a/b", "A/b"));
+    }
+
+    #[test]
+    fn plus_matches_a_single_level() {
+        assert!(matches("a/+/c", "a/b/c"));
+        assert!(matches("a/+", "a/b"));
+        assert!(matches("+", "a"));
+        assert!(matches("+/+", "a/b"));
+        assert!(!matches("a/+", "a"));
+        assert!(!matches("a/+", "a/b/c"));
+        assert!(!matches("a/+/c", "a/b/d"));
+        assert!(!matches("+", "a/b"));
+    }
+
+    #[test]
+    fn hash_matches_the_remaining_levels() {
+        assert!(matches("#", "a"));
+        assert!(matches("#", "a/b/c"));
+        assert!(matches("a/#", "a/b"));
+        assert!(matches("a/#", "a/b/c"));
+        assert!(matches("a/+/#", "a/b/c/d"));
+        assert!(!matches("a/#", "b/c"));
+        assert!(!matches("a/b/#", "a/c/d"));
+    }
+
+    #[test]
+    fn hash_matches_the_parent() {
+        assert!(matches("a/#", "a"));
+        assert!(matches("a/b/#", "a/b"));
+        assert!(!matches("a/b/#", "a"));
+    }
+
+    #[test]
+    fn empty_levels() {
+        assert!(matches("a//b", "a//b"));
+        assert!(matches("a/+/b", "a//b"));
+        assert!(matches("+/a", "/a"));
+        assert!(matches("a/+", "a/"));
+        assert!(matches("a/#", "a/"));
+        assert!(!matches("a/b", "a//b"));
+        assert!(!matches("a", "a/"));
+        assert!(!matches("/a", "a"));
+    }
+
+    #[test]
+    fn dollar_topics() {
+        let topic = "$aws/things/pendulum1/shadow/update/delta";
+        assert!(!matches("#", topic));
+        assert!(!matches("+/things/pendulum1/shadow/update/delta", topic));
+        assert!(matches("$aws/things/pendulum1/shadow/#", topic));
+        assert!(matches("$aws/things/+/shadow/update/delta", topic));
+        assert!(!matches("$aws/things/other/shadow/#", topic));
+        // Only the first level is special
+        assert!(matches("a/#", "a/$b"));
+    }
+
+    #[test]
+    fn valid_filters() {
+        for filter in [
+            "a", "a/b", "+", "#", "a/+/c", "a/#", "+/+/#", "/", "a//b", "$aws/#",
+        ] {
+            assert!(validate_filter(filter).is_ok(), "{:?}", filter);
+        }
+    }
+
+    #[test]
+    fn invalid_filters() {
+        for filter in [
+            "", "a/#/b", "#/a", "a#", "a/b#", "a+", "a/+b", "a/++/c", "++",
+        ] {
+            assert!(validate_filter(filter).is_err(), "{:?}", filter);
+        }
+    }
+
+    #[test]
+    fn router_rejects_invalid_filters() {
+        let mut router = Router::new();
+        assert!(router.add("a/#/b", |_, _| {}).is_err());
+        assert!(router.add("a+", |_, _| {}).is_err());
+        assert_eq!(router.filters().count(), 0);
+    }
+
+    #[test]
+    fn dispatch_to_every_matching_handler_in_order() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut router = Router::new();
+        for filter in ["a/b", "a/+", "#", "c"] {
+            let calls = calls.clone();
+            router
+                .add(filter, move |topic, payload| {
+                    calls
+                        .lock()
+                        .unwrap()
+                        .push((filter, topic.to_string(), payload.to_vec()));
+                })
+                .unwrap();
+        }
+        assert_eq!(
+            router.filters().collect::<Vec<_>>(),
+            ["a/b", "a/+", "#", "c"]
+        );
+
+        assert_eq!(router.dispatch("a/b", b"x"), 3);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                ("a/b", "a/b".to_string(), b"x".to_vec()),
+                ("a/+", "a/b".to_string(), b"x".to_vec()),
+                ("#", "a/b".to_string(), b"x".to_vec()),
+            ]
+        );
+
+        calls.lock().unwrap().clear();
+        assert_eq!(router.dispatch("$SYS/uptime", b""), 0);
+        assert!(calls.lock().unwrap().is_empty());
+    }
+}
@@ -1,3 +1,16 @@
+use std::{env, fs, path::PathBuf};
+
 fn main() {
     embuild::espidf::sysenv::output();
+
+    // Embed the list of known networks given by WIFI_NETWORKS=<path>, if any
+    println!("cargo:rerun-if-env-changed=WIFI_NETWORKS");
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("networks.txt");
+    match env::var("WIFI_NETWORKS") {
+        Ok(path) => {
+            println!("cargo:rerun-if-changed={path}");
+            fs::copy(&path, &out).unwrap_or_else(|e| panic!("Failed to read {path}: {e}"));
+        }
+        Err(_) => fs::write(&out, "").unwrap(),
+    }
 }
@@ -1,16 +1,21 @@
-use core::convert::TryInto;
-
-use embedded_svc::wifi::{AuthMethod, ClientConfiguration, Configuration};
-
 use esp_idf_svc::hal::prelude::Peripherals;
+use esp_idf_svc::hal::reset::restart;
 use esp_idf_svc::log::EspLogger;
-use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
 use esp_idf_svc::{eventloop::EspSystemEventLoop, nvs::EspDefaultNvsPartition};
 
-use log::info;
+use log::{info, warn};
+use std::time::Duration;
+use wifi_manager::{parse_networks, provisioning, Credentials, KnownNetwork, WifiManager};
+
+/// Name of the access point for provisioning.
+const AP_SSID: &str = "m5stamp-setup";
 
-const SSID: &str = env!("WIFI_SSID");
-const PASSWORD: &str = env!("WIFI_PASS");
+/// Known networks embedded from the file given by the `WIFI_NETWORKS` environment variable at
+/// build time (see build.rs), one `<priority>,<ssid>,<password>` per line.
+const NETWORKS: &str = include_str!(concat!(env!("OUT_DIR"), "/networks.txt"));
+
+/// Time to wait for the connection before the saved credentials are discarded.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
 
 fn main() -> anyhow::Result<()> {
     esp_idf_svc::sys::link_patches();
@@ -20,44 +25,44 @@ fn main() -> anyhow::Result<()> {
     let sys_loop = EspSystemEventLoop::take()?;
     let nvs = EspDefaultNvsPartition::take()?;
 
-    let mut wifi = BlockingWifi::wrap(
-        EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs))?,
-        sys_loop,
-    )?;
-
-    connect_wifi(&mut wifi)?;
-
-    let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
-
-    info!("Wifi DHCP info: {:?}", ip_info);
-
-    info!("Shutting down in 5s...");
-
-    std::thread::sleep(core::time::Duration::from_secs(5));
-
-    Ok(())
-}
-
-fn connect_wifi(wifi: &mut BlockingWifi<EspWifi<'static>>) -> anyhow::Result<()> {
-    let wifi_configuration: Configuration = Configuration::Client(ClientConfiguration {
-        ssid: SSID.try_into().unwrap(),
-        bssid: None,
-        auth_method: AuthMethod::WPA2Personal,
-        password: PASSWORD.try_into().unwrap(),
-        channel: None,
-        ..Default::default()
-    });
-
-    wifi.set_configuration(&wifi_configuration)?;
-
-    wifi.start()?;
-    info!("Wifi started");
-
-    wifi.connect()?;
-    info!("Wifi connected");
-
-    wifi.wait_netif_up()?;
-    info!("Wifi netif up");
+    // Known networks from the build environment and from provisioning
+    let mut networks = parse_networks(NETWORKS)?;
+    if let (Some(ssid), Some(password)) = (option_env!("WIFI_SSID"), option_env!("WIFI_PASS")) {
+        networks.push(KnownNetwork::new(ssid, password, 0));
+    }
+    let provisioned = Credentials::load(nvs.clone())?;
+    let only_provisioned = networks.is_empty() && provisioned.is_some();
+    networks.extend(provisioned.map(KnownNetwork::from));
+    if networks.is_empty() {
+        info!("No Wifi credentials, starting provisioning");
+        match provisioning::provision(peripherals.modem, sys_loop, nvs, AP_SSID)? {}
+    }
+
+    for network in &networks {
+        info!(
+            "Known network: \"{}\" (priority {})",
+            network.ssid, network.priority
+        );
+    }
+    let wifi = WifiManager::start(peripherals.modem, sys_loop, Some(nvs.clone()), networks)?;
+
+    if !wifi.wait_connected(CONNECT_TIMEOUT) {
+        if only_provisioned {
+            // The network may have changed, so provision again after reboot
+            warn!("Could not connect, discarding the credentials and rebooting");
+            Credentials::clear(nvs)?;
+            restart();
+        }
+        warn!("Not connected yet, still retrying in the background");
+    }
+
+    // Print the status for a while to see reconnections, e.g. by turning off the access point
+    for _ in 0..12 {
+        info!("Wifi status: {:?}", wifi.status());
+        std::thread::sleep(Duration::from_secs(5));
+    }
+
+    info!("Shutting down...");
 
     Ok(())
 }
@@ -0,0 +1,75 @@
+//! Exponential backoff between connection attempts.
+//!
+//! This module does not depend on ESP-IDF so that it can be checked on the host.
+use std::time::Duration;
+
+/// Delays that grow by `factor` after each failed attempt, up to `max`.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    factor: u32,
+    current: Duration,
+}
+
+impl Default for Backoff {
+    /// 1s, 2s, 4s, ... up to 60s.
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1), Duration::from_secs(60), 2)
+    }
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration, factor: u32) -> Self {
+        Backoff {
+            initial,
+            max,
+            factor: factor.max(1),
+            current: initial,
+        }
+    }
+
+    /// Delay before the next attempt. Each call increases the following delay.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * self.factor).min(self.max);
+        delay
+    }
+
+    /// Start again from the initial delay, e.g. after a successful connection.
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(delays: &[u64]) -> Vec<Duration> {
+        delays.iter().map(|s| Duration::from_secs(*s)).collect()
+    }
+
+    #[test]
+    fn grows_up_to_max() {
+        let mut backoff = Backoff::default();
+        let delays: Vec<_> = (0..9).map(|_| backoff.next_delay()).collect();
+        assert_eq!(delays, secs(&[1, 2, 4, 8, 16, 32, 60, 60, 60]));
+    }
+
+    #[test]
+    fn reset_starts_again() {
+        let mut backoff = Backoff::new(Duration::from_secs(2), Duration::from_secs(10), 3);
+        assert_eq!(backoff.next_delay(), Duration::from_secs(2));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(6));
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn factor_below_one_keeps_the_delay() {
+        let mut backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(60), 0);
+        let delays: Vec<_> = (0..3).map(|_| backoff.next_delay()).collect();
+        assert_eq!(delays, secs(&[5, 5, 5]));
+    }
+}
@@ -0,0 +1,62 @@
+//! Wi-Fi credentials stored in NVS.
+use crate::networks::KnownNetwork;
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+
+const NAMESPACE: &str = "wifi";
+const SSID_KEY: &str = "ssid";
+const PASSWORD_KEY: &str = "password";
+
+/// Open the NVS namespace of the credentials.
+pub(crate) fn storage(nvs: EspDefaultNvsPartition) -> Result<EspNvs<NvsDefault>> {
+    Ok(EspNvs::new(nvs, NAMESPACE, true)?)
+}
+
+/// SSID and password of a network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub ssid: String,
+    pub password: String,
+}
+
+impl Credentials {
+    /// Load the credentials saved by provisioning, or `None` if nothing is saved.
+    pub fn load(nvs: EspDefaultNvsPartition) -> Result<Option<Self>> {
+        let nvs = storage(nvs)?;
+        let mut ssid = [0; 33];
+        let mut password = [0; 65];
+        let ssid = nvs.get_str(SSID_KEY, &mut ssid)?;
+        let password = nvs.get_str(PASSWORD_KEY, &mut password)?;
+        Ok(match (ssid, password) {
+            (Some(ssid), Some(password)) if !ssid.is_empty() => Some(Credentials {
+                ssid: ssid.to_string(),
+                password: password.to_string(),
+            }),
+            _ => None,
+        })
+    }
+
+    pub fn save(&self, nvs: &mut EspNvs<NvsDefault>) -> Result<()> {
+        nvs.set_str(SSID_KEY, &self.ssid)?;
+        nvs.set_str(PASSWORD_KEY, &self.password)?;
+        Ok(())
+    }
+
+    /// Remove the saved credentials, so that provisioning starts after the next boot.
+    pub fn clear(nvs: EspDefaultNvsPartition) -> Result<()> {
+        let mut nvs = storage(nvs)?;
+        nvs.remove(SSID_KEY)?;
+        nvs.remove(PASSWORD_KEY)?;
+        Ok(())
+    }
+}
+
+impl From<Credentials> for KnownNetwork {
+    fn from(credentials: Credentials) -> Self {
+        KnownNetwork {
+            ssid: credentials.ssid,
+            password: credentials.password,
+            priority: 0,
+        }
+    }
+}
@@ -0,0 +1,25 @@
+//! Wi-Fi station connection shared by the projects in this repository.
+//!
+//! [`WifiManager`] connects in a background task and reconnects with exponential backoff when
+//! the link is lost, so that the caller is never blocked by the network. Before each attempt,
+//! the access points are scanned to choose among the known networks.
+//!
+//! The parts that use ESP-IDF are enabled by the `esp` feature. The others, such as the
+//! selection of the networks and the captive portal, are checked on the host.
+mod backoff;
+#[cfg(feature = "esp")]
+mod credentials;
+#[cfg(feature = "esp")]
+mod manager;
+mod networks;
+#[cfg(any(feature = "esp", test))]
+mod portal;
+#[cfg(feature = "esp")]
+pub mod provisioning;
+
+pub use backoff::Backoff;
+#[cfg(feature = "esp")]
+pub use credentials::Credentials;
+#[cfg(feature = "esp")]
+pub use manager::{ConnectionState, Status, WifiManager};
+pub use networks::{parse_networks, KnownNetwork};
@@ -0,0 +1,242 @@
+//! Background connection of the Wi-Fi station.
+use crate::backoff::Backoff;
+use crate::networks::{self, KnownNetwork};
+use anyhow::{bail, Result};
+use embedded_svc::wifi::{AccessPointInfo, AuthMethod, ClientConfiguration, Configuration};
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::modem::Modem;
+use esp_idf_svc::hal::peripheral::Peripheral;
+use esp_idf_svc::ipv4::Ipv4Addr;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::sys::{esp, esp_wifi_sta_get_ap_info, wifi_ap_record_t};
+use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
+use log::{info, warn};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Period to check the link and update the RSSI.
+const POLL_PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Disconnected,
+    Connecting,
+    Connected,
+}
+
+/// Connection status reported by [`WifiManager::status`].
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub state: ConnectionState,
+
+    /// IP address while connected.
+    pub ip: Option<Ipv4Addr>,
+
+    /// Signal strength of the access point in dBm while connected.
+    pub rssi: Option<i8>,
+
+    /// Number of failed attempts since the last successful connection.
+    pub failures: u32,
+}
+
+/// Keeps a Wi-Fi station connected.
+pub struct WifiManager {
+    status: Arc<Mutex<Status>>,
+}
+
+impl WifiManager {
+    /// Start connecting to one of `networks` in a background task.
+    pub fn start(
+        modem: impl Peripheral<P = Modem> + 'static,
+        sys_loop: EspSystemEventLoop,
+        nvs: Option<EspDefaultNvsPartition>,
+        networks: Vec<KnownNetwork>,
+    ) -> Result<Self> {
+        if networks.is_empty() {
+            bail!("No networks to connect to");
+        }
+        let mut wifi = BlockingWifi::wrap(EspWifi::new(modem, sys_loop.clone(), nvs)?, sys_loop)?;
+        wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+        wifi.start()?;
+        info!("Wifi started");
+
+        let status = Arc::new(Mutex::new(Status {
+            state: ConnectionState::Disconnected,
+            ip: None,
+            rssi: None,
+            failures: 0,
+        }));
+        let shared = status.clone();
+        std::thread::Builder::new()
+            .name("wifi".into())
+            .stack_size(6144)
+            .spawn(move || run(wifi, networks, shared))?;
+
+        Ok(WifiManager { status })
+    }
+
+    pub fn status(&self) -> Status {
+        self.status.lock().unwrap().clone()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.status().state == ConnectionState::Connected
+    }
+
+    /// Wait until connected. Returns `false` if not connected within `timeout`.
+    pub fn wait_connected(&self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while !self.is_connected() {
+            if start.elapsed() > timeout {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        true
+    }
+}
+
+fn update(status: &Mutex<Status>, f: impl FnOnce(&mut Status)) {
+    if let Ok(mut status) = status.lock() {
+        f(&mut status);
+    }
+}
+
+/// Signal strength of the connected access point.
+fn rssi() -> Option<i8> {
+    let mut ap_info = wifi_ap_record_t::default();
+    unsafe { esp!(esp_wifi_sta_get_ap_info(&mut ap_info)) }
+        .ok()
+        .map(|_| ap_info.rssi)
+}
+
+/// Log the scanned access points, strongest first.
+fn report(access_points: &[AccessPointInfo]) {
+    info!("Found {} access points", access_points.len());
+    for ap in access_points {
+        info!(
+            "  {:>4} dBm  ch {:>2}  {:<16}  {}",
+            ap.signal_strength,
+            ap.channel,
+            format!("{:?}", ap.auth_method.unwrap_or(AuthMethod::None)),
+            ap.ssid
+        );
+    }
+}
+
+/// Scan the access points and choose the configuration for the best known network.
+///
+/// The authentication method and the channel are taken from the scan. If no known network is
+/// visible, e.g. because it has a hidden SSID, the network with the highest priority is tried.
+fn choose(
+    wifi: &mut BlockingWifi<EspWifi<'static>>,
+    networks: &[KnownNetwork],
+) -> ClientConfiguration {
+    let mut access_points = wifi.scan().unwrap_or_else(|e| {
+        warn!("Failed to scan: {:?}", e);
+        vec![]
+    });
+    access_points.sort_by_key(|ap| std::cmp::Reverse(ap.signal_strength));
+    report(&access_points);
+
+    let visible: Vec<(&str, i8)> = access_points
+        .iter()
+        .map(|ap| (ap.ssid.as_str(), ap.signal_strength))
+        .collect();
+    match networks::select(networks, &visible) {
+        Some((k, v)) => {
+            let ap = &access_points[v];
+            info!("Choosing \"{}\" ({} dBm)", ap.ssid, ap.signal_strength);
+            ClientConfiguration {
+                ssid: ap.ssid.clone(),
+                bssid: Some(ap.bssid),
+                auth_method: ap.auth_method.unwrap_or(AuthMethod::None),
+                password: networks[k].password.as_str().try_into().unwrap_or_default(),
+                channel: Some(ap.channel),
+                ..Default::default()
+            }
+        }
+        None => {
+            let network = networks.iter().max_by_key(|n| n.priority).unwrap();
+            warn!("No known network found, trying \"{}\"", network.ssid);
+            ClientConfiguration {
+                ssid: network.ssid.as_str().try_into().unwrap_or_default(),
+                auth_method: if network.password.is_empty() {
+                    AuthMethod::None
+                } else {
+                    AuthMethod::WPA2Personal
+                },
+                password: network.password.as_str().try_into().unwrap_or_default(),
+                ..Default::default()
+            }
+        }
+    }
+}
+
+fn connect(
+    wifi: &mut BlockingWifi<EspWifi<'static>>,
+    networks: &[KnownNetwork],
+) -> Result<Ipv4Addr> {
+    let configuration = choose(wifi, networks);
+    wifi.set_configuration(&Configuration::Client(configuration))?;
+    wifi.connect()?;
+    info!("Wifi connected");
+    wifi.wait_netif_up()?;
+    let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
+    info!("Wifi DHCP info: {:?}", ip_info);
+    Ok(ip_info.ip)
+}
+
+/// Body of the background task.
+fn run(
+    mut wifi: BlockingWifi<EspWifi<'static>>,
+    networks: Vec<KnownNetwork>,
+    status: Arc<Mutex<Status>>,
+) {
+    let mut backoff = Backoff::default();
+    let mut was_connected = false;
+    loop {
+        if wifi.is_connected().unwrap_or(false) {
+            update(&status, |s| s.rssi = rssi());
+            std::thread::sleep(POLL_PERIOD);
+            continue;
+        }
+
+        // Not connected yet, or the link was lost
+        if was_connected {
+            warn!("Wifi disconnected");
+            was_connected = false;
+        }
+        update(&status, |s| {
+            s.state = ConnectionState::Connecting;
+            s.ip = None;
+            s.rssi = None;
+        });
+
+        match connect(&mut wifi, &networks) {
+            Ok(ip) => {
+                backoff.reset();
+                was_connected = true;
+                update(&status, |s| {
+                    s.state = ConnectionState::Connected;
+                    s.ip = Some(ip);
+                    s.rssi = rssi();
+                    s.failures = 0;
+                });
+            }
+            Err(e) => {
+                let _ = wifi.disconnect();
+                let delay = backoff.next_delay();
+                warn!(
+                    "Failed to connect to Wifi: {:?}, retrying in {:?}",
+                    e, delay
+                );
+                update(&status, |s| {
+                    s.state = ConnectionState::Disconnected;
+                    s.failures += 1;
+                });
+                std::thread::sleep(delay);
+            }
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! Known networks and the choice among scanned access points.
+//!
+//! This module does not depend on ESP-IDF so that it can be checked on the host.
+use anyhow::{bail, Context, Result};
+
+/// A network that the station may connect to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KnownNetwork {
+    pub ssid: String,
+    pub password: String,
+
+    /// Networks with a higher priority are preferred over stronger ones.
+    pub priority: u8,
+}
+
+impl KnownNetwork {
+    pub fn new(ssid: &str, password: &str, priority: u8) -> Self {
+        KnownNetwork {
+            ssid: ssid.to_string(),
+            password: password.to_string(),
+            priority,
+        }
+    }
+}
+
+/// Parse a list of networks with one network per line in the form
+/// `<priority>,<ssid>,<password>`.
+///
+/// The password is the rest of the line and may contain commas. Empty lines and lines starting
+/// with `#` are skipped.
+pub fn parse_networks(text: &str) -> Result<Vec<KnownNetwork>> {
+    let mut networks = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        let mut fields = line.splitn(3, ',');
+        let priority = fields.next().unwrap_or_default().trim();
+        let priority: u8 = priority
+            .parse()
+            .with_context(|| format!("Invalid priority at line {}: {}", i + 1, priority))?;
+        let ssid = fields.next().unwrap_or_default();
+        if ssid.is_empty() || ssid.len() > 32 {
+            bail!("Invalid SSID at line {}", i + 1);
+        }
+        let password = fields.next().unwrap_or_default();
+        networks.push(KnownNetwork::new(ssid, password, priority));
+    }
+    Ok(networks)
+}
+
+/// Choose the network to connect to from the scanned access points given as `(ssid, rssi)`.
+///
+/// Among the known networks that are visible, the one with the highest priority is chosen, and
+/// the strongest access point among equal priorities. Returns the indices into `known` and
+/// `visible`, or `None` if no known network is visible.
+#[cfg(any(feature = "esp", test))]
+pub fn select(known: &[KnownNetwork], visible: &[(&str, i8)]) -> Option<(usize, usize)> {
+    let mut best: Option<(usize, usize)> = None;
+    for (v, (ssid, rssi)) in visible.iter().enumerate() {
+        let Some(k) = known.iter().position(|n| n.ssid == *ssid) else {
+            continue;
+        };
+        let better = match best {
+            None => true,
+            Some((bk, bv)) => (known[k].priority, *rssi) > (known[bk].priority, visible[bv].1),
+        };
+        if better {
+            best = Some((k, v));
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_lines() {
+        let text = "# priority,ssid,password\n\n2,home,secret,with,commas\r\n  \n0,guest,\n";
+        let networks = parse_networks(text).unwrap();
+        assert_eq!(
+            networks,
+            vec![
+                KnownNetwork::new("home", "secret,with,commas", 2),
+                KnownNetwork::new("guest", "", 0),
+            ]
+        );
+        assert!(parse_networks("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_errors_name_the_line() {
+        for (text, line) in [
+            ("1,home,pass\nx,office,pass", "line 2"),
+            ("256,home,pass", "line 1"),
+            ("1,,pass", "line 1"),
+            ("1", "line 1"),
+        ] {
+            let error = parse_networks(text).unwrap_err().to_string();
+            assert!(error.contains(line), "{}: {}", text, error);
+        }
+        let long = format!("1,{},pass", "a".repeat(33));
+        assert!(parse_networks(&long).is_err());
+    }
+
+    #[test]
+    fn select_prefers_priority_then_rssi() {
+        let known = [
+            KnownNetwork::new("home", "", 1),
+            KnownNetwork::new("office", "", 1),
+            KnownNetwork::new("phone", "", 5),
+        ];
+        // Equal priorities: the strongest access point
+        let visible = [("home", -70), ("office", -50), ("cafe", -30)];
+        assert_eq!(select(&known, &visible), Some((1, 1)));
+        // A higher priority wins over a stronger access point
+        let visible = [("home", -40), ("phone", -85), ("office", -50)];
+        assert_eq!(select(&known, &visible), Some((2, 1)));
+        // The strongest of several access points with the same SSID
+        let visible = [("home", -80), ("home", -60), ("home", -75)];
+        assert_eq!(select(&known, &visible), Some((0, 1)));
+    }
+
+    #[test]
+    fn select_without_known_networks() {
+        let known = [KnownNetwork::new("home", "", 0)];
+        assert_eq!(select(&known, &[]), None);
+        assert_eq!(select(&known, &[("cafe", -30), ("Home", -40)]), None);
+        assert_eq!(select(&[], &[("home", -30)]), None);
+    }
+}
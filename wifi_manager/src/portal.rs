@@ -0,0 +1,174 @@
+//! Parts of the provisioning portal that do not depend on ESP-IDF, so that they can be checked
+//! on the host.
+use anyhow::{bail, Result};
+
+/// Decode a `application/x-www-form-urlencoded` value.
+fn url_decode(value: &str) -> Result<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex = bytes.get(i + 1..i + 3).unwrap_or_default();
+                let hex = std::str::from_utf8(hex).unwrap_or_default();
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) if hex.len() == 2 => decoded.push(byte),
+                    _ => bail!("Invalid escape in form value"),
+                }
+                i += 2;
+            }
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    Ok(String::from_utf8(decoded)?)
+}
+
+/// Parse and validate the submitted form into an SSID and a password.
+pub fn parse_form(body: &str) -> Result<(String, String)> {
+    let mut ssid = None;
+    let mut password = String::new();
+    for pair in body.trim().split('&') {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match name {
+            "ssid" => ssid = Some(url_decode(value)?),
+            "password" => password = url_decode(value)?,
+            _ => {}
+        }
+    }
+
+    let Some(ssid) = ssid.filter(|ssid| !ssid.is_empty()) else {
+        bail!("SSID is empty");
+    };
+    if ssid.len() > 32 {
+        bail!("SSID is longer than 32 bytes");
+    }
+    if !password.is_empty() && !(8..=64).contains(&password.len()) {
+        bail!("Password must be 8 to 64 characters");
+    }
+    Ok((ssid, password))
+}
+
+/// Answer a DNS query with `ip` for any name, so that clients open the portal.
+///
+/// Returns `None` if the query is malformed. Queries other than A records are answered
+/// without records.
+pub fn dns_answer(query: &[u8], ip: [u8; 4]) -> Option<Vec<u8>> {
+    // Header, then a single question: name labels, type and class
+    if query.len() < 12 || u16::from_be_bytes([query[4], query[5]]) != 1 {
+        return None;
+    }
+    let mut end = 12;
+    loop {
+        let len = *query.get(end)? as usize;
+        end += 1;
+        if len == 0 {
+            break;
+        }
+        if len & 0xC0 != 0 {
+            return None;
+        }
+        end += len;
+    }
+    let qtype = u16::from_be_bytes([*query.get(end)?, *query.get(end + 1)?]);
+    end += 4;
+    if end > query.len() {
+        return None;
+    }
+
+    let is_a = qtype == 1;
+    let mut answer = Vec::with_capacity(end + 16);
+    answer.extend_from_slice(&query[..2]); // ID
+    answer.push(0x80 | (query[2] & 0x01)); // Response, keep RD
+    answer.push(0x80); // RA, no error
+    answer.extend_from_slice(&[0, 1, 0, is_a as u8, 0, 0, 0, 0]);
+    answer.extend_from_slice(&query[12..end]);
+    if is_a {
+        answer.extend_from_slice(&[0xC0, 0x0C]); // Pointer to the question name
+        answer.extend_from_slice(&[0, 1, 0, 1]); // A, IN
+        answer.extend_from_slice(&60u32.to_be_bytes()); // TTL
+        answer.extend_from_slice(&[0, 4]);
+        answer.extend_from_slice(&ip);
+    }
+    Some(answer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn form_values_are_decoded() {
+        let (ssid, password) = parse_form("ssid=My+Home%21&password=p%40ss%26word").unwrap();
+        assert_eq!(ssid, "My Home!");
+        assert_eq!(password, "p@ss&word");
+
+        let (ssid, password) =
+            parse_form("password=&ssid=%E3%83%9B%E3%83%BC%E3%83%A0\r\n").unwrap();
+        assert_eq!(ssid, "ホーム");
+        assert_eq!(password, "");
+    }
+
+    #[test]
+    fn invalid_forms_are_rejected() {
+        for body in [
+            "",
+            "password=12345678",
+            "ssid=&password=12345678",
+            "ssid=home&password=short",
+            "ssid=home&password=%zz345678",
+            "ssid=home%2",
+            "ssid=%FF%FE",
+        ] {
+            assert!(parse_form(body).is_err(), "{}", body);
+        }
+        assert!(parse_form(&format!("ssid={}", "a".repeat(33))).is_err());
+        assert!(parse_form(&format!("ssid=a&password={}", "a".repeat(65))).is_err());
+        assert!(parse_form(&format!("ssid={}", "a".repeat(32))).is_ok());
+    }
+
+    /// A query for `example.com` with the given type.
+    fn query(qtype: u16) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(b"\x07example\x03com\x00");
+        query.extend_from_slice(&qtype.to_be_bytes());
+        query.extend_from_slice(&[0, 1]);
+        query
+    }
+
+    #[test]
+    fn a_query_is_answered_with_the_portal() {
+        let query = query(1);
+        let answer = dns_answer(&query, [192, 168, 71, 1]).unwrap();
+        assert_eq!(&answer[..2], &[0x12, 0x34]);
+        assert_eq!(answer[2], 0x81);
+        assert_eq!(&answer[4..12], &[0, 1, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(&answer[12..query.len()], &query[12..]);
+        let record = &answer[query.len()..];
+        assert_eq!(&record[..6], &[0xC0, 0x0C, 0, 1, 0, 1]);
+        assert_eq!(&record[10..], &[0, 4, 192, 168, 71, 1]);
+    }
+
+    #[test]
+    fn other_queries_have_no_records() {
+        let query = query(28);
+        let answer = dns_answer(&query, [192, 168, 71, 1]).unwrap();
+        assert_eq!(&answer[6..8], &[0, 0]);
+        assert_eq!(answer.len(), query.len());
+    }
+
+    #[test]
+    fn malformed_queries_are_ignored() {
+        let query = query(1);
+        assert!(dns_answer(&query[..11], [0; 4]).is_none());
+        assert!(dns_answer(&query[..query.len() - 1], [0; 4]).is_none());
+        let mut two_questions = query.clone();
+        two_questions[5] = 2;
+        assert!(dns_answer(&two_questions, [0; 4]).is_none());
+        let mut compressed = query;
+        compressed[12] = 0xC0;
+        assert!(dns_answer(&compressed, [0; 4]).is_none());
+    }
+}
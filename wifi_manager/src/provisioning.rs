@@ -0,0 +1,152 @@
+//! Provisioning of the Wi-Fi credentials with a SoftAP captive portal.
+//!
+//! The device starts an open access point and serves a form to enter the credentials. DNS
+//! queries are answered with the address of the device, so that clients show the form as a
+//! captive portal. The credentials are saved to NVS and the device reboots.
+use crate::credentials::{self, Credentials};
+use crate::portal::{dns_answer, parse_form};
+use anyhow::Result;
+use embedded_svc::wifi::{AccessPointConfiguration, AuthMethod, Configuration};
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::modem::Modem;
+use esp_idf_svc::hal::peripheral::Peripheral;
+use esp_idf_svc::hal::reset::restart;
+use esp_idf_svc::http::server::{Configuration as HttpConfiguration, EspHttpServer};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::{Read, Write};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
+use log::{error, info, warn};
+use std::convert::Infallible;
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The page with the form to enter the credentials.
+const FORM_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><meta name="viewport" content="width=device-width"><title>Wi-Fi setup</title></head>
+<body>
+<h1>Wi-Fi setup</h1>
+<form method="post" action="/">
+<p><label>SSID<br><input name="ssid" maxlength="32" required></label></p>
+<p><label>Password<br><input name="password" type="password" maxlength="64"></label></p>
+<p><button type="submit">Save and reboot</button></p>
+</form>
+</body>
+</html>
+"#;
+
+/// The page shown after the credentials are saved.
+const SAVED_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Wi-Fi setup</title></head>
+<body><p>Saved. The device reboots and connects to the network.</p></body>
+</html>
+"#;
+
+/// Maximum size of the submitted form.
+const MAX_FORM_SIZE: usize = 512;
+
+/// Run the provisioning portal with the access point `ap_ssid`.
+///
+/// This only returns an error, since the device reboots once the credentials are saved.
+pub fn provision(
+    modem: impl Peripheral<P = Modem> + 'static,
+    sys_loop: EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
+    ap_ssid: &str,
+) -> Result<Infallible> {
+    let mut wifi = BlockingWifi::wrap(
+        EspWifi::new(modem, sys_loop.clone(), Some(nvs.clone()))?,
+        sys_loop,
+    )?;
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: ap_ssid.try_into().unwrap_or_default(),
+        auth_method: AuthMethod::None,
+        channel: 1,
+        ..Default::default()
+    }))?;
+    wifi.start()?;
+    wifi.wait_netif_up()?;
+    let ip = wifi.wifi().ap_netif().get_ip_info()?.ip;
+    info!(
+        "Provisioning: connect to \"{}\" and open http://{}/",
+        ap_ssid, ip
+    );
+
+    let storage = Arc::new(Mutex::new(credentials::storage(nvs)?));
+    let saved = Arc::new(AtomicBool::new(false));
+
+    let mut server = EspHttpServer::new(&HttpConfiguration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+    // Any page shows the form, as captive portal checks request various URLs
+    server.fn_handler("/*", Method::Get, |req| -> Result<()> {
+        req.into_ok_response()?.write_all(FORM_PAGE.as_bytes())?;
+        Ok(())
+    })?;
+    let saved_flag = saved.clone();
+    server.fn_handler("/*", Method::Post, move |mut req| -> Result<()> {
+        let mut body = [0; MAX_FORM_SIZE];
+        let mut len = 0;
+        while len < body.len() {
+            match req.read(&mut body[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+
+        let body = String::from_utf8_lossy(&body[..len]);
+        let result = parse_form(&body).and_then(|(ssid, password)| {
+            let credentials = Credentials { ssid, password };
+            credentials.save(&mut storage.lock().unwrap())?;
+            info!("Saved credentials for \"{}\"", credentials.ssid);
+            Ok(())
+        });
+        match result {
+            Ok(()) => {
+                req.into_ok_response()?.write_all(SAVED_PAGE.as_bytes())?;
+                saved_flag.store(true, Ordering::Release);
+            }
+            Err(e) => {
+                warn!("Invalid credentials: {:?}", e);
+                req.into_status_response(400)?
+                    .write_all(format!("{}\n", e).as_bytes())?;
+            }
+        }
+        Ok(())
+    })?;
+
+    let dns_ip = ip.octets();
+    std::thread::Builder::new()
+        .name("dns".into())
+        .stack_size(4096)
+        .spawn(move || {
+            if let Err(e) = serve_dns(dns_ip) {
+                error!("DNS server stopped: {:?}", e);
+            }
+        })?;
+
+    while !saved.load(Ordering::Acquire) {
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    // Let the response reach the client before rebooting
+    std::thread::sleep(Duration::from_secs(1));
+    info!("Rebooting into station mode...");
+    restart();
+}
+
+fn serve_dns(ip: [u8; 4]) -> Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:53")?;
+    let mut buf = [0; 512];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf)?;
+        if let Some(answer) = dns_answer(&buf[..len], ip) {
+            let _ = socket.send_to(&answer, from);
+        }
+    }
+}